@@ -1,4 +1,5 @@
 use std::fmt::{self, Debug};
+#[cfg(test)]
 use std::marker::PhantomData;
 
 use anyhow::bail;
@@ -6,22 +7,28 @@ use anyhow::Result as AnyResult;
 use cosmwasm_std::testing::{mock_env, MockApi, MockStorage};
 use cosmwasm_std::{
     from_slice, to_binary, Addr, Api, Binary, BlockInfo, ContractResult, CosmosMsg, CustomQuery,
-    Empty, GovMsg, IbcMsg, IbcQuery, Querier, QuerierResult, QuerierWrapper, QueryRequest, Record,
-    Storage, SystemError, SystemResult,
+    Empty, GovMsg, IbcMsg, IbcQuery, Order, Querier, QuerierResult, QuerierWrapper, QueryRequest,
+    Record, StdResult, Storage, SystemError, SystemResult, TransactionInfo,
 };
+use cw_storage_plus::{Item, KeyDeserialize, Map, PrimaryKey};
 use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use crate::bank::{Bank, BankKeeper, BankSudo};
 use crate::contracts::Contract;
+use crate::diff::StorageDiff;
 use crate::executor::{AppResponse, Executor};
 use crate::gov::Gov;
+use crate::history::{StorageAtHeight, StorageHistory};
 use crate::ibc::Ibc;
 use crate::module::{FailingModule, Module};
 use crate::staking::{Distribution, DistributionKeeper, StakeKeeper, Staking, StakingSudo};
-use crate::transactions::transactional;
-use crate::wasm::{ContractData, Wasm, WasmKeeper, WasmSudo};
+use crate::tracer::{traced_call, traced_query, Trace, TraceNode, Tracer};
+use crate::transactions::{RepLog, StorageTransaction};
+use crate::wasm::{
+    AccessConfig, ContractData, ContractHistoryEntry, Wasm, WasmAdminSudo, WasmKeeper, WasmSudo,
+};
 
 pub fn next_block(block: &mut BlockInfo) {
     block.time = block.time.plus_seconds(5);
@@ -58,6 +65,14 @@ pub struct App<
     api: Api,
     storage: Storage,
     block: BlockInfo,
+    /// Number of top-level transactions executed so far in the block at `tx_height`
+    tx_count: u32,
+    /// Height of the block `tx_count` refers to, the counter is reset when it changes
+    tx_height: u64,
+    /// Versions of the state by block height, only recorded if enabled
+    history: Option<StorageHistory>,
+    /// Call tree of the last executed transaction, only recorded if tracing is enabled
+    last_trace: Option<Trace>,
 }
 
 fn no_init<BankT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT>(
@@ -624,13 +639,20 @@ impl<BankT, ApiT, StorageT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT>
             distribution: self.distribution,
             ibc: self.ibc,
             gov: self.gov,
+            transaction: None,
+            tracer: Tracer::default(),
         };
 
+        let tx_height = self.block.height;
         let mut app = App {
             router,
             api: self.api,
             block: self.block,
             storage: self.storage,
+            tx_count: 0,
+            tx_height,
+            history: None,
+            last_trace: None,
         };
         app.init_modules(init_fn);
         app
@@ -658,7 +680,9 @@ where
             &mut dyn Storage,
         ) -> T,
     {
-        init_fn(&mut self.router, &self.api, &mut self.storage)
+        let res = self.transact(|router, api, storage, _| Ok(init_fn(router, api, storage)));
+        // init_fn itself cannot fail, so neither can committing its changes
+        res.unwrap()
     }
 
     pub fn read_module<F, T>(&self, query_fn: F) -> T
@@ -671,6 +695,52 @@ where
     {
         query_fn(&self.router, &self.api, &self.storage)
     }
+
+    /// Runs `action` on a cache over the app storage, so its writes are only committed if it
+    /// succeeds. Committed writes are recorded in the state history if versioning is enabled.
+    fn transact<F, T>(&mut self, action: F) -> AnyResult<T>
+    where
+        F: FnOnce(
+            &mut Router<BankT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT>,
+            &dyn Api,
+            &mut dyn Storage,
+            &BlockInfo,
+        ) -> AnyResult<T>,
+    {
+        self.transact_and_inspect(action, |_| ())
+            .map(|(res, _)| res)
+    }
+
+    /// Same as `transact`, but also passes the writes about to be committed to `inspect`.
+    fn transact_and_inspect<F, I, T, U>(&mut self, action: F, inspect: I) -> AnyResult<(T, U)>
+    where
+        F: FnOnce(
+            &mut Router<BankT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT>,
+            &dyn Api,
+            &mut dyn Storage,
+            &BlockInfo,
+        ) -> AnyResult<T>,
+        I: FnOnce(&RepLog) -> U,
+    {
+        let Self {
+            router,
+            api,
+            storage,
+            block,
+            history,
+            ..
+        } = self;
+
+        let mut cache = StorageTransaction::new(&*storage);
+        let res = action(router, &*api, &mut cache, block)?;
+        let rep_log = cache.prepare();
+        let inspected = inspect(&rep_log);
+        if let Some(history) = history {
+            history.record(&*storage, block, &rep_log);
+        }
+        rep_log.commit(storage);
+        Ok((res, inspected))
+    }
 }
 
 // Helper functions to call some custom WasmKeeper logic.
@@ -705,15 +775,161 @@ where
         self.init_modules(|router, _, _| router.wasm.store_code(code) as u64)
     }
 
+    /// Stores the code like `store_code`, under a name it can be found by with `code_id_by_name`
+    pub fn store_code_with_name(
+        &mut self,
+        name: impl Into<String>,
+        code: Box<dyn Contract<CustomT::ExecT, CustomT::QueryT>>,
+    ) -> AnyResult<u64> {
+        self.init_modules(|router, _, _| {
+            let code_id = router.wasm.store_code_with_name(name, code)?;
+            Ok(code_id as u64)
+        })
+    }
+
+    /// Replaces the implementation behind the code id, so all contracts using it run `code`
+    /// from now on, without being migrated
+    pub fn replace_code(
+        &mut self,
+        code_id: u64,
+        code: Box<dyn Contract<CustomT::ExecT, CustomT::QueryT>>,
+    ) -> AnyResult<()> {
+        self.init_modules(|router, _, _| router.wasm.replace_code(code_id as usize, code))
+    }
+
+    /// Returns the id of the code stored with the given name
+    pub fn code_id_by_name(&self, name: &str) -> AnyResult<u64> {
+        self.read_module(|router, _, _| {
+            let code_id = router.wasm.code_id_by_name(name)?;
+            Ok(code_id as u64)
+        })
+    }
+
+    /// Stores the code on behalf of `sender`, like uploading wasm bytecode with `MsgStoreCode`.
+    /// Unlike `store_code`, it respects the upload access rules of the wasm keeper, and returns
+    /// the `store_code` event along with the new code id.
+    pub fn upload_code(
+        &mut self,
+        sender: Addr,
+        code: Box<dyn Contract<CustomT::ExecT, CustomT::QueryT>>,
+    ) -> AnyResult<(u64, AppResponse)> {
+        self.init_modules(|router, _, _| {
+            let (code_id, res) = router.wasm.upload_code(sender, code)?;
+            Ok((code_id as u64, res))
+        })
+    }
+
+    /// Sets who may instantiate the given code, everybody can by default
+    pub fn set_instantiate_permission(
+        &mut self,
+        code_id: u64,
+        permission: AccessConfig,
+    ) -> AnyResult<()> {
+        self.init_modules(|router, _, _| {
+            router
+                .wasm
+                .set_instantiate_permission(code_id as usize, permission)
+        })
+    }
+
     /// This allows to get `ContractData` for specific contract
     pub fn contract_data(&self, address: &Addr) -> AnyResult<ContractData> {
         self.read_module(|router, _, storage| router.wasm.load_contract(storage, address))
     }
 
+    /// Returns the addresses of all contracts currently using the code
+    pub fn contracts_by_code(&self, code_id: u64) -> AnyResult<Vec<Addr>> {
+        self.read_module(|router, _, storage| {
+            router.wasm.contracts_by_code(storage, code_id as usize)
+        })
+    }
+
+    /// Returns the addresses of all contracts instantiated by the creator
+    pub fn contracts_by_creator(&self, creator: &Addr) -> AnyResult<Vec<Addr>> {
+        self.read_module(|router, _, storage| router.wasm.contracts_by_creator(storage, creator))
+    }
+
+    /// Returns the address of the only contract with the given label
+    pub fn contract_by_label(&self, label: &str) -> AnyResult<Addr> {
+        self.read_module(|router, _, storage| router.wasm.contract_by_label(storage, label))
+    }
+
+    /// Returns the code history of the contract, starting with its instantiation
+    pub fn contract_history(&self, address: &Addr) -> AnyResult<Vec<ContractHistoryEntry>> {
+        self.read_module(|router, _, storage| router.wasm.contract_history(storage, address))
+    }
+
     /// This gets a raw state dump of all key-values held by a given contract
     pub fn dump_wasm_raw(&self, address: &Addr) -> Vec<Record> {
         self.read_module(|router, _, storage| router.wasm.dump_wasm_raw(storage, address))
     }
+
+    /// Loads the value of the `Item` from the storage of the contract, so its state can be
+    /// inspected without a dedicated query
+    pub fn read_item<T>(&self, address: &Addr, item: &Item<T>) -> AnyResult<T>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.read_module(|router, _, storage| {
+            let storage = router.wasm.contract_storage_readonly(storage, address);
+            item.load(storage.as_ref()).map_err(Into::into)
+        })
+    }
+
+    /// Loads the value stored under `key` in the `Map` from the storage of the contract
+    pub fn read_map_entry<'a, K, T>(
+        &self,
+        address: &Addr,
+        map: &Map<'a, K, T>,
+        key: K,
+    ) -> AnyResult<Option<T>>
+    where
+        K: PrimaryKey<'a>,
+        T: Serialize + DeserializeOwned,
+    {
+        self.read_module(|router, _, storage| {
+            let storage = router.wasm.contract_storage_readonly(storage, address);
+            map.may_load(storage.as_ref(), key).map_err(Into::into)
+        })
+    }
+
+    /// Loads all entries of the `Map` from the storage of the contract, in ascending key order
+    pub fn read_map<'a, K, T>(
+        &self,
+        address: &Addr,
+        map: &Map<'a, K, T>,
+    ) -> AnyResult<Vec<(K::Output, T)>>
+    where
+        K: PrimaryKey<'a> + KeyDeserialize,
+        K::Output: 'static,
+        T: Serialize + DeserializeOwned,
+    {
+        self.read_module(|router, _, storage| {
+            let storage = router.wasm.contract_storage_readonly(storage, address);
+            map.range(storage.as_ref(), None, None, Order::Ascending)
+                .collect::<StdResult<_>>()
+                .map_err(Into::into)
+        })
+    }
+
+    /// Runs `action` on the raw storage of an existing contract, to seed or corrupt its state,
+    /// e.g. with the storage layout of an older version of the contract. The writes are committed
+    /// like the ones of any transaction, so they are recorded in the state history.
+    pub fn contract_storage_mut<F, T>(&mut self, address: &Addr, action: F) -> AnyResult<T>
+    where
+        F: FnOnce(&mut dyn Storage) -> T,
+    {
+        self.transact(|router, _, storage, _| {
+            router.wasm.load_contract(storage, address)?;
+            let mut contract_storage = router.wasm.contract_storage(storage, address);
+            Ok(action(contract_storage.as_mut()))
+        })
+    }
+
+    /// Sets the raw value stored under `key` by the contract
+    pub fn set_contract_raw(&mut self, address: &Addr, key: &[u8], value: &[u8]) -> AnyResult<()> {
+        self.contract_storage_mut(address, |storage| storage.set(key, value))
+    }
 }
 
 impl<BankT, ApiT, StorageT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT>
@@ -733,11 +949,21 @@ where
 {
     pub fn set_block(&mut self, block: BlockInfo) {
         self.block = block;
+        self.record_block();
     }
 
     // this let's use use "next block" steps that add eg. one height and 5 seconds
     pub fn update_block<F: Fn(&mut BlockInfo)>(&mut self, action: F) {
         action(&mut self.block);
+        self.record_block();
+    }
+
+    /// Keeps the info of the current block in the state history, so historical queries see the
+    /// block as it was even if nothing was committed in it
+    fn record_block(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.record_block(&self.block);
+        }
     }
 
     /// Returns a copy of the current block_info
@@ -745,9 +971,62 @@ where
         self.block.clone()
     }
 
+    /// Enables recording the call tree of every executed transaction, with every message,
+    /// submessage, reply and query along with their results. The trace of the last transaction
+    /// is available with `last_trace`.
+    pub fn enable_tracing(&mut self) {
+        self.router.tracer.enable();
+    }
+
+    /// Returns the call tree of the last transaction run with `execute`, `execute_multi`, `sudo`
+    /// or `wasm_sudo`, if tracing is enabled. It can be printed as an indented tree, or exported as JSON.
+    pub fn last_trace(&self) -> Option<&Trace> {
+        self.last_trace.as_ref()
+    }
+
+    /// Starts recording all state changes from the current block on, so the state at any height
+    /// since then can be queried with `wrap_at_height`. Does nothing if already enabled.
+    pub fn enable_state_versioning(&mut self) {
+        if self.history.is_none() {
+            self.history = Some(StorageHistory::new(&self.block));
+        }
+    }
+
+    /// Historical counterpart of `wrap`, returning a querier working on the state as it was at
+    /// the end of the block with the given height. State versioning has to be enabled at this
+    /// height already.
+    ///
+    ///   let old: CountResponse = app.wrap_at_height(h)?.wrap().query_wasm_smart(addr, &msg)?;
+    pub fn wrap_at_height(
+        &self,
+        height: u64,
+    ) -> AnyResult<HistoricalQuerier<'_, CustomT::ExecT, CustomT::QueryT>> {
+        let history = match &self.history {
+            Some(history) => history,
+            None => bail!("State versioning is not enabled"),
+        };
+        let block = history.block_at(height, &self.block)?;
+        Ok(HistoricalQuerier {
+            router: &self.router,
+            api: &self.api,
+            storage: StorageAtHeight::new(&self.storage, history, height),
+            block,
+        })
+    }
+
+    /// Returns the index the next top-level transaction gets within the current block.
+    /// The counter starts over whenever the block height changes.
+    fn current_transaction_index(&mut self) -> u32 {
+        if self.tx_height != self.block.height {
+            self.tx_height = self.block.height;
+            self.tx_count = 0;
+        }
+        self.tx_count
+    }
+
     /// Simple helper so we get access to all the QuerierWrapper helpers,
     /// eg. wrap().query_wasm_smart, query_all_balances, ...
-    pub fn wrap(&self) -> QuerierWrapper<CustomT::QueryT> {
+    pub fn wrap(&self) -> QuerierWrapper<'_, CustomT::QueryT> {
         QuerierWrapper::new(self)
     }
 
@@ -759,22 +1038,69 @@ where
         sender: Addr,
         msgs: Vec<cosmwasm_std::CosmosMsg<CustomT::ExecT>>,
     ) -> AnyResult<Vec<AppResponse>> {
+        self.execute_and_inspect(sender, msgs, |_| ())
+            .map(|(res, _)| res)
+    }
+
+    /// Runs a single CosmosMsg just like `execute`, and also returns all the storage writes
+    /// and deletions it made, grouped by module namespace. Useful to audit unexpected writes.
+    pub fn execute_with_diff(
+        &mut self,
+        sender: Addr,
+        msg: cosmwasm_std::CosmosMsg<CustomT::ExecT>,
+    ) -> AnyResult<(AppResponse, StorageDiff)> {
+        let (mut res, diff) =
+            self.execute_and_inspect(sender, vec![msg], StorageDiff::from_rep_log)?;
+        Ok((res.pop().unwrap(), diff))
+    }
+
+    fn execute_and_inspect<I, U>(
+        &mut self,
+        sender: Addr,
+        msgs: Vec<cosmwasm_std::CosmosMsg<CustomT::ExecT>>,
+        inspect: I,
+    ) -> AnyResult<(Vec<AppResponse>, U)>
+    where
+        I: FnOnce(&RepLog) -> U,
+    {
         // we need to do some caching of storage here, once in the entry point:
         // meaning, wrap current state, all writes go to a cache, only when execute
         // returns a success do we flush it (otherwise drop it)
 
-        let Self {
-            block,
-            router,
-            api,
-            storage,
-        } = self;
+        // every top-level call takes a slot in the block, even if it fails
+        let index = self.current_transaction_index();
+        self.tx_count += 1;
+        self.router.transaction = Some(TransactionInfo { index });
+
+        self.router.tracer.start();
+        let res = self.transact_and_inspect(
+            |router, api, write_cache, block| {
+                msgs.into_iter()
+                    .map(|msg| router.execute(api, write_cache, block, sender.clone(), msg))
+                    .collect()
+            },
+            inspect,
+        );
+        self.last_trace = self.router.tracer.finish();
+        res
+    }
 
-        transactional(&mut *storage, |write_cache, _| {
-            msgs.into_iter()
-                .map(|msg| router.execute(&*api, write_cache, block, sender.clone(), msg))
-                .collect()
-        })
+    /// Produces a block out of the given transactions, then advances the chain to the next block.
+    ///
+    /// Every transaction is a sender with its messages, which are executed atomically just like
+    /// with `execute_multi`. Transactions run in the given order, all with the same block height
+    /// and time. A failed transaction is rolled back on its own and doesn't affect the following
+    /// ones, so the result contains an outcome for every transaction in the block.
+    pub fn run_block(
+        &mut self,
+        txs: Vec<(Addr, Vec<cosmwasm_std::CosmosMsg<CustomT::ExecT>>)>,
+    ) -> Vec<AnyResult<Vec<AppResponse>>> {
+        let results = txs
+            .into_iter()
+            .map(|(sender, msgs)| self.execute_multi(sender, msgs))
+            .collect();
+        self.update_block(next_block);
+        results
     }
 
     /// Call a smart contract in "sudo" mode.
@@ -787,18 +1113,15 @@ where
     ) -> AnyResult<AppResponse> {
         let msg = to_binary(msg)?;
 
-        let Self {
-            block,
-            router,
-            api,
-            storage,
-        } = self;
+        // sudo is not a transaction on its own, it just sees the position of the next one
+        let index = self.current_transaction_index();
+        self.router.transaction = Some(TransactionInfo { index });
 
-        transactional(&mut *storage, |write_cache, _| {
-            router
-                .wasm
-                .sudo(&*api, contract_addr.into(), write_cache, router, block, msg)
-        })
+        let msg = SudoMsg::Wasm(WasmSudo {
+            contract_addr: contract_addr.into(),
+            msg,
+        });
+        self.traced_sudo(msg)
     }
 
     /// Runs arbitrary SudoMsg.
@@ -808,16 +1131,18 @@ where
         // we need to do some caching of storage here, once in the entry point:
         // meaning, wrap current state, all writes go to a cache, only when execute
         // returns a success do we flush it (otherwise drop it)
-        let Self {
-            block,
-            router,
-            api,
-            storage,
-        } = self;
+        let index = self.current_transaction_index();
+        self.router.transaction = Some(TransactionInfo { index });
 
-        transactional(&mut *storage, |write_cache, _| {
-            router.sudo(&*api, write_cache, block, msg)
-        })
+        self.traced_sudo(msg)
+    }
+
+    fn traced_sudo(&mut self, msg: SudoMsg) -> AnyResult<AppResponse> {
+        self.router.tracer.start();
+        let res = self
+            .transact(|router, api, write_cache, block| router.sudo(api, write_cache, block, msg));
+        self.last_trace = self.router.tracer.finish();
+        res
     }
 }
 
@@ -832,6 +1157,9 @@ pub struct Router<Bank, Custom, Wasm, Staking, Distr, Ibc, Gov> {
     pub distribution: Distr,
     pub ibc: Ibc,
     pub gov: Gov,
+    /// Position of the transaction being executed within the current block, set by `App`
+    pub(crate) transaction: Option<TransactionInfo>,
+    pub(crate) tracer: Tracer,
 }
 
 impl<BankT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT>
@@ -860,6 +1188,49 @@ where
             block_info,
         }
     }
+
+    fn execute_msg(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        block: &BlockInfo,
+        sender: Addr,
+        msg: CosmosMsg<CustomT::ExecT>,
+    ) -> AnyResult<AppResponse> {
+        match msg {
+            CosmosMsg::Wasm(msg) => self.wasm.execute(api, storage, self, block, sender, msg),
+            CosmosMsg::Bank(msg) => self.bank.execute(api, storage, self, block, sender, msg),
+            CosmosMsg::Custom(msg) => self.custom.execute(api, storage, self, block, sender, msg),
+            CosmosMsg::Staking(msg) => self.staking.execute(api, storage, self, block, sender, msg),
+            CosmosMsg::Distribution(msg) => self
+                .distribution
+                .execute(api, storage, self, block, sender, msg),
+            CosmosMsg::Ibc(msg) => self.ibc.execute(api, storage, self, block, sender, msg),
+            CosmosMsg::Gov(msg) => self.gov.execute(api, storage, self, block, sender, msg),
+            _ => bail!("Cannot execute {:?}", msg),
+        }
+    }
+
+    fn query_request(
+        &self,
+        api: &dyn Api,
+        storage: &dyn Storage,
+        block: &BlockInfo,
+        request: QueryRequest<CustomT::QueryT>,
+    ) -> AnyResult<Binary> {
+        let querier = self.querier(api, storage, block);
+        match request {
+            QueryRequest::Wasm(req) => self.wasm.query(api, storage, &querier, block, req),
+            QueryRequest::Bank(req) => self.bank.query(api, storage, &querier, block, req),
+            QueryRequest::Custom(req) => self.custom.query(api, storage, &querier, block, req),
+            QueryRequest::Staking(req) => self.staking.query(api, storage, &querier, block, req),
+            QueryRequest::Ibc(req) => self.ibc.query(api, storage, &querier, block, req),
+            QueryRequest::Stargate { path, data } if path.starts_with(WASM_QUERY_SERVICE) => {
+                self.wasm.query_stargate(api, storage, block, path, data)
+            }
+            _ => unimplemented!(),
+        }
+    }
 }
 
 /// We use it to allow calling into modules from another module in sudo mode.
@@ -869,14 +1240,24 @@ pub enum SudoMsg {
     Custom(Empty),
     Staking(StakingSudo),
     Wasm(WasmSudo),
+    WasmAdmin(WasmAdminSudo),
 }
 
+/// Prefix of paths of Stargate queries handled by the wasm module
+const WASM_QUERY_SERVICE: &str = "/cosmwasm.wasm.v1.Query/";
+
 impl From<WasmSudo> for SudoMsg {
     fn from(wasm: WasmSudo) -> Self {
         SudoMsg::Wasm(wasm)
     }
 }
 
+impl From<WasmAdminSudo> for SudoMsg {
+    fn from(wasm: WasmAdminSudo) -> Self {
+        SudoMsg::WasmAdmin(wasm)
+    }
+}
+
 impl From<BankSudo> for SudoMsg {
     fn from(bank: BankSudo) -> Self {
         SudoMsg::Bank(bank)
@@ -917,6 +1298,17 @@ pub trait CosmosRouter {
         block: &BlockInfo,
         msg: SudoMsg,
     ) -> AnyResult<AppResponse>;
+
+    /// Returns the info about the transaction currently being executed, which is passed to
+    /// contracts as part of their `Env`.
+    fn transaction_info(&self) -> Option<TransactionInfo> {
+        None
+    }
+
+    /// Returns the tracer if the current transaction is being traced
+    fn tracer(&self) -> Option<&Tracer> {
+        None
+    }
 }
 
 impl<BankT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT> CosmosRouter
@@ -943,18 +1335,12 @@ where
         sender: Addr,
         msg: CosmosMsg<Self::ExecC>,
     ) -> AnyResult<AppResponse> {
-        match msg {
-            CosmosMsg::Wasm(msg) => self.wasm.execute(api, storage, self, block, sender, msg),
-            CosmosMsg::Bank(msg) => self.bank.execute(api, storage, self, block, sender, msg),
-            CosmosMsg::Custom(msg) => self.custom.execute(api, storage, self, block, sender, msg),
-            CosmosMsg::Staking(msg) => self.staking.execute(api, storage, self, block, sender, msg),
-            CosmosMsg::Distribution(msg) => self
-                .distribution
-                .execute(api, storage, self, block, sender, msg),
-            CosmosMsg::Ibc(msg) => self.ibc.execute(api, storage, self, block, sender, msg),
-            CosmosMsg::Gov(msg) => self.gov.execute(api, storage, self, block, sender, msg),
-            _ => bail!("Cannot execute {:?}", msg),
-        }
+        let trace = self
+            .tracer()
+            .map(|tracer| (tracer, TraceNode::msg(&sender, &msg)));
+        traced_call(trace, storage, |storage| {
+            self.execute_msg(api, storage, block, sender, msg)
+        })
     }
 
     /// this is used by `RouterQuerier` to actual implement the `Querier` interface.
@@ -967,15 +1353,13 @@ where
         block: &BlockInfo,
         request: QueryRequest<Self::QueryC>,
     ) -> AnyResult<Binary> {
-        let querier = self.querier(api, storage, block);
-        match request {
-            QueryRequest::Wasm(req) => self.wasm.query(api, storage, &querier, block, req),
-            QueryRequest::Bank(req) => self.bank.query(api, storage, &querier, block, req),
-            QueryRequest::Custom(req) => self.custom.query(api, storage, &querier, block, req),
-            QueryRequest::Staking(req) => self.staking.query(api, storage, &querier, block, req),
-            QueryRequest::Ibc(req) => self.ibc.query(api, storage, &querier, block, req),
-            _ => unimplemented!(),
-        }
+        let trace = self.tracer().map(|tracer| {
+            let sender = tracer.current_target();
+            (tracer, TraceNode::query(sender, &request))
+        });
+        traced_query(trace, storage, |storage| {
+            self.query_request(api, storage, block, request)
+        })
     }
 
     fn sudo(
@@ -985,26 +1369,40 @@ where
         block: &BlockInfo,
         msg: SudoMsg,
     ) -> AnyResult<AppResponse> {
-        match msg {
+        let trace = self.tracer().map(|tracer| (tracer, TraceNode::sudo(&msg)));
+        traced_call(trace, storage, |storage| match msg {
             SudoMsg::Wasm(msg) => {
                 self.wasm
                     .sudo(api, msg.contract_addr, storage, self, block, msg.msg)
             }
             SudoMsg::Bank(msg) => self.bank.sudo(api, storage, self, block, msg),
             SudoMsg::Staking(msg) => self.staking.sudo(api, storage, self, block, msg),
+            SudoMsg::WasmAdmin(msg) => self.wasm.admin_sudo(api, storage, self, block, msg),
             SudoMsg::Custom(_) => unimplemented!(),
-        }
+        })
+    }
+
+    fn transaction_info(&self) -> Option<TransactionInfo> {
+        self.transaction.clone()
+    }
+
+    fn tracer(&self) -> Option<&Tracer> {
+        Some(&self.tracer).filter(|tracer| tracer.is_recording())
     }
 }
 
-pub struct MockRouter<ExecC, QueryC>(PhantomData<(ExecC, QueryC)>);
+/// Router panicking on any call, for testing modules which are not expected to use the router
+#[cfg(test)]
+pub(crate) struct MockRouter<ExecC, QueryC>(PhantomData<(ExecC, QueryC)>);
 
+#[cfg(test)]
 impl Default for MockRouter<Empty, Empty> {
     fn default() -> Self {
         Self::new()
     }
 }
 
+#[cfg(test)]
 impl<ExecC, QueryC> MockRouter<ExecC, QueryC> {
     pub fn new() -> Self
     where
@@ -1014,6 +1412,7 @@ impl<ExecC, QueryC> MockRouter<ExecC, QueryC> {
     }
 }
 
+#[cfg(test)]
 impl<ExecC, QueryC> CosmosRouter for MockRouter<ExecC, QueryC>
 where
     QueryC: CustomQuery,
@@ -1076,6 +1475,41 @@ impl<'a, ExecC, QueryC> RouterQuerier<'a, ExecC, QueryC> {
     }
 }
 
+/// Querier working on the state from some point in the past, as returned by
+/// `App::wrap_at_height`. Queries are made through `wrap`, like with `App`.
+pub struct HistoricalQuerier<'a, ExecC, QueryC> {
+    router: &'a dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+    api: &'a dyn Api,
+    storage: StorageAtHeight<'a>,
+    block: BlockInfo,
+}
+
+impl<'a, ExecC, QueryC> HistoricalQuerier<'a, ExecC, QueryC>
+where
+    ExecC: Clone + fmt::Debug + PartialEq + JsonSchema + DeserializeOwned + 'static,
+    QueryC: CustomQuery + DeserializeOwned + 'static,
+{
+    /// Simple helper so we get access to all the QuerierWrapper helpers
+    pub fn wrap(&self) -> QuerierWrapper<'_, QueryC> {
+        QuerierWrapper::new(self)
+    }
+
+    /// Returns the block info of the state this querier works on
+    pub fn block_info(&self) -> BlockInfo {
+        self.block.clone()
+    }
+}
+
+impl<'a, ExecC, QueryC> Querier for HistoricalQuerier<'a, ExecC, QueryC>
+where
+    ExecC: Clone + fmt::Debug + PartialEq + JsonSchema + DeserializeOwned + 'static,
+    QueryC: CustomQuery + DeserializeOwned + 'static,
+{
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        RouterQuerier::new(self.router, self.api, &self.storage, &self.block).raw_query(bin_request)
+    }
+}
+
 impl<'a, ExecC, QueryC> Querier for RouterQuerier<'a, ExecC, QueryC>
 where
     ExecC: Clone + fmt::Debug + PartialEq + JsonSchema + DeserializeOwned + 'static,
@@ -1091,11 +1525,16 @@ where
                 })
             }
         };
-        let contract_result: ContractResult<Binary> = self
+        let res = self
             .router
-            .query(self.api, self.storage, self.block_info, request)
-            .into();
-        SystemResult::Ok(contract_result)
+            .query(self.api, self.storage, self.block_info, request);
+        match res {
+            Err(err) => match err.downcast::<SystemError>() {
+                Ok(err) => SystemResult::Err(err),
+                Err(err) => SystemResult::Ok(ContractResult::Err(err.to_string())),
+            },
+            Ok(res) => SystemResult::Ok(ContractResult::Ok(res)),
+        }
     }
 }
 
@@ -1105,13 +1544,22 @@ mod test {
     use cosmwasm_std::testing::MockQuerier;
     use cosmwasm_std::{
         coin, coins, to_binary, AllBalanceResponse, Attribute, BankMsg, BankQuery, Coin, Event,
-        OverflowError, OverflowOperation, Reply, StdError, StdResult, SubMsg, WasmMsg,
+        Reply, StdError, StdResult, SubMsg, WasmMsg,
     };
 
-    use crate::error::Error;
-    use crate::test_helpers::contracts::{caller, echo, error, hackatom, payout, reflect};
+    use crate::error::{AbciCode, BankError, EntryPoint, Error, WasmError};
+    use crate::gas::INSTANCE_COST;
+    use crate::test_helpers::contracts::{
+        caller, echo, env, error, hackatom, payout, recurse, reflect,
+    };
     use crate::test_helpers::{CustomMsg, EmptyMsg};
-    use crate::transactions::StorageTransaction;
+    use crate::tracer::TraceKind;
+    use crate::transactions::{transactional, StorageTransaction};
+    use crate::wasm::{
+        ContractHistoryOperation, PageRequest, QueryContractHistoryRequest,
+        QueryContractsByCodeRequest, QueryContractsByCreatorRequest, ReentrancyPolicy,
+        StoreCodeResponse, DEFAULT_MAX_CALL_DEPTH,
+    };
 
     fn get_balance<BankT, ApiT, StorageT, CustomT, WasmT>(
         app: &App<BankT, ApiT, StorageT, CustomT, WasmT>,
@@ -1141,617 +1589,2219 @@ mod test {
     }
 
     #[test]
-    fn send_tokens() {
+    fn transaction_index_per_block() {
         let owner = Addr::unchecked("owner");
-        let rcpt = Addr::unchecked("receiver");
-        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
-        let rcpt_funds = vec![coin(5, "btc")];
+        let mut app = App::default();
 
-        let mut app = App::new(|router, _, storage| {
-            // initialization moved to App construction
-            router
-                .bank
-                .init_balance(storage, &owner, init_funds)
-                .unwrap();
-            router
-                .bank
-                .init_balance(storage, &rcpt, rcpt_funds)
+        let code_id = app.store_code(env::contract());
+        let contract_addr = app
+            .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "env", None)
+            .unwrap();
+
+        let executed_index = |app: &App| {
+            let env: cosmwasm_std::Env = app
+                .wrap()
+                .query_wasm_smart(&contract_addr, &env::QueryMsg::Executed {})
                 .unwrap();
-        });
+            env.transaction.unwrap().index
+        };
+        // instantiation was the first transaction in this block
+        assert_eq!(0, executed_index(&app));
 
-        // send both tokens
-        let to_send = vec![coin(30, "eth"), coin(5, "btc")];
-        let msg: cosmwasm_std::CosmosMsg = BankMsg::Send {
-            to_address: rcpt.clone().into(),
-            amount: to_send,
-        }
-        .into();
-        app.execute(owner.clone(), msg.clone()).unwrap();
-        let rich = get_balance(&app, &owner);
-        assert_eq!(vec![coin(15, "btc"), coin(70, "eth")], rich);
-        let poor = get_balance(&app, &rcpt);
-        assert_eq!(vec![coin(10, "btc"), coin(30, "eth")], poor);
+        app.execute_contract(owner.clone(), contract_addr.clone(), &EmptyMsg {}, &[])
+            .unwrap();
+        assert_eq!(1, executed_index(&app));
 
-        // can send from other account (but funds will be deducted from sender)
-        app.execute(rcpt.clone(), msg).unwrap();
+        // failed transactions still take their place in the block
+        app.send_tokens(owner.clone(), contract_addr.clone(), &coins(1, "eth"))
+            .unwrap_err();
+        app.execute_contract(owner.clone(), contract_addr.clone(), &EmptyMsg {}, &[])
+            .unwrap();
+        assert_eq!(3, executed_index(&app));
 
-        // cannot send too much
-        let msg = BankMsg::Send {
-            to_address: rcpt.into(),
-            amount: coins(20, "btc"),
-        }
-        .into();
-        app.execute(owner.clone(), msg).unwrap_err();
+        // counter is reset in the next block
+        app.update_block(next_block);
+        app.execute_contract(owner, contract_addr.clone(), &EmptyMsg {}, &[])
+            .unwrap();
+        assert_eq!(0, executed_index(&app));
 
-        let rich = get_balance(&app, &owner);
-        assert_eq!(vec![coin(15, "btc"), coin(70, "eth")], rich);
+        // queries sent from outside of a transaction get no transaction info
+        let env: cosmwasm_std::Env = app
+            .wrap()
+            .query_wasm_smart(&contract_addr, &env::QueryMsg::Current {})
+            .unwrap();
+        assert_eq!(env.transaction, None);
     }
 
     #[test]
-    fn simple_contract() {
-        // set personal balance
-        let owner = Addr::unchecked("owner");
-        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
+    fn run_block_executes_all_transactions() {
+        let alice = Addr::unchecked("alice");
+        let bob = Addr::unchecked("bob");
+        let carol = Addr::unchecked("carol");
 
         let mut app = App::new(|router, _, storage| {
             router
                 .bank
-                .init_balance(storage, &owner, init_funds)
+                .init_balance(storage, &alice, coins(100, "eth"))
                 .unwrap();
         });
+        let BlockInfo { time, height, .. } = app.block_info();
 
-        // set up contract
-        let code_id = app.store_code(payout::contract());
-        let msg = payout::InstantiateMessage {
-            payout: coin(5, "eth"),
-        };
-        let contract_addr = app
-            .instantiate_contract(
-                code_id,
-                owner.clone(),
-                &msg,
-                &coins(23, "eth"),
-                "Payout",
-                None,
-            )
-            .unwrap();
-
-        let contract_data = app.contract_data(&contract_addr).unwrap();
-        assert_eq!(
-            contract_data,
-            ContractData {
-                code_id: code_id as usize,
-                creator: owner.clone(),
-                admin: None,
-                label: "Payout".to_owned(),
-                created: app.block_info().height
+        let send = |to: &Addr, amount: u128| -> CosmosMsg {
+            BankMsg::Send {
+                to_address: to.to_string(),
+                amount: coins(amount, "eth"),
             }
-        );
+            .into()
+        };
 
-        // sender funds deducted
-        let sender = get_balance(&app, &owner);
-        assert_eq!(sender, vec![coin(20, "btc"), coin(77, "eth")]);
-        // get contract address, has funds
-        let funds = get_balance(&app, &contract_addr);
-        assert_eq!(funds, coins(23, "eth"));
+        // bob's transfer is ordered before alice pays him, so it fails
+        let results = app.run_block(vec![
+            (bob.clone(), vec![send(&carol, 10)]),
+            (alice.clone(), vec![send(&bob, 30)]),
+            (bob.clone(), vec![send(&carol, 10), send(&carol, 50)]),
+            (bob.clone(), vec![send(&carol, 20)]),
+        ]);
+
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap().len(), 1);
+        // the whole transaction is reverted, including the first message
+        assert!(results[2].is_err());
+        assert_eq!(results[3].as_ref().unwrap().len(), 1);
+
+        assert_eq!(get_balance(&app, &alice), coins(70, "eth"));
+        assert_eq!(get_balance(&app, &bob), coins(10, "eth"));
+        assert_eq!(get_balance(&app, &carol), coins(20, "eth"));
+
+        // block is advanced after the transactions are processed
+        assert_eq!(app.block_info().height, height + 1);
+        assert_eq!(app.block_info().time, time.plus_seconds(5));
+    }
 
-        // create empty account
-        let random = Addr::unchecked("random");
-        let funds = get_balance(&app, &random);
-        assert_eq!(funds, vec![]);
+    #[test]
+    fn run_block_shares_height_between_transactions() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
 
-        // do one payout and see money coming in
-        let res = app
-            .execute_contract(random.clone(), contract_addr.clone(), &EmptyMsg {}, &[])
+        let code_id = app.store_code(env::contract());
+        let contract_addr = app
+            .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "env", None)
             .unwrap();
-        assert_eq!(3, res.events.len());
-
-        // the call to payout does emit this as well as custom attributes
-        let payout_exec = &res.events[0];
-        assert_eq!(payout_exec.ty.as_str(), "execute");
-        assert_eq!(payout_exec.attributes, [("_contract_addr", &contract_addr)]);
-
-        // next is a custom wasm event
-        let custom_attrs = res.custom_attrs(1);
-        assert_eq!(custom_attrs, [("action", "payout")]);
-
-        // then the transfer event
-        let expected_transfer = Event::new("transfer")
-            .add_attribute("recipient", "random")
-            .add_attribute("sender", &contract_addr)
-            .add_attribute("amount", "5eth");
-        assert_eq!(&expected_transfer, &res.events[2]);
+        app.update_block(next_block);
+        let height = app.block_info().height;
 
-        // random got cash
-        let funds = get_balance(&app, &random);
-        assert_eq!(funds, coins(5, "eth"));
-        // contract lost it
-        let funds = get_balance(&app, &contract_addr);
-        assert_eq!(funds, coins(18, "eth"));
+        let execute: CosmosMsg = WasmMsg::Execute {
+            contract_addr: contract_addr.to_string(),
+            msg: to_binary(&EmptyMsg {}).unwrap(),
+            funds: vec![],
+        }
+        .into();
+        let results = app.run_block(vec![
+            (owner.clone(), vec![execute.clone()]),
+            (owner, vec![execute]),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+
+        // last transaction was the second one, at the height of the produced block
+        let env: cosmwasm_std::Env = app
+            .wrap()
+            .query_wasm_smart(&contract_addr, &env::QueryMsg::Executed {})
+            .unwrap();
+        assert_eq!(env.block.height, height);
+        assert_eq!(env.transaction.unwrap().index, 1);
+        assert_eq!(app.block_info().height, height + 1);
     }
 
     #[test]
-    fn reflect_success() {
-        // set personal balance
+    fn query_state_at_past_height() {
         let owner = Addr::unchecked("owner");
-        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
-
-        let mut app = custom_app::<CustomMsg, Empty, _>(|router, _, storage| {
+        let rcpt = Addr::unchecked("receiver");
+        let mut app = App::new(|router, _, storage| {
             router
                 .bank
-                .init_balance(storage, &owner, init_funds)
+                .init_balance(storage, &owner, coins(100, "eth"))
                 .unwrap();
         });
 
-        // set up payout contract
-        let payout_id = app.store_code(payout::contract());
-        let msg = payout::InstantiateMessage {
-            payout: coin(5, "eth"),
-        };
-        let payout_addr = app
-            .instantiate_contract(
-                payout_id,
-                owner.clone(),
-                &msg,
-                &coins(23, "eth"),
-                "Payout",
-                None,
-            )
+        let code_id = app.store_code(env::contract());
+        let contract_addr = app
+            .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "env", None)
             .unwrap();
+        // nothing was recorded yet
+        assert!(app.wrap_at_height(app.block_info().height).is_err());
 
-        // set up reflect contract
-        let reflect_id = app.store_code(reflect::contract());
-        let reflect_addr = app
-            .instantiate_contract(reflect_id, owner, &EmptyMsg {}, &[], "Reflect", None)
+        app.enable_state_versioning();
+        let since = app.block_info().height;
+        app.send_tokens(owner.clone(), rcpt.clone(), &coins(10, "eth"))
             .unwrap();
+        app.update_block(next_block);
 
-        // reflect account is empty
-        let funds = get_balance(&app, &reflect_addr);
-        assert_eq!(funds, vec![]);
-        // reflect count is 1
-        let qres: payout::CountResponse = app
-            .wrap()
-            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+        app.send_tokens(owner.clone(), rcpt.clone(), &coins(20, "eth"))
             .unwrap();
-        assert_eq!(0, qres.count);
+        app.execute_contract(owner, contract_addr.clone(), &EmptyMsg {}, &[])
+            .unwrap();
+        app.update_block(next_block);
+        let empty_block = app.block_info();
+        app.update_block(next_block);
+        let current = app.block_info().height;
 
-        // reflecting payout message pays reflect contract
-        let msg = SubMsg::new(WasmMsg::Execute {
-            contract_addr: payout_addr.clone().into(),
-            msg: b"{}".into(),
-            funds: vec![],
-        });
-        let msgs = reflect::Message {
-            messages: vec![msg],
+        let balance_at = |height| {
+            app.wrap_at_height(height)
+                .unwrap()
+                .wrap()
+                .query_balance(&rcpt, "eth")
+                .unwrap()
+                .amount
+                .u128()
         };
-        let res = app
-            .execute_contract(Addr::unchecked("random"), reflect_addr.clone(), &msgs, &[])
+        assert_eq!(balance_at(since), 10);
+        assert_eq!(balance_at(since + 1), 30);
+        assert_eq!(balance_at(current), 30);
+
+        // contract state is versioned as well, and the querier sees the historical block
+        let querier = app.wrap_at_height(since).unwrap();
+        assert_eq!(querier.block_info().height, since);
+        let executed: cosmwasm_std::Env = querier
+            .wrap()
+            .query_wasm_smart(&contract_addr, &env::QueryMsg::Executed {})
             .unwrap();
+        assert_eq!(executed.block.height, since);
+        let queried: cosmwasm_std::Env = querier
+            .wrap()
+            .query_wasm_smart(&contract_addr, &env::QueryMsg::Current {})
+            .unwrap();
+        assert_eq!(queried.block.height, since);
 
-        // ensure the attributes were relayed from the sub-message
-        assert_eq!(4, res.events.len(), "{:?}", res.events);
-
-        // reflect only returns standard wasm-execute event
-        let ref_exec = &res.events[0];
-        assert_eq!(ref_exec.ty.as_str(), "execute");
-        assert_eq!(ref_exec.attributes, [("_contract_addr", &reflect_addr)]);
+        let executed: cosmwasm_std::Env = app
+            .wrap_at_height(since + 2)
+            .unwrap()
+            .wrap()
+            .query_wasm_smart(&contract_addr, &env::QueryMsg::Executed {})
+            .unwrap();
+        assert_eq!(executed.block.height, since + 1);
 
-        // the call to payout does emit this as well as custom attributes
-        let payout_exec = &res.events[1];
-        assert_eq!(payout_exec.ty.as_str(), "execute");
-        assert_eq!(payout_exec.attributes, [("_contract_addr", &payout_addr)]);
+        assert!(app.wrap_at_height(since - 1).is_err());
+        assert!(app.wrap_at_height(current + 1).is_err());
 
-        let payout = &res.events[2];
-        assert_eq!(payout.ty.as_str(), "wasm");
-        assert_eq!(
-            payout.attributes,
-            [
-                ("_contract_addr", payout_addr.as_str()),
-                ("action", "payout")
-            ]
-        );
+        // blocks without commits keep their own info
+        let querier = app.wrap_at_height(empty_block.height).unwrap();
+        assert_eq!(querier.block_info(), empty_block);
 
-        // final event is the transfer from bank
-        let second = &res.events[3];
-        assert_eq!(second.ty.as_str(), "transfer");
-        assert_eq!(3, second.attributes.len());
-        assert_eq!(second.attributes[0], ("recipient", &reflect_addr));
-        assert_eq!(second.attributes[1], ("sender", &payout_addr));
-        assert_eq!(second.attributes[2], ("amount", "5eth"));
+        // writes made with init_modules are versioned as well
+        app.init_modules(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &rcpt, coins(500, "btc"))
+                .unwrap()
+        });
+        let btc_at = |height| {
+            app.wrap_at_height(height)
+                .unwrap()
+                .wrap()
+                .query_balance(&rcpt, "btc")
+                .unwrap()
+                .amount
+                .u128()
+        };
+        assert_eq!(btc_at(since), 0);
+        assert_eq!(btc_at(current - 1), 0);
+        assert_eq!(btc_at(current), 500);
+    }
 
-        // ensure transfer was executed with reflect as sender
-        let funds = get_balance(&app, &reflect_addr);
-        assert_eq!(funds, coins(5, "eth"));
+    #[test]
+    fn execute_with_diff_reports_writes() {
+        let owner = Addr::unchecked("owner");
+        let rcpt = Addr::unchecked("receiver");
+        let mut app = App::new(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &owner, coins(100, "eth"))
+                .unwrap();
+        });
 
-        // reflect count updated
-        let qres: payout::CountResponse = app
-            .wrap()
-            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+        let code_id = app.store_code(env::contract());
+        let contract_addr = app
+            .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "env", None)
             .unwrap();
-        assert_eq!(1, qres.count);
+
+        let send = BankMsg::Send {
+            to_address: rcpt.to_string(),
+            amount: coins(10, "eth"),
+        };
+        let (_, diff) = app.execute_with_diff(owner.clone(), send.into()).unwrap();
+        assert_eq!(diff.namespaces.keys().collect::<Vec<_>>(), vec!["bank"]);
+        assert_eq!(diff.writes("bank").len(), 2);
+
+        let execute = WasmMsg::Execute {
+            contract_addr: contract_addr.to_string(),
+            msg: to_binary(&EmptyMsg {}).unwrap(),
+            funds: vec![],
+        };
+        let (_, diff) = app.execute_with_diff(owner, execute.into()).unwrap();
+        let writes = diff.contract_writes(&contract_addr);
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].decoded_key.as_deref(), Some("env"));
+        let raw = app.wrap().query_wasm_raw(&contract_addr, b"env").unwrap();
+        assert_eq!(writes[0].value, raw);
     }
 
     #[test]
-    fn reflect_error() {
-        // set personal balance
+    fn tracing_records_call_tree() {
         let owner = Addr::unchecked("owner");
-        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
-
-        let mut app = custom_app::<CustomMsg, Empty, _>(|router, _, storage| {
+        let beneficiary = Addr::unchecked("beneficiary");
+        let mut app = App::new(|router, _, storage| {
             router
                 .bank
-                .init_balance(storage, &owner, init_funds)
+                .init_balance(storage, &owner, coins(100, "eth"))
                 .unwrap();
         });
 
-        // set up reflect contract
-        let reflect_id = app.store_code(reflect::contract());
-        let reflect_addr = app
+        let echo_id = app.store_code(echo::contract());
+        let echo_addr = app
             .instantiate_contract(
-                reflect_id,
-                owner,
-                &EmptyMsg {},
-                &coins(40, "eth"),
-                "Reflect",
+                echo_id,
+                owner.clone(),
+                &echo::InitMessage::<Empty>::default(),
+                &[],
+                "echo",
                 None,
             )
             .unwrap();
+        let hackatom_id = app.store_code(hackatom::contract());
+        let hackatom_addr = app
+            .instantiate_contract(
+                hackatom_id,
+                owner.clone(),
+                &hackatom::InstantiateMsg {
+                    beneficiary: beneficiary.to_string(),
+                },
+                &coins(30, "eth"),
+                "hackatom",
+                None,
+            )
+            .unwrap();
+        // nothing is recorded until enabled
+        assert_eq!(app.last_trace(), None);
+        app.enable_tracing();
 
-        // reflect has 40 eth
-        let funds = get_balance(&app, &reflect_addr);
-        assert_eq!(funds, coins(40, "eth"));
-        let random = Addr::unchecked("random");
-
-        // sending 7 eth works
-        let msg = SubMsg::new(BankMsg::Send {
-            to_address: random.clone().into(),
-            amount: coins(7, "eth"),
-        });
-        let msgs = reflect::Message {
-            messages: vec![msg],
+        let submsg: SubMsg = SubMsg::reply_always(
+            WasmMsg::Execute {
+                contract_addr: hackatom_addr.to_string(),
+                msg: to_binary(&EmptyMsg {}).unwrap(),
+                funds: vec![],
+            },
+            1,
+        );
+        let msg = echo::Message {
+            sub_msg: vec![submsg],
+            ..echo::Message::default()
+        };
+        app.execute_contract(owner.clone(), echo_addr.clone(), &msg, &[])
+            .unwrap();
+
+        let trace = app.last_trace().unwrap();
+        assert_eq!(trace.calls.len(), 1);
+        let call = &trace.calls[0];
+        assert_eq!(call.kind, TraceKind::Msg);
+        assert_eq!(call.sender.as_deref(), Some("owner"));
+        assert_eq!(call.target, echo_addr.as_str());
+        assert_eq!(call.error, None);
+
+        let submsg = &call.children[0];
+        assert_eq!(submsg.kind, TraceKind::SubMsg);
+        assert_eq!(submsg.target, hackatom_addr.as_str());
+        let kinds: Vec<_> = submsg.children.iter().map(|node| node.kind).collect();
+        assert_eq!(kinds, vec![TraceKind::Msg, TraceKind::Reply]);
+
+        // the contract queries its balance, then sends it out
+        let execute = &submsg.children[0];
+        assert_eq!(execute.sender.as_deref(), Some(echo_addr.as_str()));
+        let (query, send) = (&execute.children[0], &execute.children[1]);
+        assert_eq!(query.kind, TraceKind::Query);
+        assert_eq!(query.sender.as_deref(), Some(hackatom_addr.as_str()));
+        assert_eq!(query.target, "bank");
+        assert_eq!(send.kind, TraceKind::Msg);
+        assert_eq!(send.target, "bank");
+        assert_eq!(send.events[0].ty, "transfer");
+        assert!(send.storage_ops.writes > 0);
+        assert!(execute.storage_ops.writes >= send.storage_ops.writes);
+
+        let tree = trace.to_string();
+        assert!(tree.starts_with(&format!("msg owner -> {}", echo_addr)));
+        assert!(tree.contains(&format!("\n      query {} -> bank", hackatom_addr)));
+        let json: serde_json::Value = serde_json::from_str(&trace.to_json()).unwrap();
+        assert_eq!(json["calls"][0]["children"][0]["kind"], "sub_msg");
+
+        // failures are traced with their error
+        let msg: echo::Message<Empty> = echo::Message {
+            sub_msg: vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: "unknown".to_string(),
+                msg: to_binary(&EmptyMsg {}).unwrap(),
+                funds: vec![],
+            })],
+            ..echo::Message::default()
         };
+        app.execute_contract(owner.clone(), echo_addr, &msg, &[])
+            .unwrap_err();
+        let trace = app.last_trace().unwrap();
+        let failed = &trace.calls[0].children[0];
+        assert_eq!(failed.target, "unknown");
+        assert!(failed.error.is_some());
+        assert!(trace.calls[0].error.is_some());
+
+        // so are privileged messages
+        app.sudo(
+            BankSudo::Mint {
+                to_address: owner.to_string(),
+                amount: coins(10, "eth"),
+            }
+            .into(),
+        )
+        .unwrap();
+        let trace = app.last_trace().unwrap();
+        assert_eq!(trace.calls[0].kind, TraceKind::Sudo);
+        assert_eq!(trace.calls[0].sender, None);
+        assert_eq!(trace.calls[0].target, "bank");
+        assert!(trace.to_string().starts_with("sudo -> bank"));
+    }
+
+    #[test]
+    fn contract_wrapper_with_closures() {
+        use crate::ContractWrapper;
+        use cosmwasm_std::{Deps, DepsMut, Env, MessageInfo, Response};
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // contract failing every n-th execution, without any top-level functions
+        fn flaky(fail_every: u32) -> Box<dyn Contract<Empty>> {
+            let until_failure = Rc::new(Cell::new(fail_every));
+            let execute = move |_: DepsMut, _: Env, _: MessageInfo, _: EmptyMsg| {
+                until_failure.set(until_failure.get() - 1);
+                if until_failure.get() == 0 {
+                    until_failure.set(fail_every);
+                    return Err(StdError::generic_err("Flaky failure"));
+                }
+                Ok(Response::new())
+            };
+            let instantiate =
+                |_: DepsMut, _: Env, _: MessageInfo, _: EmptyMsg| StdResult::Ok(Response::new());
+            let query = move |_: Deps, _: Env, _: EmptyMsg| to_binary(&fail_every);
+            let sudo = move |_: DepsMut, _: Env, _: EmptyMsg| {
+                StdResult::Ok(Response::new().add_attribute("fail_every", fail_every.to_string()))
+            };
+            let migrate = |_: DepsMut, _: Env, _: EmptyMsg| {
+                StdResult::Ok(Response::new().add_attribute("migrated", "true"))
+            };
+            let contract = ContractWrapper::new_with_closures(
+                Box::new(execute),
+                Box::new(instantiate),
+                Box::new(query),
+            )
+            .with_sudo_closure(Box::new(sudo))
+            .with_migrate_closure(Box::new(migrate));
+            Box::new(contract)
+        }
+
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+        let code_id = app.store_code(flaky(3));
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                owner.clone(),
+                &EmptyMsg {},
+                &[],
+                "Flaky",
+                Some(owner.to_string()),
+            )
+            .unwrap();
+
+        let results: Vec<_> = (0..6)
+            .map(|_| {
+                app.execute_contract(owner.clone(), contract.clone(), &EmptyMsg {}, &[])
+                    .is_ok()
+            })
+            .collect();
+        assert_eq!(results, vec![true, true, false, true, true, false]);
+
+        let fail_every: u32 = app
+            .wrap()
+            .query_wasm_smart(&contract, &EmptyMsg {})
+            .unwrap();
+        assert_eq!(fail_every, 3);
+        let res = app.wasm_sudo(contract.clone(), &EmptyMsg {}).unwrap();
+        assert_eq!(res.custom_attrs(1), [("fail_every", "3")]);
         let res = app
-            .execute_contract(random.clone(), reflect_addr.clone(), &msgs, &[])
+            .migrate_contract(owner, contract, &EmptyMsg {}, code_id)
             .unwrap();
-        // no wasm events as no attributes
-        assert_eq!(2, res.events.len());
-        // standard wasm-execute event
-        let exec = &res.events[0];
-        assert_eq!(exec.ty.as_str(), "execute");
-        assert_eq!(exec.attributes, [("_contract_addr", &reflect_addr)]);
-        // only transfer event from bank
-        let transfer = &res.events[1];
-        assert_eq!(transfer.ty.as_str(), "transfer");
+        assert_eq!(res.custom_attrs(1), [("migrated", "true")]);
+    }
 
-        // ensure random got paid
-        let funds = get_balance(&app, &random);
-        assert_eq!(funds, coins(7, "eth"));
+    #[test]
+    fn write_contract_storage() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+        let code_id = app.store_code(payout::contract());
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                owner,
+                &payout::InstantiateMessage {
+                    payout: coin(5, "eth"),
+                },
+                &[],
+                "Payout",
+                None,
+            )
+            .unwrap();
 
-        // reflect count should be updated to 1
-        let qres: payout::CountResponse = app
+        app.set_contract_raw(&contract, b"count", b"42").unwrap();
+        let res: payout::CountResponse = app
             .wrap()
-            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+            .query_wasm_smart(&contract, &payout::QueryMsg::Count {})
             .unwrap();
-        assert_eq!(1, qres.count);
+        assert_eq!(res.count, 42);
 
-        // sending 8 eth, then 3 btc should fail both
-        let msg = SubMsg::new(BankMsg::Send {
-            to_address: random.clone().into(),
-            amount: coins(8, "eth"),
-        });
-        let msg2 = SubMsg::new(BankMsg::Send {
-            to_address: random.clone().into(),
-            amount: coins(3, "btc"),
-        });
-        let msgs = reflect::Message {
-            messages: vec![msg, msg2],
-        };
-        let err = app
-            .execute_contract(random.clone(), reflect_addr.clone(), &msgs, &[])
+        app.contract_storage_mut(&contract, |storage| storage.remove(b"payout"))
+            .unwrap();
+        app.wrap()
+            .query_wasm_smart::<payout::InstantiateMessage>(&contract, &payout::QueryMsg::Payout {})
             .unwrap_err();
         assert_eq!(
-            StdError::overflow(OverflowError::new(OverflowOperation::Sub, 0, 3)),
-            err.downcast().unwrap()
+            app.dump_wasm_raw(&contract),
+            vec![(b"count".to_vec(), b"42".to_vec())]
         );
 
-        // first one should have been rolled-back on error (no second payment)
-        let funds = get_balance(&app, &random);
-        assert_eq!(funds, coins(7, "eth"));
+        // only existing contracts can be written to
+        app.set_contract_raw(&Addr::unchecked("unknown"), b"count", b"1")
+            .unwrap_err();
+    }
 
-        // failure should not update reflect count
-        let qres: payout::CountResponse = app
-            .wrap()
-            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+    #[test]
+    fn read_typed_contract_state() {
+        const COUNT: Item<u32> = Item::new("count");
+        const PAYOUT: Item<payout::InstantiateMessage> = Item::new("payout");
+        const BALANCES: Map<&Addr, u128> = Map::new("balances");
+
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+        let code_id = app.store_code(payout::contract());
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                owner.clone(),
+                &payout::InstantiateMessage {
+                    payout: coin(5, "eth"),
+                },
+                &[],
+                "Payout",
+                None,
+            )
             .unwrap();
-        assert_eq!(1, qres.count);
+
+        assert_eq!(app.read_item(&contract, &COUNT).unwrap(), 1);
+        let payout = app.read_item(&contract, &PAYOUT).unwrap();
+        assert_eq!(payout.payout, coin(5, "eth"));
+
+        let other = Addr::unchecked("other");
+        app.contract_storage_mut(&contract, |storage| {
+            BALANCES.save(storage, &owner, &100)?;
+            BALANCES.save(storage, &other, &7)
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            app.read_map_entry(&contract, &BALANCES, &other).unwrap(),
+            Some(7)
+        );
+        assert_eq!(
+            app.read_map_entry(&contract, &BALANCES, &contract).unwrap(),
+            None
+        );
+        assert_eq!(
+            app.read_map(&contract, &BALANCES).unwrap(),
+            vec![(other, 7), (owner, 100)]
+        );
     }
 
     #[test]
-    fn sudo_works() {
+    fn swap_contract_code() {
         let owner = Addr::unchecked("owner");
-        let init_funds = vec![coin(100, "eth")];
-
         let mut app = App::new(|router, _, storage| {
             router
                 .bank
-                .init_balance(storage, &owner, init_funds)
+                .init_balance(storage, &owner, coins(100, "eth"))
                 .unwrap();
         });
-
-        let payout_id = app.store_code(payout::contract());
-        let msg = payout::InstantiateMessage {
-            payout: coin(5, "eth"),
-        };
-        let payout_addr = app
-            .instantiate_contract(payout_id, owner, &msg, &coins(23, "eth"), "Payout", None)
-            .unwrap();
-
-        // count is 1
-        let payout::CountResponse { count } = app
-            .wrap()
-            .query_wasm_smart(&payout_addr, &payout::QueryMsg::Count {})
+        let code_id = app.store_code(payout::contract());
+        let error_code_id = app.store_code(error::contract(true));
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                owner.clone(),
+                &payout::InstantiateMessage {
+                    payout: coin(5, "eth"),
+                },
+                &coins(50, "eth"),
+                "Payout",
+                None,
+            )
             .unwrap();
-        assert_eq!(1, count);
+        let payout = |app: &mut App| {
+            app.execute_contract(owner.clone(), contract.clone(), &EmptyMsg {}, &[])
+        };
+        payout(&mut app).unwrap();
 
-        // wasm_sudo call
-        let msg = payout::SudoMsg { set_count: 25 };
-        app.wasm_sudo(payout_addr.clone(), &msg).unwrap();
+        // replacing the code affects every contract using it, with its state left intact
+        app.replace_code(code_id, error::contract(true)).unwrap();
+        let err = payout(&mut app).unwrap_err();
+        assert_eq!(
+            StdError::generic_err("Handle failed"),
+            err.downcast().unwrap()
+        );
+        app.replace_code(code_id, payout::contract()).unwrap();
+        payout(&mut app).unwrap();
+        assert_eq!(get_balance(&app, &owner), coins(60, "eth"));
 
-        // count is 25
-        let payout::CountResponse { count } = app
-            .wrap()
-            .query_wasm_smart(&payout_addr, &payout::QueryMsg::Count {})
+        // a single contract can be pointed at another code without migrating it
+        let res = app
+            .sudo(
+                WasmAdminSudo::SetContractCode {
+                    contract_addr: contract.to_string(),
+                    code_id: error_code_id,
+                }
+                .into(),
+            )
             .unwrap();
-        assert_eq!(25, count);
-
-        // we can do the same with sudo call
-        let msg = payout::SudoMsg { set_count: 49 };
-        let sudo_msg = WasmSudo {
-            contract_addr: payout_addr.clone(),
-            msg: to_binary(&msg).unwrap(),
-        };
-        app.sudo(sudo_msg.into()).unwrap();
+        assert_eq!(
+            res.events,
+            vec![Event::new("set_contract_code")
+                .add_attribute("_contract_addr", &contract)
+                .add_attribute("code_id", error_code_id.to_string())]
+        );
+        payout(&mut app).unwrap_err();
+        assert_eq!(
+            app.contract_data(&contract).unwrap().code_id,
+            error_code_id as usize
+        );
+        assert_eq!(
+            app.contracts_by_code(error_code_id).unwrap(),
+            vec![contract.clone()]
+        );
+        assert_eq!(app.contract_history(&contract).unwrap().len(), 1);
 
-        let payout::CountResponse { count } = app
-            .wrap()
-            .query_wasm_smart(&payout_addr, &payout::QueryMsg::Count {})
-            .unwrap();
-        assert_eq!(49, count);
+        let err = app.replace_code(7, payout::contract()).unwrap_err();
+        assert_eq!(Error::UnregisteredCodeId(7), err.downcast().unwrap());
     }
 
-    // this demonstrates that we can mint tokens and send from other accounts via a custom module,
-    // as an example of ability to do privileged actions
-    mod custom_handler {
-        use super::*;
+    #[test]
+    fn send_tokens() {
+        let owner = Addr::unchecked("owner");
+        let rcpt = Addr::unchecked("receiver");
+        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
+        let rcpt_funds = vec![coin(5, "btc")];
 
-        use anyhow::{bail, Result as AnyResult};
-        use cw_storage_plus::Item;
-        use serde::{Deserialize, Serialize};
+        let mut app = App::new(|router, _, storage| {
+            // initialization moved to App construction
+            router
+                .bank
+                .init_balance(storage, &owner, init_funds)
+                .unwrap();
+            router
+                .bank
+                .init_balance(storage, &rcpt, rcpt_funds)
+                .unwrap();
+        });
 
-        use crate::Executor;
+        // send both tokens
+        let to_send = vec![coin(30, "eth"), coin(5, "btc")];
+        let msg: cosmwasm_std::CosmosMsg = BankMsg::Send {
+            to_address: rcpt.clone().into(),
+            amount: to_send,
+        }
+        .into();
+        app.execute(owner.clone(), msg.clone()).unwrap();
+        let rich = get_balance(&app, &owner);
+        assert_eq!(vec![coin(15, "btc"), coin(70, "eth")], rich);
+        let poor = get_balance(&app, &rcpt);
+        assert_eq!(vec![coin(10, "btc"), coin(30, "eth")], poor);
 
-        const LOTTERY: Item<Coin> = Item::new("lottery");
-        const PITY: Item<Coin> = Item::new("pity");
+        // can send from other account (but funds will be deducted from sender)
+        app.execute(rcpt.clone(), msg).unwrap();
 
-        #[derive(Clone, std::fmt::Debug, PartialEq, JsonSchema, Serialize, Deserialize)]
-        struct CustomMsg {
-            // we mint LOTTERY tokens to this one
-            lucky_winner: String,
-            // we transfer PITY from lucky_winner to runner_up
-            runner_up: String,
+        // cannot send too much
+        let msg = BankMsg::Send {
+            to_address: rcpt.into(),
+            amount: coins(20, "btc"),
         }
+        .into();
+        app.execute(owner.clone(), msg).unwrap_err();
 
-        struct CustomHandler {}
+        let rich = get_balance(&app, &owner);
+        assert_eq!(vec![coin(15, "btc"), coin(70, "eth")], rich);
+    }
 
-        impl Module for CustomHandler {
-            type ExecT = CustomMsg;
-            type QueryT = Empty;
+    #[test]
+    fn simple_contract() {
+        // set personal balance
+        let owner = Addr::unchecked("owner");
+        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
+
+        let mut app = App::new(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &owner, init_funds)
+                .unwrap();
+        });
+
+        // set up contract
+        let code_id = app.store_code(payout::contract());
+        let msg = payout::InstantiateMessage {
+            payout: coin(5, "eth"),
+        };
+        let contract_addr = app
+            .instantiate_contract(
+                code_id,
+                owner.clone(),
+                &msg,
+                &coins(23, "eth"),
+                "Payout",
+                None,
+            )
+            .unwrap();
+
+        let contract_data = app.contract_data(&contract_addr).unwrap();
+        assert_eq!(
+            contract_data,
+            ContractData {
+                code_id: code_id as usize,
+                creator: owner.clone(),
+                admin: None,
+                label: "Payout".to_owned(),
+                created: app.block_info().height,
+                code_name: None,
+            }
+        );
+
+        // sender funds deducted
+        let sender = get_balance(&app, &owner);
+        assert_eq!(sender, vec![coin(20, "btc"), coin(77, "eth")]);
+        // get contract address, has funds
+        let funds = get_balance(&app, &contract_addr);
+        assert_eq!(funds, coins(23, "eth"));
+
+        // create empty account
+        let random = Addr::unchecked("random");
+        let funds = get_balance(&app, &random);
+        assert_eq!(funds, vec![]);
+
+        // do one payout and see money coming in
+        let res = app
+            .execute_contract(random.clone(), contract_addr.clone(), &EmptyMsg {}, &[])
+            .unwrap();
+        assert_eq!(3, res.events.len());
+
+        // the call to payout does emit this as well as custom attributes
+        let payout_exec = &res.events[0];
+        assert_eq!(payout_exec.ty.as_str(), "execute");
+        assert_eq!(payout_exec.attributes, [("_contract_addr", &contract_addr)]);
+
+        // next is a custom wasm event
+        let custom_attrs = res.custom_attrs(1);
+        assert_eq!(custom_attrs, [("action", "payout")]);
+
+        // then the transfer event
+        let expected_transfer = Event::new("transfer")
+            .add_attribute("recipient", "random")
+            .add_attribute("sender", &contract_addr)
+            .add_attribute("amount", "5eth");
+        assert_eq!(&expected_transfer, &res.events[2]);
+
+        // random got cash
+        let funds = get_balance(&app, &random);
+        assert_eq!(funds, coins(5, "eth"));
+        // contract lost it
+        let funds = get_balance(&app, &contract_addr);
+        assert_eq!(funds, coins(18, "eth"));
+    }
+
+    #[test]
+    fn reflect_success() {
+        // set personal balance
+        let owner = Addr::unchecked("owner");
+        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
+
+        let mut app = custom_app::<CustomMsg, Empty, _>(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &owner, init_funds)
+                .unwrap();
+        });
+
+        // set up payout contract
+        let payout_id = app.store_code(payout::contract());
+        let msg = payout::InstantiateMessage {
+            payout: coin(5, "eth"),
+        };
+        let payout_addr = app
+            .instantiate_contract(
+                payout_id,
+                owner.clone(),
+                &msg,
+                &coins(23, "eth"),
+                "Payout",
+                None,
+            )
+            .unwrap();
+
+        // set up reflect contract
+        let reflect_id = app.store_code(reflect::contract());
+        let reflect_addr = app
+            .instantiate_contract(reflect_id, owner, &EmptyMsg {}, &[], "Reflect", None)
+            .unwrap();
+
+        // reflect account is empty
+        let funds = get_balance(&app, &reflect_addr);
+        assert_eq!(funds, vec![]);
+        // reflect count is 1
+        let qres: payout::CountResponse = app
+            .wrap()
+            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+            .unwrap();
+        assert_eq!(0, qres.count);
+
+        // reflecting payout message pays reflect contract
+        let msg = SubMsg::new(WasmMsg::Execute {
+            contract_addr: payout_addr.clone().into(),
+            msg: b"{}".into(),
+            funds: vec![],
+        });
+        let msgs = reflect::Message {
+            messages: vec![msg],
+        };
+        let res = app
+            .execute_contract(Addr::unchecked("random"), reflect_addr.clone(), &msgs, &[])
+            .unwrap();
+
+        // ensure the attributes were relayed from the sub-message
+        assert_eq!(4, res.events.len(), "{:?}", res.events);
+
+        // reflect only returns standard wasm-execute event
+        let ref_exec = &res.events[0];
+        assert_eq!(ref_exec.ty.as_str(), "execute");
+        assert_eq!(ref_exec.attributes, [("_contract_addr", &reflect_addr)]);
+
+        // the call to payout does emit this as well as custom attributes
+        let payout_exec = &res.events[1];
+        assert_eq!(payout_exec.ty.as_str(), "execute");
+        assert_eq!(payout_exec.attributes, [("_contract_addr", &payout_addr)]);
+
+        let payout = &res.events[2];
+        assert_eq!(payout.ty.as_str(), "wasm");
+        assert_eq!(
+            payout.attributes,
+            [
+                ("_contract_addr", payout_addr.as_str()),
+                ("action", "payout")
+            ]
+        );
+
+        // final event is the transfer from bank
+        let second = &res.events[3];
+        assert_eq!(second.ty.as_str(), "transfer");
+        assert_eq!(3, second.attributes.len());
+        assert_eq!(second.attributes[0], ("recipient", &reflect_addr));
+        assert_eq!(second.attributes[1], ("sender", &payout_addr));
+        assert_eq!(second.attributes[2], ("amount", "5eth"));
+
+        // ensure transfer was executed with reflect as sender
+        let funds = get_balance(&app, &reflect_addr);
+        assert_eq!(funds, coins(5, "eth"));
+
+        // reflect count updated
+        let qres: payout::CountResponse = app
+            .wrap()
+            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+            .unwrap();
+        assert_eq!(1, qres.count);
+    }
+
+    #[test]
+    fn reflect_error() {
+        // set personal balance
+        let owner = Addr::unchecked("owner");
+        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
+
+        let mut app = custom_app::<CustomMsg, Empty, _>(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &owner, init_funds)
+                .unwrap();
+        });
+
+        // set up reflect contract
+        let reflect_id = app.store_code(reflect::contract());
+        let reflect_addr = app
+            .instantiate_contract(
+                reflect_id,
+                owner,
+                &EmptyMsg {},
+                &coins(40, "eth"),
+                "Reflect",
+                None,
+            )
+            .unwrap();
+
+        // reflect has 40 eth
+        let funds = get_balance(&app, &reflect_addr);
+        assert_eq!(funds, coins(40, "eth"));
+        let random = Addr::unchecked("random");
+
+        // sending 7 eth works
+        let msg = SubMsg::new(BankMsg::Send {
+            to_address: random.clone().into(),
+            amount: coins(7, "eth"),
+        });
+        let msgs = reflect::Message {
+            messages: vec![msg],
+        };
+        let res = app
+            .execute_contract(random.clone(), reflect_addr.clone(), &msgs, &[])
+            .unwrap();
+        // no wasm events as no attributes
+        assert_eq!(2, res.events.len());
+        // standard wasm-execute event
+        let exec = &res.events[0];
+        assert_eq!(exec.ty.as_str(), "execute");
+        assert_eq!(exec.attributes, [("_contract_addr", &reflect_addr)]);
+        // only transfer event from bank
+        let transfer = &res.events[1];
+        assert_eq!(transfer.ty.as_str(), "transfer");
+
+        // ensure random got paid
+        let funds = get_balance(&app, &random);
+        assert_eq!(funds, coins(7, "eth"));
+
+        // reflect count should be updated to 1
+        let qres: payout::CountResponse = app
+            .wrap()
+            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+            .unwrap();
+        assert_eq!(1, qres.count);
+
+        // sending 8 eth, then 3 btc should fail both
+        let msg = SubMsg::new(BankMsg::Send {
+            to_address: random.clone().into(),
+            amount: coins(8, "eth"),
+        });
+        let msg2 = SubMsg::new(BankMsg::Send {
+            to_address: random.clone().into(),
+            amount: coins(3, "btc"),
+        });
+        let msgs = reflect::Message {
+            messages: vec![msg, msg2],
+        };
+        let err = app
+            .execute_contract(random.clone(), reflect_addr.clone(), &msgs, &[])
+            .unwrap_err();
+        assert_eq!(
+            BankError::InsufficientFunds {
+                needed: coin(3, "btc"),
+                available: coin(0, "btc"),
+            },
+            err.downcast().unwrap()
+        );
+
+        // first one should have been rolled-back on error (no second payment)
+        let funds = get_balance(&app, &random);
+        assert_eq!(funds, coins(7, "eth"));
+
+        // failure should not update reflect count
+        let qres: payout::CountResponse = app
+            .wrap()
+            .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Count {})
+            .unwrap();
+        assert_eq!(1, qres.count);
+    }
+
+    #[test]
+    fn sudo_works() {
+        let owner = Addr::unchecked("owner");
+        let init_funds = vec![coin(100, "eth")];
+
+        let mut app = App::new(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &owner, init_funds)
+                .unwrap();
+        });
+
+        let payout_id = app.store_code(payout::contract());
+        let msg = payout::InstantiateMessage {
+            payout: coin(5, "eth"),
+        };
+        let payout_addr = app
+            .instantiate_contract(payout_id, owner, &msg, &coins(23, "eth"), "Payout", None)
+            .unwrap();
+
+        // count is 1
+        let payout::CountResponse { count } = app
+            .wrap()
+            .query_wasm_smart(&payout_addr, &payout::QueryMsg::Count {})
+            .unwrap();
+        assert_eq!(1, count);
+
+        // wasm_sudo call
+        let msg = payout::SudoMsg { set_count: 25 };
+        app.wasm_sudo(payout_addr.clone(), &msg).unwrap();
+
+        // count is 25
+        let payout::CountResponse { count } = app
+            .wrap()
+            .query_wasm_smart(&payout_addr, &payout::QueryMsg::Count {})
+            .unwrap();
+        assert_eq!(25, count);
+
+        // we can do the same with sudo call
+        let msg = payout::SudoMsg { set_count: 49 };
+        let sudo_msg = WasmSudo {
+            contract_addr: payout_addr.clone(),
+            msg: to_binary(&msg).unwrap(),
+        };
+        app.sudo(sudo_msg.into()).unwrap();
+
+        let payout::CountResponse { count } = app
+            .wrap()
+            .query_wasm_smart(&payout_addr, &payout::QueryMsg::Count {})
+            .unwrap();
+        assert_eq!(49, count);
+    }
+
+    // this demonstrates that we can mint tokens and send from other accounts via a custom module,
+    // as an example of ability to do privileged actions
+    mod custom_handler {
+        use super::*;
+
+        use anyhow::{bail, Result as AnyResult};
+        use cw_storage_plus::Item;
+        use serde::{Deserialize, Serialize};
+
+        use crate::Executor;
+
+        const LOTTERY: Item<Coin> = Item::new("lottery");
+        const PITY: Item<Coin> = Item::new("pity");
+
+        #[derive(Clone, std::fmt::Debug, PartialEq, JsonSchema, Serialize, Deserialize)]
+        struct CustomMsg {
+            // we mint LOTTERY tokens to this one
+            lucky_winner: String,
+            // we transfer PITY from lucky_winner to runner_up
+            runner_up: String,
+        }
+
+        struct CustomHandler {}
+
+        impl Module for CustomHandler {
+            type ExecT = CustomMsg;
+            type QueryT = Empty;
             type SudoT = Empty;
 
-            fn execute<ExecC, QueryC>(
-                &self,
-                api: &dyn Api,
-                storage: &mut dyn Storage,
-                router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
-                block: &BlockInfo,
-                _sender: Addr,
-                msg: Self::ExecT,
-            ) -> AnyResult<AppResponse>
-            where
-                ExecC:
-                    std::fmt::Debug + Clone + PartialEq + JsonSchema + DeserializeOwned + 'static,
-                QueryC: CustomQuery + DeserializeOwned + 'static,
-            {
-                let lottery = LOTTERY.load(storage)?;
-                let pity = PITY.load(storage)?;
+            fn execute<ExecC, QueryC>(
+                &self,
+                api: &dyn Api,
+                storage: &mut dyn Storage,
+                router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+                block: &BlockInfo,
+                _sender: Addr,
+                msg: Self::ExecT,
+            ) -> AnyResult<AppResponse>
+            where
+                ExecC:
+                    std::fmt::Debug + Clone + PartialEq + JsonSchema + DeserializeOwned + 'static,
+                QueryC: CustomQuery + DeserializeOwned + 'static,
+            {
+                let lottery = LOTTERY.load(storage)?;
+                let pity = PITY.load(storage)?;
+
+                // mint new tokens
+                let mint = BankSudo::Mint {
+                    to_address: msg.lucky_winner.clone(),
+                    amount: vec![lottery],
+                };
+                router.sudo(api, storage, block, mint.into())?;
+
+                // send from an arbitrary account (not the module)
+                let transfer = BankMsg::Send {
+                    to_address: msg.runner_up,
+                    amount: vec![pity],
+                };
+                let rcpt = api.addr_validate(&msg.lucky_winner)?;
+                router.execute(api, storage, block, rcpt, transfer.into())?;
+
+                Ok(AppResponse::default())
+            }
+
+            fn sudo<ExecC, QueryC>(
+                &self,
+                _api: &dyn Api,
+                _storage: &mut dyn Storage,
+                _router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+                _block: &BlockInfo,
+                _msg: Self::SudoT,
+            ) -> AnyResult<AppResponse>
+            where
+                ExecC:
+                    std::fmt::Debug + Clone + PartialEq + JsonSchema + DeserializeOwned + 'static,
+                QueryC: CustomQuery + DeserializeOwned + 'static,
+            {
+                bail!("sudo not implemented for CustomHandler")
+            }
+
+            fn query(
+                &self,
+                _api: &dyn Api,
+                _storage: &dyn Storage,
+                _querier: &dyn Querier,
+                _block: &BlockInfo,
+                _request: Self::QueryT,
+            ) -> AnyResult<Binary> {
+                bail!("query not implemented for CustomHandler")
+            }
+        }
+
+        impl CustomHandler {
+            // this is a custom initialization method
+            pub fn set_payout(
+                &self,
+                storage: &mut dyn Storage,
+                lottery: Coin,
+                pity: Coin,
+            ) -> AnyResult<()> {
+                LOTTERY.save(storage, &lottery)?;
+                PITY.save(storage, &pity)?;
+                Ok(())
+            }
+        }
+
+        // let's call this custom handler
+        #[test]
+        fn dispatches_messages() {
+            let winner = "winner".to_string();
+            let second = "second".to_string();
+
+            // payments. note 54321 - 12321 = 42000
+            let denom = "tix";
+            let lottery = coin(54321, denom);
+            let bonus = coin(12321, denom);
+
+            let mut app = BasicAppBuilder::<CustomMsg, Empty>::new_custom()
+                .with_custom(CustomHandler {})
+                .build(|router, _, storage| {
+                    router
+                        .custom
+                        .set_payout(storage, lottery.clone(), bonus.clone())
+                        .unwrap();
+                });
+
+            // query that balances are empty
+            let start = app.wrap().query_balance(&winner, denom).unwrap();
+            assert_eq!(start, coin(0, denom));
+
+            // trigger the custom module
+            let msg = CosmosMsg::Custom(CustomMsg {
+                lucky_winner: winner.clone(),
+                runner_up: second.clone(),
+            });
+            app.execute(Addr::unchecked("anyone"), msg).unwrap();
+
+            // see if coins were properly added
+            let big_win = app.wrap().query_balance(&winner, denom).unwrap();
+            assert_eq!(big_win, coin(42000, denom));
+            let little_win = app.wrap().query_balance(&second, denom).unwrap();
+            assert_eq!(little_win, bonus);
+        }
+    }
+
+    #[test]
+    fn reflect_submessage_reply_works() {
+        // set personal balance
+        let owner = Addr::unchecked("owner");
+        let random = Addr::unchecked("random");
+        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
+
+        let mut app = custom_app::<CustomMsg, Empty, _>(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &owner, init_funds)
+                .unwrap();
+        });
+
+        // set up reflect contract
+        let reflect_id = app.store_code(reflect::contract());
+        let reflect_addr = app
+            .instantiate_contract(
+                reflect_id,
+                owner,
+                &EmptyMsg {},
+                &coins(40, "eth"),
+                "Reflect",
+                None,
+            )
+            .unwrap();
+
+        // no reply writen beforehand
+        let query = reflect::QueryMsg::Reply { id: 123 };
+        let res: StdResult<Reply> = app.wrap().query_wasm_smart(&reflect_addr, &query);
+        res.unwrap_err();
+
+        // reflect sends 7 eth, success
+        let msg = SubMsg::reply_always(
+            BankMsg::Send {
+                to_address: random.clone().into(),
+                amount: coins(7, "eth"),
+            },
+            123,
+        );
+        let msgs = reflect::Message {
+            messages: vec![msg],
+        };
+        let res = app
+            .execute_contract(random.clone(), reflect_addr.clone(), &msgs, &[])
+            .unwrap();
+
+        // expected events: execute, transfer, reply, custom wasm (set in reply)
+        assert_eq!(4, res.events.len(), "{:?}", res.events);
+        res.assert_event(&Event::new("execute").add_attribute("_contract_addr", &reflect_addr));
+        res.assert_event(&Event::new("transfer").add_attribute("amount", "7eth"));
+        res.assert_event(
+            &Event::new("reply")
+                .add_attribute("_contract_addr", reflect_addr.as_str())
+                .add_attribute("mode", "handle_success"),
+        );
+        res.assert_event(&Event::new("wasm-custom").add_attribute("from", "reply"));
+
+        // ensure success was written
+        let res: Reply = app.wrap().query_wasm_smart(&reflect_addr, &query).unwrap();
+        assert_eq!(res.id, 123);
+        // validate the events written in the reply blob...should just be bank transfer
+        let reply = res.result.unwrap();
+        assert_eq!(1, reply.events.len());
+        AppResponse::from(reply)
+            .assert_event(&Event::new("transfer").add_attribute("amount", "7eth"));
+
+        // reflect sends 300 btc, failure, but error caught by submessage (so shows success)
+        let msg = SubMsg::reply_always(
+            BankMsg::Send {
+                to_address: random.clone().into(),
+                amount: coins(300, "btc"),
+            },
+            456,
+        );
+        let msgs = reflect::Message {
+            messages: vec![msg],
+        };
+        let _res = app
+            .execute_contract(random, reflect_addr.clone(), &msgs, &[])
+            .unwrap();
+
+        // ensure error was written
+        let query = reflect::QueryMsg::Reply { id: 456 };
+        let res: Reply = app.wrap().query_wasm_smart(&reflect_addr, &query).unwrap();
+        assert_eq!(res.id, 456);
+        assert_eq!(
+            res.result.unwrap_err(),
+            "Insufficient funds: needed 300btc, available 0btc"
+        );
+    }
+
+    /// Sends failing submessages from a reflect contract and returns the errors its `reply` saw
+    fn redacted_reply_errors(wasm: WasmKeeper<CustomMsg, Empty>) -> Vec<String> {
+        let owner = Addr::unchecked("owner");
+        let mut app = BasicAppBuilder::<CustomMsg, Empty>::new_custom()
+            .with_wasm::<FailingModule<CustomMsg, Empty, Empty>, _>(wasm)
+            .build(|_, _, _| {});
+
+        let reflect_id = app.store_code(reflect::contract());
+        let reflect_addr = app
+            .instantiate_contract(
+                reflect_id,
+                owner.clone(),
+                &EmptyMsg {},
+                &[],
+                "Reflect",
+                None,
+            )
+            .unwrap();
+
+        let failing_msgs: Vec<CosmosMsg<CustomMsg>> = vec![
+            // contract failing on its invalid message
+            WasmMsg::Execute {
+                contract_addr: reflect_addr.to_string(),
+                msg: Binary::from(b"invalid".as_ref()),
+                funds: vec![],
+            }
+            .into(),
+            // module errors, not coming from a contract
+            BankMsg::Send {
+                to_address: owner.to_string(),
+                amount: coins(300, "btc"),
+            }
+            .into(),
+            WasmMsg::Instantiate {
+                admin: None,
+                code_id: 1234,
+                msg: to_binary(&EmptyMsg {}).unwrap(),
+                funds: vec![],
+                label: "Unknown".to_owned(),
+            }
+            .into(),
+            CosmosMsg::Custom(CustomMsg::SetName {
+                name: "failing".to_owned(),
+            }),
+        ];
+        let ids = 1..=failing_msgs.len() as u64;
+        let msgs = reflect::Message {
+            messages: failing_msgs
+                .into_iter()
+                .zip(ids.clone())
+                .map(|(msg, id)| SubMsg::reply_on_error(msg, id))
+                .collect(),
+        };
+        app.execute_contract(owner, reflect_addr.clone(), &msgs, &[])
+            .unwrap();
+
+        ids.map(|id| {
+            let reply: Reply = app
+                .wrap()
+                .query_wasm_smart(&reflect_addr, &reflect::QueryMsg::Reply { id })
+                .unwrap();
+            reply.result.unwrap_err()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn reply_sees_redacted_errors() {
+        let errors = redacted_reply_errors(WasmKeeper::new().with_error_redaction(true));
+        assert_eq!(
+            errors,
+            vec![
+                "codespace: wasm, code: 5",
+                "codespace: sdk, code: 5",
+                "codespace: wasm, code: 8",
+                "codespace: undefined, code: 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn reply_sees_custom_redacted_errors() {
+        let errors = redacted_reply_errors(WasmKeeper::new().with_custom_error_redaction(|err| {
+            if err.downcast_ref::<BankError>().is_some() {
+                AbciCode::new("bank", 2)
+            } else {
+                AbciCode::of(err)
+            }
+        }));
+        assert_eq!(
+            errors,
+            vec![
+                "codespace: wasm, code: 5",
+                "codespace: bank, code: 2",
+                "codespace: wasm, code: 8",
+                "codespace: undefined, code: 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn call_depth_is_limited() {
+        let owner = Addr::unchecked("owner");
+        let mut app = AppBuilder::new()
+            .with_wasm::<FailingModule<Empty, Empty, Empty>, _>(
+                WasmKeeper::new().with_max_call_depth(10),
+            )
+            .build(|_, _, _| {});
+
+        let code_id = app.store_code(recurse::contract());
+        let contract = app
+            .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "Recurse", None)
+            .unwrap();
+
+        // the first call and 9 nested ones fit the limit
+        app.execute_contract(
+            owner.clone(),
+            contract.clone(),
+            &recurse::Message { depth: 9 },
+            &[],
+        )
+        .unwrap();
+
+        let err = app
+            .execute_contract(owner, contract, &recurse::Message { depth: 10 }, &[])
+            .unwrap_err();
+        assert_eq!(
+            WasmError::CallDepthExceeded { max: 10 },
+            err.downcast().unwrap()
+        );
+    }
+
+    #[test]
+    fn default_call_depth_limit_prevents_stack_overflow() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+
+        let code_id = app.store_code(recurse::contract());
+        let contract = app
+            .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "Recurse", None)
+            .unwrap();
+
+        let err = app
+            .execute_contract(owner, contract, &recurse::Message { depth: u32::MAX }, &[])
+            .unwrap_err();
+        assert_eq!(
+            WasmError::CallDepthExceeded {
+                max: DEFAULT_MAX_CALL_DEPTH
+            },
+            err.downcast().unwrap()
+        );
+    }
+
+    #[test]
+    fn query_depth_is_limited() {
+        let mut app = App::default();
+        let code_id = app.store_code(recurse::contract());
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                Addr::unchecked("owner"),
+                &EmptyMsg {},
+                &[],
+                "Recurse",
+                None,
+            )
+            .unwrap();
+
+        // the outermost query and 9 nested ones fit the limit
+        let depth: u32 = app
+            .wrap()
+            .query_wasm_smart(&contract, &recurse::Message { depth: 9 })
+            .unwrap();
+        assert_eq!(depth, 9);
+
+        let err = app
+            .wrap()
+            .query_wasm_smart::<u32>(&contract, &recurse::Message { depth: 10 })
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Exceeded recursion limit of 10 nested queries"));
+    }
+
+    #[test]
+    fn query_gas_is_limited() {
+        let mut app = AppBuilder::new()
+            .with_wasm::<FailingModule<Empty, Empty, Empty>, _>(
+                WasmKeeper::new().with_query_gas_limit(3 * INSTANCE_COST),
+            )
+            .build(|_, _, _| {});
+        let code_id = app.store_code(recurse::contract());
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                Addr::unchecked("owner"),
+                &EmptyMsg {},
+                &[],
+                "Recurse",
+                None,
+            )
+            .unwrap();
+
+        // the recursive query doesn't read the storage, so every query costs the instance cost
+        let depth: u32 = app
+            .wrap()
+            .query_wasm_smart(&contract, &recurse::Message { depth: 2 })
+            .unwrap();
+        assert_eq!(depth, 2);
+
+        let err = app
+            .wrap()
+            .query_wasm_smart::<u32>(&contract, &recurse::Message { depth: 3 })
+            .unwrap_err();
+        assert!(err.to_string().contains(&format!(
+            "Query ran out of gas, used {} of {}",
+            4 * INSTANCE_COST,
+            3 * INSTANCE_COST
+        )));
+    }
+
+    #[test]
+    fn query_gas_limit_stops_storage_reads() {
+        use crate::ContractWrapper;
+        use cosmwasm_std::{Deps, DepsMut, Env, MessageInfo, Response};
+
+        let mut app = AppBuilder::new()
+            .with_wasm::<FailingModule<Empty, Empty, Empty>, _>(
+                WasmKeeper::new().with_query_gas_limit(10 * INSTANCE_COST),
+            )
+            .build(|_, _, _| {});
+
+        // the query reads the same key until it is not found, which only happens out of gas
+        let execute =
+            |_: DepsMut, _: Env, _: MessageInfo, _: EmptyMsg| StdResult::Ok(Response::new());
+        let instantiate = |deps: DepsMut, _: Env, _: MessageInfo, _: EmptyMsg| {
+            deps.storage.set(b"key", b"value");
+            StdResult::Ok(Response::new())
+        };
+        let query = |deps: Deps, _: Env, _: EmptyMsg| {
+            let mut reads = 0u32;
+            while deps.storage.get(b"key").is_some() {
+                reads += 1;
+            }
+            to_binary(&reads)
+        };
+        let code_id = app.store_code(Box::new(ContractWrapper::new_with_closures(
+            Box::new(execute),
+            Box::new(instantiate),
+            Box::new(query),
+        )));
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                Addr::unchecked("owner"),
+                &EmptyMsg {},
+                &[],
+                "Reader",
+                None,
+            )
+            .unwrap();
+
+        let err = app
+            .wrap()
+            .query_wasm_smart::<u32>(&contract, &EmptyMsg {})
+            .unwrap_err();
+        assert!(err.to_string().contains("Query ran out of gas"));
+    }
+
+    #[test]
+    fn upload_code_respects_access() {
+        let deployer = Addr::unchecked("deployer");
+        let mut app = AppBuilder::new()
+            .with_wasm::<FailingModule<Empty, Empty, Empty>, _>(
+                WasmKeeper::new()
+                    .with_upload_access(AccessConfig::AnyOfAddresses(vec![deployer.clone()])),
+            )
+            .build(|_, _, _| {});
+
+        let err = app
+            .upload_code(Addr::unchecked("random"), caller::contract())
+            .unwrap_err();
+        assert_eq!(
+            WasmError::UploadUnauthorized(Addr::unchecked("random")),
+            err.downcast().unwrap()
+        );
+
+        let (code_id, res) = app
+            .upload_code(deployer.clone(), caller::contract())
+            .unwrap();
+        assert_eq!(code_id, 1);
+        let code_data = app
+            .read_module(|router, _, _| router.wasm.code_data(code_id as usize))
+            .unwrap();
+        assert_eq!(code_data.creator, deployer);
+        assert_eq!(
+            res.events,
+            vec![Event::new("store_code")
+                .add_attribute("code_checksum", code_data.checksum.to_hex())
+                .add_attribute("code_id", "1")]
+        );
+        let data: StoreCodeResponse = prost::Message::decode(res.data.unwrap().as_slice()).unwrap();
+        assert_eq!(data.code_id, code_id);
+        assert_eq!(data.checksum, code_data.checksum.to_vec());
+    }
+
+    #[test]
+    fn pin_and_unpin_codes() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+        let code_id = app.store_code(caller::contract());
+        let contract = app
+            .instantiate_contract(code_id, owner, &EmptyMsg {}, &[], "Caller", None)
+            .unwrap();
+        let pinned = |app: &App| {
+            app.wrap()
+                .query_wasm_contract_info(&contract)
+                .unwrap()
+                .pinned
+        };
+        assert!(!pinned(&app));
+
+        let res = app
+            .sudo(
+                WasmAdminSudo::PinCodes {
+                    code_ids: vec![code_id],
+                }
+                .into(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.events,
+            vec![Event::new("pin_code").add_attribute("code_id", code_id.to_string())]
+        );
+        assert!(pinned(&app));
+
+        app.sudo(
+            WasmAdminSudo::UnpinCodes {
+                code_ids: vec![code_id],
+            }
+            .into(),
+        )
+        .unwrap();
+        assert!(!pinned(&app));
+
+        let err = app
+            .sudo(WasmAdminSudo::PinCodes { code_ids: vec![7] }.into())
+            .unwrap_err();
+        assert_eq!(Error::UnregisteredCodeId(7), err.downcast().unwrap());
+    }
+
+    #[test]
+    fn contract_history_records_code_changes() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+        let code_id = app.store_code(hackatom::contract());
+        let new_code_id = app.store_code(hackatom::contract());
+
+        let init_msg = hackatom::InstantiateMsg {
+            beneficiary: "beneficiary".to_owned(),
+        };
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                owner.clone(),
+                &init_msg,
+                &[],
+                "Hackatom",
+                Some(owner.to_string()),
+            )
+            .unwrap();
+        let init_height = app.block_info().height;
+        app.update_block(next_block);
+        // the migration is the second transaction in its block
+        app.instantiate_contract(code_id, owner.clone(), &init_msg, &[], "Other", None)
+            .unwrap();
+        let migrate_msg = hackatom::MigrateMsg {
+            new_guy: "new_guy".to_owned(),
+        };
+        app.migrate_contract(owner, contract.clone(), &migrate_msg, new_code_id)
+            .unwrap();
+
+        let history = app.contract_history(&contract).unwrap();
+        assert_eq!(
+            history,
+            vec![
+                ContractHistoryEntry {
+                    operation: ContractHistoryOperation::Init,
+                    code_id: code_id as usize,
+                    block_height: init_height,
+                    tx_index: 0,
+                    msg: to_binary(&init_msg).unwrap(),
+                },
+                ContractHistoryEntry {
+                    operation: ContractHistoryOperation::Migrate,
+                    code_id: new_code_id as usize,
+                    block_height: init_height + 1,
+                    tx_index: 1,
+                    msg: to_binary(&migrate_msg).unwrap(),
+                },
+            ]
+        );
 
-                // mint new tokens
-                let mint = BankSudo::Mint {
-                    to_address: msg.lucky_winner.clone(),
-                    amount: vec![lottery],
-                };
-                router.sudo(api, storage, block, mint.into())?;
+        // the same history is available to contracts through the Stargate query
+        let request = QueryContractHistoryRequest {
+            address: contract.to_string(),
+            pagination: None,
+        };
+        let res: serde_json::Value = app
+            .wrap()
+            .query(&QueryRequest::Stargate {
+                path: "/cosmwasm.wasm.v1.Query/ContractHistory".to_owned(),
+                data: prost::Message::encode_to_vec(&request).into(),
+            })
+            .unwrap();
+        assert_eq!(
+            res["entries"][1],
+            serde_json::json!({
+                "operation": "CONTRACT_CODE_HISTORY_OPERATION_TYPE_MIGRATE",
+                "code_id": new_code_id.to_string(),
+                "updated": {
+                    "block_height": (init_height + 1).to_string(),
+                    "tx_index": "1",
+                },
+                "msg": { "new_guy": "new_guy" },
+            })
+        );
 
-                // send from an arbitrary account (not the module)
-                let transfer = BankMsg::Send {
-                    to_address: msg.runner_up,
-                    amount: vec![pity],
-                };
-                let rcpt = api.addr_validate(&msg.lucky_winner)?;
-                router.execute(api, storage, block, rcpt, transfer.into())?;
+        // and can be paginated
+        let request = QueryContractHistoryRequest {
+            address: contract.to_string(),
+            pagination: Some(PageRequest {
+                limit: 1,
+                count_total: true,
+                ..PageRequest::default()
+            }),
+        };
+        let res: serde_json::Value = app
+            .wrap()
+            .query(&QueryRequest::Stargate {
+                path: "/cosmwasm.wasm.v1.Query/ContractHistory".to_owned(),
+                data: prost::Message::encode_to_vec(&request).into(),
+            })
+            .unwrap();
+        assert_eq!(res["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            res["entries"][0]["operation"],
+            "CONTRACT_CODE_HISTORY_OPERATION_TYPE_INIT"
+        );
+        assert_eq!(
+            res["pagination"],
+            serde_json::json!({
+                "next_key": Binary::from(1u64.to_be_bytes()),
+                "total": "2",
+            })
+        );
+    }
 
-                Ok(AppResponse::default())
-            }
+    #[test]
+    fn contracts_without_instantiation_have_genesis_history() {
+        let mut app = App::default();
+        let code_id = app.store_code(payout::contract());
+        let contract = app
+            .init_modules(|router, _, storage| {
+                router.wasm.register_contract(
+                    storage,
+                    code_id as usize,
+                    Addr::unchecked("creator"),
+                    None,
+                    "Payout".to_owned(),
+                    7,
+                )
+            })
+            .unwrap();
 
-            fn sudo<ExecC, QueryC>(
-                &self,
-                _api: &dyn Api,
-                _storage: &mut dyn Storage,
-                _router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
-                _block: &BlockInfo,
-                _msg: Self::SudoT,
-            ) -> AnyResult<AppResponse>
-            where
-                ExecC:
-                    std::fmt::Debug + Clone + PartialEq + JsonSchema + DeserializeOwned + 'static,
-                QueryC: CustomQuery + DeserializeOwned + 'static,
-            {
-                bail!("sudo not implemented for CustomHandler")
-            }
+        let history = app.contract_history(&contract).unwrap();
+        assert_eq!(
+            history,
+            vec![ContractHistoryEntry {
+                operation: ContractHistoryOperation::Genesis,
+                code_id: code_id as usize,
+                block_height: 7,
+                tx_index: 0,
+                msg: Binary::default(),
+            }]
+        );
+    }
 
-            fn query(
-                &self,
-                _api: &dyn Api,
-                _storage: &dyn Storage,
-                _querier: &dyn Querier,
-                _block: &BlockInfo,
-                _request: Self::QueryT,
-            ) -> AnyResult<Binary> {
-                bail!("query not implemented for CustomHandler")
-            }
+    #[test]
+    fn list_contracts_by_code_and_creator() {
+        let owner = Addr::unchecked("owner");
+        let factory = Addr::unchecked("factory");
+        let mut app = App::default();
+        let code_id = app.store_code(hackatom::contract());
+        let new_code_id = app.store_code(hackatom::contract());
+        let init_msg = hackatom::InstantiateMsg {
+            beneficiary: "beneficiary".to_owned(),
+        };
+        let instantiate = |app: &mut App, sender: &Addr| {
+            app.instantiate_contract(
+                code_id,
+                sender.clone(),
+                &init_msg,
+                &[],
+                "Hackatom",
+                Some(owner.to_string()),
+            )
+            .unwrap()
+        };
+        let first = instantiate(&mut app, &owner);
+        let second = instantiate(&mut app, &factory);
+        // enough contracts for instantiation order to differ from address order
+        let mut children = vec![second.clone()];
+        for _ in 0..10 {
+            children.push(instantiate(&mut app, &factory));
         }
+        let third = children[1].clone();
+        let last = children[10].clone();
+        assert_eq!(last, Addr::unchecked("contract11"));
+
+        let mut all = vec![first.clone()];
+        all.extend(children.iter().cloned());
+        assert_eq!(app.contracts_by_code(code_id).unwrap(), all);
+        assert_eq!(app.contracts_by_creator(&factory).unwrap(), children);
+        assert!(app
+            .contracts_by_creator(&Addr::unchecked("random"))
+            .unwrap()
+            .is_empty());
+
+        // migrated contracts are listed under their new code, after the contracts which used it
+        // before them
+        let migrate_msg = hackatom::MigrateMsg {
+            new_guy: "new_guy".to_owned(),
+        };
+        app.migrate_contract(owner.clone(), last.clone(), &migrate_msg, new_code_id)
+            .unwrap();
+        app.migrate_contract(owner.clone(), second.clone(), &migrate_msg, new_code_id)
+            .unwrap();
+        assert_eq!(
+            app.contracts_by_code(new_code_id).unwrap(),
+            vec![last.clone(), second.clone()]
+        );
+        app.migrate_contract(owner.clone(), second.clone(), &migrate_msg, code_id)
+            .unwrap();
+        assert_eq!(
+            app.contracts_by_code(code_id).unwrap(),
+            [&all[..1], &all[2..11], &all[1..2]].concat()
+        );
+        assert_eq!(
+            app.contracts_by_code(new_code_id).unwrap(),
+            vec![last.clone()]
+        );
+        // the creator list keeps instantiation order
+        assert_eq!(app.contracts_by_creator(&factory).unwrap(), children);
 
-        impl CustomHandler {
-            // this is a custom initialization method
-            pub fn set_payout(
-                &self,
-                storage: &mut dyn Storage,
-                lottery: Coin,
-                pity: Coin,
-            ) -> AnyResult<()> {
-                LOTTERY.save(storage, &lottery)?;
-                PITY.save(storage, &pity)?;
-                Ok(())
-            }
-        }
+        // the same lists are available to contracts through Stargate queries
+        let request = QueryContractsByCodeRequest {
+            code_id: new_code_id,
+            pagination: None,
+        };
+        let res: serde_json::Value = app
+            .wrap()
+            .query(&QueryRequest::Stargate {
+                path: "/cosmwasm.wasm.v1.Query/ContractsByCode".to_owned(),
+                data: prost::Message::encode_to_vec(&request).into(),
+            })
+            .unwrap();
+        assert_eq!(
+            res,
+            serde_json::json!({
+                "contracts": [last],
+                "pagination": { "next_key": null, "total": "0" },
+            })
+        );
 
-        // let's call this custom handler
-        #[test]
-        fn dispatches_messages() {
-            let winner = "winner".to_string();
-            let second = "second".to_string();
+        // and can be paginated
+        let query_by_creator = |pagination| -> serde_json::Value {
+            let request = QueryContractsByCreatorRequest {
+                creator_address: factory.to_string(),
+                pagination: Some(pagination),
+            };
+            app.wrap()
+                .query(&QueryRequest::Stargate {
+                    path: "/cosmwasm.wasm.v1.Query/ContractsByCreator".to_owned(),
+                    data: prost::Message::encode_to_vec(&request).into(),
+                })
+                .unwrap()
+        };
+        let res = query_by_creator(PageRequest {
+            limit: 2,
+            count_total: true,
+            ..PageRequest::default()
+        });
+        assert_eq!(
+            res["contract_addresses"],
+            serde_json::json!([second, third])
+        );
+        assert_eq!(res["pagination"]["total"], "11");
+        let next_key: Binary =
+            serde_json::from_value(res["pagination"]["next_key"].clone()).unwrap();
+
+        let res = query_by_creator(PageRequest {
+            key: next_key.to_vec(),
+            limit: 9,
+            ..PageRequest::default()
+        });
+        assert_eq!(res["contract_addresses"], serde_json::json!(children[2..]));
+        assert_eq!(res["pagination"]["next_key"], serde_json::Value::Null);
+
+        let res = query_by_creator(PageRequest {
+            offset: 1,
+            limit: 2,
+            reverse: true,
+            ..PageRequest::default()
+        });
+        assert_eq!(
+            res["contract_addresses"],
+            serde_json::json!([children[9], children[8]])
+        );
 
-            // payments. note 54321 - 12321 = 42000
-            let denom = "tix";
-            let lottery = coin(54321, denom);
-            let bonus = coin(12321, denom);
+        // keys other than the `next_key` of a previous page are rejected
+        let request = QueryContractsByCreatorRequest {
+            creator_address: factory.to_string(),
+            pagination: Some(PageRequest {
+                key: vec![1, 2, 3],
+                ..PageRequest::default()
+            }),
+        };
+        let block = app.block_info();
+        let err = app
+            .read_module(|router, api, storage| {
+                router.wasm.query_stargate(
+                    api,
+                    storage,
+                    &block,
+                    "/cosmwasm.wasm.v1.Query/ContractsByCreator".to_owned(),
+                    prost::Message::encode_to_vec(&request).into(),
+                )
+            })
+            .unwrap_err();
+        assert_eq!(
+            WasmError::InvalidPaginationKey(Binary::from(vec![1, 2, 3])),
+            err.downcast().unwrap()
+        );
+    }
 
-            let mut app = BasicAppBuilder::<CustomMsg, Empty>::new_custom()
-                .with_custom(CustomHandler {})
-                .build(|router, _, storage| {
-                    router
-                        .custom
-                        .set_payout(storage, lottery.clone(), bonus.clone())
-                        .unwrap();
-                });
+    #[test]
+    fn store_code_with_name() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+        let caller_id = app.store_code(caller::contract());
+        let code_id = app
+            .store_code_with_name("hackatom-v1", hackatom::contract())
+            .unwrap();
+        let new_code_id = app
+            .store_code_with_name("hackatom-v2", hackatom::contract())
+            .unwrap();
+        assert_eq!(app.code_id_by_name("hackatom-v1").unwrap(), code_id);
+        assert_eq!(app.code_id_by_name("hackatom-v2").unwrap(), new_code_id);
+
+        let err = app
+            .store_code_with_name("hackatom-v1", hackatom::contract())
+            .unwrap_err();
+        assert_eq!(
+            WasmError::DuplicateCodeName("hackatom-v1".to_owned()),
+            err.downcast().unwrap()
+        );
+        let err = app.code_id_by_name("cw20-base").unwrap_err();
+        assert_eq!(
+            WasmError::UnknownCodeName("cw20-base".to_owned()),
+            err.downcast().unwrap()
+        );
+
+        let contract = app
+            .instantiate_contract(
+                app.code_id_by_name("hackatom-v1").unwrap(),
+                owner.clone(),
+                &hackatom::InstantiateMsg {
+                    beneficiary: "beneficiary".to_owned(),
+                },
+                &[],
+                "Hackatom",
+                Some(owner.to_string()),
+            )
+            .unwrap();
+        let contract_data = app.contract_data(&contract).unwrap();
+        assert_eq!(contract_data.code_name.as_deref(), Some("hackatom-v1"));
+        assert!(format!("{:?}", contract_data).contains("hackatom-v1"));
+
+        // the name follows the code the contract uses, and shows up in traces
+        app.enable_tracing();
+        let migrate_msg = hackatom::MigrateMsg {
+            new_guy: "new_guy".to_owned(),
+        };
+        app.migrate_contract(owner, contract.clone(), &migrate_msg, new_code_id)
+            .unwrap();
+        assert_eq!(
+            app.contract_data(&contract).unwrap().code_name.as_deref(),
+            Some("hackatom-v2")
+        );
+
+        let trace = app.last_trace().unwrap();
+        assert_eq!(trace.calls[0].code_name.as_deref(), Some("hackatom-v2"));
+        assert!(trace
+            .to_string()
+            .starts_with(&format!("msg owner -> {} (hackatom-v2)", contract)));
+
+        let code_data = app
+            .read_module(|router, _, _| router.wasm.code_data(caller_id as usize))
+            .unwrap();
+        assert_eq!(code_data.name, None);
+    }
+
+    #[test]
+    fn lookup_contracts_by_label() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::default();
+        let code_id = app.store_code(caller::contract());
+        let instantiate = |app: &mut App, label: &str| {
+            app.instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], label, None)
+        };
+        let core = instantiate(&mut app, "dao-core").unwrap();
+        let voting = instantiate(&mut app, "dao-voting").unwrap();
+        assert_eq!(app.contract_by_label("dao-core").unwrap(), core);
+        assert_eq!(app.contract_by_label("dao-voting").unwrap(), voting);
+
+        let err = app.contract_by_label("dao-proposal").unwrap_err();
+        assert_eq!(
+            WasmError::UnknownLabel("dao-proposal".to_owned()),
+            err.downcast().unwrap()
+        );
+
+        // labels don't have to be unique by default
+        let other = instantiate(&mut app, "dao-core").unwrap();
+        let err = app.contract_by_label("dao-core").unwrap_err();
+        assert_eq!(
+            WasmError::AmbiguousLabel {
+                label: "dao-core".to_owned(),
+                contracts: vec![core.clone(), other.clone()],
+            },
+            err.downcast().unwrap()
+        );
+
+        let res = app
+            .sudo(
+                WasmAdminSudo::UpdateContractLabel {
+                    contract_addr: other.to_string(),
+                    new_label: "dao-core-v2".to_owned(),
+                }
+                .into(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.events,
+            vec![Event::new("update_contract_label")
+                .add_attribute("_contract_addr", &other)
+                .add_attribute("new_label", "dao-core-v2")]
+        );
+        assert_eq!(app.contract_data(&other).unwrap().label, "dao-core-v2");
+        assert_eq!(app.contract_by_label("dao-core").unwrap(), core);
+        assert_eq!(app.contract_by_label("dao-core-v2").unwrap(), other);
+    }
+
+    #[test]
+    fn unique_labels_can_be_enforced() {
+        let owner = Addr::unchecked("owner");
+        let mut app = AppBuilder::new()
+            .with_wasm::<FailingModule<Empty, Empty, Empty>, _>(
+                WasmKeeper::new().with_unique_labels(true),
+            )
+            .build(|_, _, _| {});
+        let code_id = app.store_code(caller::contract());
+        let instantiate = |app: &mut App, label: &str| {
+            app.instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], label, None)
+        };
+        let core = instantiate(&mut app, "dao-core").unwrap();
+        let voting = instantiate(&mut app, "dao-voting").unwrap();
+
+        let err = instantiate(&mut app, "dao-core").unwrap_err();
+        let duplicate = WasmError::DuplicateLabel {
+            label: "dao-core".to_owned(),
+            contract: core.clone(),
+        };
+        assert_eq!(duplicate, err.downcast().unwrap());
+
+        let err = app
+            .sudo(
+                WasmAdminSudo::UpdateContractLabel {
+                    contract_addr: voting.to_string(),
+                    new_label: "dao-core".to_owned(),
+                }
+                .into(),
+            )
+            .unwrap_err();
+        assert_eq!(duplicate, err.downcast().unwrap());
+        assert_eq!(app.contract_by_label("dao-voting").unwrap(), voting);
+    }
+
+    #[test]
+    fn instantiate_permissions() {
+        let owner = Addr::unchecked("owner");
+        let factory = Addr::unchecked("factory");
+        let mut app = App::default();
+        let code_id = app.store_code(caller::contract());
+
+        let instantiate = |app: &mut App, sender: &Addr| {
+            app.instantiate_contract(code_id, sender.clone(), &EmptyMsg {}, &[], "Caller", None)
+        };
+        let assert_unauthorized = |err: anyhow::Error, sender: &Addr| {
+            assert_eq!(
+                WasmError::InstantiateUnauthorized {
+                    code_id: code_id as usize,
+                    sender: sender.clone(),
+                },
+                err.downcast().unwrap()
+            );
+        };
+
+        // everybody can instantiate by default
+        instantiate(&mut app, &owner).unwrap();
 
-            // query that balances are empty
-            let start = app.wrap().query_balance(&winner, denom).unwrap();
-            assert_eq!(start, coin(0, denom));
+        app.set_instantiate_permission(code_id, AccessConfig::Nobody)
+            .unwrap();
+        assert_unauthorized(instantiate(&mut app, &owner).unwrap_err(), &owner);
 
-            // trigger the custom module
-            let msg = CosmosMsg::Custom(CustomMsg {
-                lucky_winner: winner.clone(),
-                runner_up: second.clone(),
-            });
-            app.execute(Addr::unchecked("anyone"), msg).unwrap();
+        app.set_instantiate_permission(
+            code_id,
+            AccessConfig::AnyOfAddresses(vec![factory.clone()]),
+        )
+        .unwrap();
+        assert_unauthorized(instantiate(&mut app, &owner).unwrap_err(), &owner);
+        instantiate(&mut app, &factory).unwrap();
 
-            // see if coins were properly added
-            let big_win = app.wrap().query_balance(&winner, denom).unwrap();
-            assert_eq!(big_win, coin(42000, denom));
-            let little_win = app.wrap().query_balance(&second, denom).unwrap();
-            assert_eq!(little_win, bonus);
-        }
+        app.set_instantiate_permission(code_id + 1, AccessConfig::Nobody)
+            .unwrap_err();
     }
 
+    #[cfg(feature = "cosmwasm_1_2")]
     #[test]
-    fn reflect_submessage_reply_works() {
-        // set personal balance
+    fn instantiate2_uses_predictable_address() {
+        use cw_utils::parse_instantiate_response_data;
+
         let owner = Addr::unchecked("owner");
-        let random = Addr::unchecked("random");
-        let init_funds = vec![coin(20, "btc"), coin(100, "eth")];
+        let mut app = App::default();
+        let code_id = app.store_code(caller::contract());
 
-        let mut app = custom_app::<CustomMsg, Empty, _>(|router, _, storage| {
-            router
-                .bank
-                .init_balance(storage, &owner, init_funds)
-                .unwrap();
-        });
+        let instantiate2 = |app: &mut App, sender: &Addr, salt: &[u8]| {
+            let msg = WasmMsg::Instantiate2 {
+                admin: None,
+                code_id,
+                label: "Caller".to_owned(),
+                msg: to_binary(&EmptyMsg {}).unwrap(),
+                funds: vec![],
+                salt: Binary::from(salt),
+            };
+            app.execute(sender.clone(), msg.into())
+        };
 
-        // set up reflect contract
-        let reflect_id = app.store_code(reflect::contract());
-        let reflect_addr = app
+        let contract_addr = |res: AppResponse| {
+            let data = parse_instantiate_response_data(res.data.unwrap().as_slice()).unwrap();
+            Addr::unchecked(data.contract_address)
+        };
+        let expected = contract_addr(instantiate2(&mut app, &owner, b"salt").unwrap());
+        app.contract_data(&expected).unwrap();
+
+        // the address only depends on code, creator and salt
+        let mut other_app = App::default();
+        other_app.store_code(caller::contract());
+        other_app
+            .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "Caller", None)
+            .unwrap();
+        let other = contract_addr(instantiate2(&mut other_app, &owner, b"salt").unwrap());
+        assert_eq!(other, expected);
+
+        // the same salt can't be used twice, another one can
+        let err = instantiate2(&mut app, &owner, b"salt").unwrap_err();
+        assert_eq!(
+            WasmError::DuplicateContractAddress(expected),
+            err.downcast().unwrap()
+        );
+        instantiate2(&mut app, &owner, b"pepper").unwrap();
+
+        app.set_instantiate_permission(code_id, AccessConfig::Nobody)
+            .unwrap();
+        let err = instantiate2(&mut app, &owner, b"sugar").unwrap_err();
+        assert_eq!(
+            WasmError::InstantiateUnauthorized {
+                code_id: code_id as usize,
+                sender: owner,
+            },
+            err.downcast().unwrap()
+        );
+    }
+
+    fn recurse_app(policy: ReentrancyPolicy) -> (BasicApp, Addr) {
+        let mut app = AppBuilder::new()
+            .with_wasm::<FailingModule<Empty, Empty, Empty>, _>(
+                WasmKeeper::new().with_reentrancy_policy(policy),
+            )
+            .build(|_, _, _| {});
+        app.enable_tracing();
+
+        let code_id = app.store_code(recurse::contract());
+        let contract = app
             .instantiate_contract(
-                reflect_id,
-                owner,
+                code_id,
+                Addr::unchecked("owner"),
                 &EmptyMsg {},
-                &coins(40, "eth"),
-                "Reflect",
+                &[],
+                "Recurse",
                 None,
             )
             .unwrap();
+        (app, contract)
+    }
 
-        // no reply writen beforehand
-        let query = reflect::QueryMsg::Reply { id: 123 };
-        let res: StdResult<Reply> = app.wrap().query_wasm_smart(&reflect_addr, &query);
-        res.unwrap_err();
+    #[test]
+    fn reentrancy_policy() {
+        let owner = Addr::unchecked("owner");
+        // contract calling itself once
+        let msg = recurse::Message { depth: 1 };
 
-        // reflect sends 7 eth, success
-        let msg = SubMsg::reply_always(
-            BankMsg::Send {
-                to_address: random.clone().into(),
-                amount: coins(7, "eth"),
-            },
-            123,
-        );
-        let msgs = reflect::Message {
-            messages: vec![msg],
-        };
-        let res = app
-            .execute_contract(random.clone(), reflect_addr.clone(), &msgs, &[])
+        let (mut app, contract) = recurse_app(ReentrancyPolicy::Allow);
+        app.execute_contract(owner.clone(), contract, &msg, &[])
             .unwrap();
+        let nested = &app.last_trace().unwrap().calls[0].children[0];
+        assert!(nested.warnings.is_empty());
 
-        // expected events: execute, transfer, reply, custom wasm (set in reply)
-        assert_eq!(4, res.events.len(), "{:?}", res.events);
-        res.assert_event(&Event::new("execute").add_attribute("_contract_addr", &reflect_addr));
-        res.assert_event(&Event::new("transfer").add_attribute("amount", "7eth"));
-        res.assert_event(
-            &Event::new("reply")
-                .add_attribute("_contract_addr", reflect_addr.as_str())
-                .add_attribute("mode", "handle_success"),
+        let (mut app, contract) = recurse_app(ReentrancyPolicy::Warn);
+        app.execute_contract(owner.clone(), contract.clone(), &msg, &[])
+            .unwrap();
+        let nested = &app.last_trace().unwrap().calls[0].children[0];
+        assert_eq!(
+            nested.warnings,
+            vec![format!(
+                "Contract {0} re-entered with execute, call stack: {0}",
+                contract
+            )]
         );
-        res.assert_event(&Event::new("wasm-custom").add_attribute("from", "reply"));
 
-        // ensure success was written
-        let res: Reply = app.wrap().query_wasm_smart(&reflect_addr, &query).unwrap();
-        assert_eq!(res.id, 123);
-        // validate the events written in the reply blob...should just be bank transfer
-        let reply = res.result.unwrap();
-        assert_eq!(1, reply.events.len());
-        AppResponse::from(reply)
-            .assert_event(&Event::new("transfer").add_attribute("amount", "7eth"));
+        let (mut app, contract) = recurse_app(ReentrancyPolicy::Fail);
+        let err = app
+            .execute_contract(owner, contract.clone(), &msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            WasmError::Reentrancy {
+                contract: contract.clone(),
+                entry_point: EntryPoint::Execute,
+                path: vec![contract],
+            },
+            err.downcast().unwrap()
+        );
+    }
 
-        // reflect sends 300 btc, failure, but error caught by submessage (so shows success)
-        let msg = SubMsg::reply_always(
+    #[test]
+    fn reply_is_not_reentrancy() {
+        let owner = Addr::unchecked("owner");
+        let mut app = BasicAppBuilder::<CustomMsg, Empty>::new_custom()
+            .with_wasm::<FailingModule<CustomMsg, Empty, Empty>, _>(
+                WasmKeeper::new().with_reentrancy_policy(ReentrancyPolicy::Fail),
+            )
+            .build(|_, _, _| {});
+
+        let reflect_id = app.store_code(reflect::contract());
+        let reflect_addr = app
+            .instantiate_contract(
+                reflect_id,
+                owner.clone(),
+                &EmptyMsg {},
+                &[],
+                "Reflect",
+                None,
+            )
+            .unwrap();
+
+        let failing_send = SubMsg::reply_on_error(
             BankMsg::Send {
-                to_address: random.clone().into(),
+                to_address: owner.to_string(),
                 amount: coins(300, "btc"),
             },
-            456,
+            1,
         );
         let msgs = reflect::Message {
-            messages: vec![msg],
+            messages: vec![failing_send],
         };
-        let _res = app
-            .execute_contract(random, reflect_addr.clone(), &msgs, &[])
+        app.execute_contract(owner, reflect_addr, &msgs, &[])
             .unwrap();
-
-        // ensure error was written
-        let query = reflect::QueryMsg::Reply { id: 456 };
-        let res: Reply = app.wrap().query_wasm_smart(&reflect_addr, &query).unwrap();
-        assert_eq!(res.id, 456);
-        assert!(res.result.is_err());
-        // TODO: check error?
     }
 
     fn query_router<BankT, CustomT, WasmT, StakingT, DistrT, IbcT, GovT>(
@@ -2031,14 +4081,23 @@ mod test {
         assert_eq!(info.admin, Some(owner2.clone()));
 
         // update admin fails if not owner2
-        app.execute(
-            owner.clone(),
-            CosmosMsg::Wasm(WasmMsg::UpdateAdmin {
-                contract_addr: contract.to_string(),
-                admin: owner.to_string(),
-            }),
-        )
-        .unwrap_err();
+        let err = app
+            .execute(
+                owner.clone(),
+                CosmosMsg::Wasm(WasmMsg::UpdateAdmin {
+                    contract_addr: contract.to_string(),
+                    admin: owner.to_string(),
+                }),
+            )
+            .unwrap_err();
+        assert!(err.to_string().starts_with("error executing WasmMsg"));
+        assert_eq!(
+            WasmError::Unauthorized {
+                sender: owner.clone(),
+                admin: Some(owner2.clone()),
+            },
+            err.downcast().unwrap()
+        );
 
         // check admin still the same
         let info = app.contract_data(&contract).unwrap();
@@ -2620,6 +4679,7 @@ mod test {
     mod custom_messages {
         use super::*;
         use crate::custom_handler::CachingCustomHandler;
+        use serde::Deserialize;
 
         #[test]
         fn triggering_custom_msg() {
@@ -2660,6 +4720,237 @@ mod test {
 
             assert!(custom_handler_state.queries().is_empty());
         }
+
+        #[derive(Clone, Debug, PartialEq, JsonSchema, Serialize, Deserialize)]
+        struct PriceQuery {
+            denom: String,
+        }
+
+        impl CustomQuery for PriceQuery {}
+
+        /// Custom types of an app, supersets of the ones the contract is written against
+        #[derive(Clone, Debug, PartialEq, JsonSchema, Serialize, Deserialize)]
+        enum AppMsg {
+            Contract(CustomMsg),
+            Other {},
+        }
+
+        impl From<CustomMsg> for AppMsg {
+            fn from(msg: CustomMsg) -> Self {
+                AppMsg::Contract(msg)
+            }
+        }
+
+        #[derive(Clone, Debug, PartialEq, JsonSchema, Serialize, Deserialize)]
+        enum AppQuery {
+            Price(PriceQuery),
+            Other {},
+        }
+
+        impl CustomQuery for AppQuery {}
+
+        impl From<PriceQuery> for AppQuery {
+            fn from(query: PriceQuery) -> Self {
+                AppQuery::Price(query)
+            }
+        }
+
+        #[test]
+        fn converting_custom_types() {
+            use crate::{ContractWrapper, CustomAdapter};
+            use cosmwasm_std::{to_vec, ContractResult, Deps, DepsMut, Env, MessageInfo, Response};
+
+            let owner = Addr::unchecked("owner");
+            let custom_handler = CachingCustomHandler::<AppMsg, AppQuery>::new();
+            let custom_handler_state = custom_handler.state();
+            let mut app = AppBuilder::new_custom()
+                .with_custom(custom_handler)
+                .build(no_init);
+
+            // contract querying a price, then sending a custom message, with its own types
+            let execute = |deps: DepsMut<PriceQuery>, _: Env, _: MessageInfo, _: EmptyMsg| {
+                let query = QueryRequest::Custom(PriceQuery {
+                    denom: "eth".to_owned(),
+                });
+                match deps.querier.raw_query(&to_vec(&query)?) {
+                    SystemResult::Ok(ContractResult::Ok(_)) => {}
+                    res => return Err(StdError::generic_err(format!("{:?}", res))),
+                }
+                Ok(Response::new().add_message(CosmosMsg::Custom(CustomMsg::SetAge { age: 30 })))
+            };
+            let instantiate = |_: DepsMut<PriceQuery>, _: Env, _: MessageInfo, _: EmptyMsg| {
+                StdResult::Ok(Response::<CustomMsg>::new())
+            };
+            let query = |_: Deps<PriceQuery>, _: Env, _: EmptyMsg| to_binary(&EmptyMsg {});
+            let contract: Box<dyn Contract<CustomMsg, PriceQuery>> =
+                Box::new(ContractWrapper::new_with_closures(
+                    Box::new(execute),
+                    Box::new(instantiate),
+                    Box::new(query),
+                ));
+
+            let code_id = app.store_code(Box::new(CustomAdapter::new(contract)));
+            let contract = app
+                .instantiate_contract(code_id, owner.clone(), &EmptyMsg {}, &[], "Price", None)
+                .unwrap();
+            app.execute_contract(owner, contract, &EmptyMsg {}, &[])
+                .unwrap();
+
+            assert_eq!(
+                custom_handler_state.queries().to_owned(),
+                vec![AppQuery::Price(PriceQuery {
+                    denom: "eth".to_owned()
+                })]
+            );
+            assert_eq!(
+                custom_handler_state.execs().to_owned(),
+                vec![AppMsg::Contract(CustomMsg::SetAge { age: 30 })]
+            );
+        }
+
+        #[test]
+        fn routing_custom_types_by_contract() {
+            use crate::{ContractWrapper, CustomRouter, RoutedContract};
+            use cosmwasm_std::{to_vec, ContractResult, Deps, DepsMut, Env, MessageInfo, Response};
+
+            let owner = Addr::unchecked("owner");
+            let echo_handler = CachingCustomHandler::<CustomMsg, Empty>::new();
+            let echo_handler_state = echo_handler.state();
+            let app_handler = CachingCustomHandler::<AppMsg, AppQuery>::new();
+            let app_handler_state = app_handler.state();
+            let custom = CustomRouter::new()
+                .with_handler(echo_handler)
+                .unwrap()
+                .with_handler(app_handler)
+                .unwrap();
+            let mut app = AppBuilder::new_custom().with_custom(custom).build(no_init);
+
+            // contract querying and sending custom types of its own
+            let execute = |deps: DepsMut<AppQuery>, _: Env, _: MessageInfo, _: EmptyMsg| {
+                let query = QueryRequest::Custom(AppQuery::Other {});
+                match deps.querier.raw_query(&to_vec(&query)?) {
+                    SystemResult::Ok(ContractResult::Ok(_)) => {}
+                    res => return Err(StdError::generic_err(format!("{:?}", res))),
+                }
+                Ok(Response::new().add_message(CosmosMsg::Custom(AppMsg::Other {})))
+            };
+            let instantiate = |_: DepsMut<AppQuery>, _: Env, _: MessageInfo, _: EmptyMsg| {
+                StdResult::Ok(Response::<AppMsg>::new())
+            };
+            let query = |_: Deps<AppQuery>, _: Env, _: EmptyMsg| to_binary(&EmptyMsg {});
+            let app_contract: Box<dyn Contract<AppMsg, AppQuery>> =
+                Box::new(ContractWrapper::new_with_closures(
+                    Box::new(execute),
+                    Box::new(instantiate),
+                    Box::new(query),
+                ));
+
+            let echo_id = app.store_code(Box::new(RoutedContract::new(echo::custom_contract::<
+                CustomMsg,
+            >())));
+            let app_id = app.store_code(Box::new(RoutedContract::new(app_contract)));
+            let echo = app
+                .instantiate_contract(echo_id, owner.clone(), &EmptyMsg {}, &[], "Echo", None)
+                .unwrap();
+            let app_contract = app
+                .instantiate_contract(app_id, owner.clone(), &EmptyMsg {}, &[], "App", None)
+                .unwrap();
+
+            app.execute_contract(
+                owner.clone(),
+                echo,
+                &echo::Message {
+                    sub_msg: vec![SubMsg::new(CosmosMsg::Custom(CustomMsg::SetAge {
+                        age: 20,
+                    }))],
+                    ..Default::default()
+                },
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(owner, app_contract, &EmptyMsg {}, &[])
+                .unwrap();
+
+            assert_eq!(
+                echo_handler_state.execs().to_owned(),
+                vec![CustomMsg::SetAge { age: 20 }]
+            );
+            assert!(echo_handler_state.queries().is_empty());
+            assert_eq!(app_handler_state.execs().to_owned(), vec![AppMsg::Other {}]);
+            assert_eq!(
+                app_handler_state.queries().to_owned(),
+                vec![AppQuery::Other {}]
+            );
+        }
+
+        #[test]
+        fn routing_custom_types_without_handler() {
+            use crate::{CustomRouter, RoutedContract};
+
+            let owner = Addr::unchecked("owner");
+            let mut app = AppBuilder::new_custom()
+                .with_custom(CustomRouter::new())
+                .build(no_init);
+
+            let echo_id = app.store_code(Box::new(RoutedContract::new(echo::custom_contract::<
+                CustomMsg,
+            >())));
+            let echo = app
+                .instantiate_contract(echo_id, owner.clone(), &EmptyMsg {}, &[], "Echo", None)
+                .unwrap();
+
+            let err = app
+                .execute_contract(
+                    owner,
+                    echo,
+                    &echo::Message {
+                        sub_msg: vec![SubMsg::new(CosmosMsg::Custom(CustomMsg::SetAge {
+                            age: 20,
+                        }))],
+                        ..Default::default()
+                    },
+                    &[],
+                )
+                .unwrap_err();
+            assert_eq!(
+                err.root_cause().to_string(),
+                format!(
+                    "No custom handler for messages of type {}",
+                    std::any::type_name::<CustomMsg>()
+                )
+            );
+        }
+
+        #[test]
+        fn routing_custom_types_without_queries() {
+            use crate::CustomRouter;
+
+            // both handlers take no queries, which are not routed
+            CustomRouter::new()
+                .with_handler(CachingCustomHandler::<CustomMsg, Empty>::new())
+                .unwrap()
+                .with_handler(CachingCustomHandler::<AppMsg, Empty>::new())
+                .unwrap();
+        }
+
+        #[test]
+        fn routing_custom_types_to_duplicate_handlers() {
+            use crate::CustomRouter;
+
+            let err = CustomRouter::new()
+                .with_handler(CachingCustomHandler::<CustomMsg, Empty>::new())
+                .unwrap()
+                .with_handler(CachingCustomHandler::<CustomMsg, AppQuery>::new())
+                .map(|_| ())
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "A custom handler is already registered for messages of type {}",
+                    std::any::type_name::<CustomMsg>()
+                )
+            );
+        }
     }
 
     mod protobuf_wrapped_data {
@@ -2807,6 +5098,7 @@ mod test {
 
     mod errors {
         use super::*;
+        use crate::error::{CallContext, EntryPoint};
 
         #[test]
         fn simple_instantiation() {
@@ -2829,7 +5121,7 @@ mod test {
             }
 
             // We're expecting exactly 2 nested error types
-            // (the original error, WasmMsg context)
+            // (the original error, contract call context)
             assert_eq!(err.chain().count(), 2);
         }
 
@@ -2859,7 +5151,7 @@ mod test {
             }
 
             // We're expecting exactly 2 nested error types
-            // (the original error, WasmMsg context)
+            // (the original error, contract call context)
             assert_eq!(err.chain().count(), 2);
         }
 
@@ -2899,7 +5191,7 @@ mod test {
             }
 
             // We're expecting exactly 3 nested error types
-            // (the original error, 2 WasmMsg contexts)
+            // (the original error, 2 contract call contexts)
             assert_eq!(err.chain().count(), 3);
         }
 
@@ -2923,6 +5215,25 @@ mod test {
                 .instantiate_contract(error_code_id, owner, &msg, &[], "error", None)
                 .unwrap();
 
+            // call path expected in the error context
+            let expected_path = vec![
+                (
+                    caller_addr1.clone(),
+                    EntryPoint::Execute,
+                    Some(Addr::unchecked("random")),
+                ),
+                (
+                    caller_addr2.clone(),
+                    EntryPoint::Execute,
+                    Some(caller_addr1.clone()),
+                ),
+                (
+                    error_addr.clone(),
+                    EntryPoint::Execute,
+                    Some(caller_addr2.clone()),
+                ),
+            ];
+
             // caller1 calls caller2, caller2 calls error
             let msg = WasmMsg::Execute {
                 contract_addr: caller_addr2.into(),
@@ -2950,8 +5261,17 @@ mod test {
             }
 
             // We're expecting exactly 4 nested error types
-            // (the original error, 3 WasmMsg contexts)
+            // (the original error, 3 contract call contexts)
             assert_eq!(err.chain().count(), 4);
+
+            // the outermost context holds the whole call path
+            let context: &CallContext = err.downcast_ref().unwrap();
+            let path: Vec<_> = context
+                .calls
+                .iter()
+                .map(|call| (call.contract.clone(), call.entry_point, call.sender.clone()))
+                .collect();
+            assert_eq!(path, expected_path);
         }
     }
 }
@@ -10,6 +10,7 @@ use cw_storage_plus::Map;
 use cw_utils::NativeBalance;
 
 use crate::app::CosmosRouter;
+use crate::error::BankError;
 use crate::executor::AppResponse;
 use crate::module::Module;
 use crate::prefixed_storage::{prefixed, prefixed_read};
@@ -96,8 +97,24 @@ impl BankKeeper {
         from_address: Addr,
         amount: Vec<Coin>,
     ) -> AnyResult<()> {
-        let amount = self.normalize_amount(amount)?;
+        // merge coins of the same denom, so each is checked against the balance at once
+        let mut amount = NativeBalance(self.normalize_amount(amount)?);
+        amount.normalize();
+        let amount = amount.into_vec();
         let a = self.get_balance(bank_storage, &from_address)?;
+        for needed in &amount {
+            let available = a
+                .iter()
+                .find(|c| c.denom == needed.denom)
+                .map(|c| c.amount)
+                .unwrap_or_default();
+            if available < needed.amount {
+                bail!(BankError::InsufficientFunds {
+                    needed: needed.clone(),
+                    available: Coin::new(available.u128(), &needed.denom),
+                });
+            }
+        }
         let a = (NativeBalance(a) - amount)?;
         self.set_balance(bank_storage, &from_address, a.into_vec())
     }
@@ -106,7 +123,7 @@ impl BankKeeper {
     fn normalize_amount(&self, amount: Vec<Coin>) -> AnyResult<Vec<Coin>> {
         let res: Vec<_> = amount.into_iter().filter(|x| !x.amount.is_zero()).collect();
         if res.is_empty() {
-            bail!("Cannot transfer empty coins amount")
+            bail!(BankError::EmptyAmount)
         } else {
             Ok(res)
         }
@@ -157,7 +174,7 @@ impl Module for BankKeeper {
                 self.burn(&mut bank_storage, sender, amount)?;
                 Ok(AppResponse::default())
             }
-            m => bail!("Unsupported bank message: {:?}", m),
+            m => bail!(BankError::UnsupportedMsg(m)),
         }
     }
 
@@ -205,7 +222,7 @@ impl Module for BankKeeper {
                 let res = BalanceResponse { amount };
                 Ok(to_binary(&res)?)
             }
-            q => bail!("Unsupported bank query: {:?}", q),
+            q => bail!(BankError::UnsupportedQuery(q)),
         }
     }
 }
@@ -216,7 +233,7 @@ mod test {
 
     use crate::app::MockRouter;
     use cosmwasm_std::testing::{mock_env, MockApi, MockQuerier, MockStorage};
-    use cosmwasm_std::{coins, from_slice, Empty, StdError};
+    use cosmwasm_std::{coins, from_slice, Empty};
 
     fn query_balance(
         bank: &BankKeeper,
@@ -381,11 +398,32 @@ mod test {
         let err = bank
             .execute(&api, &mut store, &router, &block, owner.clone(), msg)
             .unwrap_err();
-        assert!(matches!(err.downcast().unwrap(), StdError::Overflow { .. }));
+        assert_eq!(
+            err.downcast::<BankError>().unwrap(),
+            BankError::InsufficientFunds {
+                needed: coin(20, "btc"),
+                available: coin(15, "btc"),
+            }
+        );
 
         let rich = query_balance(&bank, &api, &store, &owner);
         assert_eq!(vec![coin(15, "btc"), coin(70, "eth")], rich);
 
+        // coins of the same denom are burnt together
+        let msg = BankMsg::Burn {
+            amount: vec![coin(10, "btc"), coin(10, "btc")],
+        };
+        let err = bank
+            .execute(&api, &mut store, &router, &block, owner.clone(), msg)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<BankError>().unwrap(),
+            BankError::InsufficientFunds {
+                needed: coin(20, "btc"),
+                available: coin(15, "btc"),
+            }
+        );
+
         // cannot burn from empty account
         let msg = BankMsg::Burn {
             amount: coins(1, "btc"),
@@ -393,7 +431,13 @@ mod test {
         let err = bank
             .execute(&api, &mut store, &router, &block, rcpt, msg)
             .unwrap_err();
-        assert!(matches!(err.downcast().unwrap(), StdError::Overflow { .. }));
+        assert_eq!(
+            err.downcast::<BankError>().unwrap(),
+            BankError::InsufficientFunds {
+                needed: coin(1, "btc"),
+                available: coin(0, "btc"),
+            }
+        );
     }
 
     #[test]
@@ -2,11 +2,13 @@ use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use std::error::Error;
 use std::fmt::{self, Debug, Display};
+use std::marker::PhantomData;
 use std::ops::Deref;
 
 use cosmwasm_std::{
-    from_slice, Binary, CosmosMsg, CustomQuery, Deps, DepsMut, Empty, Env, MessageInfo,
-    QuerierWrapper, Reply, Response, SubMsg,
+    from_slice, to_vec, Binary, CosmosMsg, CustomQuery, Deps, DepsMut, Empty, Env, MessageInfo,
+    Querier, QuerierResult, QuerierWrapper, QueryRequest, Reply, Response, SubMsg, SystemError,
+    SystemResult,
 };
 
 use anyhow::{anyhow, bail, Result as AnyResult};
@@ -40,6 +42,11 @@ where
     fn reply(&self, deps: DepsMut<Q>, env: Env, msg: Reply) -> AnyResult<Response<T>>;
 
     fn migrate(&self, deps: DepsMut<Q>, env: Env, msg: Vec<u8>) -> AnyResult<Response<T>>;
+
+    /// Whether the contract has IBC entry points, so its instances get an IBC port
+    fn has_ibc_entry_points(&self) -> bool {
+        false
+    }
 }
 
 type ContractFn<T, C, E, Q> =
@@ -48,11 +55,12 @@ type PermissionedFn<T, C, E, Q> = fn(deps: DepsMut<Q>, env: Env, msg: T) -> Resu
 type ReplyFn<C, E, Q> = fn(deps: DepsMut<Q>, env: Env, msg: Reply) -> Result<Response<C>, E>;
 type QueryFn<T, E, Q> = fn(deps: Deps<Q>, env: Env, msg: T) -> Result<Binary, E>;
 
-type ContractClosure<T, C, E, Q> =
+pub type ContractClosure<T, C, E, Q> =
     Box<dyn Fn(DepsMut<Q>, Env, MessageInfo, T) -> Result<Response<C>, E>>;
-type PermissionedClosure<T, C, E, Q> = Box<dyn Fn(DepsMut<Q>, Env, T) -> Result<Response<C>, E>>;
-type ReplyClosure<C, E, Q> = Box<dyn Fn(DepsMut<Q>, Env, Reply) -> Result<Response<C>, E>>;
-type QueryClosure<T, E, Q> = Box<dyn Fn(Deps<Q>, Env, T) -> Result<Binary, E>>;
+pub type PermissionedClosure<T, C, E, Q> =
+    Box<dyn Fn(DepsMut<Q>, Env, T) -> Result<Response<C>, E>>;
+pub type ReplyClosure<C, E, Q> = Box<dyn Fn(DepsMut<Q>, Env, Reply) -> Result<Response<C>, E>>;
+pub type QueryClosure<T, E, Q> = Box<dyn Fn(Deps<Q>, Env, T) -> Result<Binary, E>>;
 
 /// Wraps the exported functions from a contract and provides the normalized format
 /// Place T4 and E4 at the end, as we just want default placeholders for most contracts that don't have sudo
@@ -91,6 +99,7 @@ pub struct ContractWrapper<
     sudo_fn: Option<PermissionedClosure<T4, C, E4, Q>>,
     reply_fn: Option<ReplyClosure<C, E5, Q>>,
     migrate_fn: Option<PermissionedClosure<T6, C, E6, Q>>,
+    ibc_entry_points: bool,
 }
 
 impl<T1, T2, T3, E1, E2, E3, C, Q> ContractWrapper<T1, T2, T3, E1, E2, E3, C, Q>
@@ -116,6 +125,25 @@ where
             sudo_fn: None,
             reply_fn: None,
             migrate_fn: None,
+            ibc_entry_points: false,
+        }
+    }
+
+    /// Same as `new`, but takes closures, which may capture state like a configurable failure,
+    /// instead of plain functions
+    pub fn new_with_closures(
+        execute_fn: ContractClosure<T1, C, E1, Q>,
+        instantiate_fn: ContractClosure<T2, C, E2, Q>,
+        query_fn: QueryClosure<T3, E3, Q>,
+    ) -> Self {
+        Self {
+            execute_fn,
+            instantiate_fn,
+            query_fn,
+            sudo_fn: None,
+            reply_fn: None,
+            migrate_fn: None,
+            ibc_entry_points: false,
         }
     }
 
@@ -133,6 +161,7 @@ where
             sudo_fn: None,
             reply_fn: None,
             migrate_fn: None,
+            ibc_entry_points: false,
         }
     }
 }
@@ -169,6 +198,27 @@ where
             sudo_fn: Some(Box::new(sudo_fn)),
             reply_fn: self.reply_fn,
             migrate_fn: self.migrate_fn,
+            ibc_entry_points: self.ibc_entry_points,
+        }
+    }
+
+    /// A correlate of new_with_closures
+    pub fn with_sudo_closure<T4A, E4A>(
+        self,
+        sudo_fn: PermissionedClosure<T4A, C, E4A, Q>,
+    ) -> ContractWrapper<T1, T2, T3, E1, E2, E3, C, Q, T4A, E4A, E5, T6, E6>
+    where
+        T4A: DeserializeOwned + 'static,
+        E4A: Display + Debug + Send + Sync + 'static,
+    {
+        ContractWrapper {
+            execute_fn: self.execute_fn,
+            instantiate_fn: self.instantiate_fn,
+            query_fn: self.query_fn,
+            sudo_fn: Some(sudo_fn),
+            reply_fn: self.reply_fn,
+            migrate_fn: self.migrate_fn,
+            ibc_entry_points: self.ibc_entry_points,
         }
     }
 
@@ -187,6 +237,7 @@ where
             sudo_fn: Some(customize_permissioned_fn(sudo_fn)),
             reply_fn: self.reply_fn,
             migrate_fn: self.migrate_fn,
+            ibc_entry_points: self.ibc_entry_points,
         }
     }
 
@@ -204,6 +255,26 @@ where
             sudo_fn: self.sudo_fn,
             reply_fn: Some(Box::new(reply_fn)),
             migrate_fn: self.migrate_fn,
+            ibc_entry_points: self.ibc_entry_points,
+        }
+    }
+
+    /// A correlate of new_with_closures
+    pub fn with_reply_closure<E5A>(
+        self,
+        reply_fn: ReplyClosure<C, E5A, Q>,
+    ) -> ContractWrapper<T1, T2, T3, E1, E2, E3, C, Q, T4, E4, E5A, T6, E6>
+    where
+        E5A: Display + Debug + Send + Sync + 'static,
+    {
+        ContractWrapper {
+            execute_fn: self.execute_fn,
+            instantiate_fn: self.instantiate_fn,
+            query_fn: self.query_fn,
+            sudo_fn: self.sudo_fn,
+            reply_fn: Some(reply_fn),
+            migrate_fn: self.migrate_fn,
+            ibc_entry_points: self.ibc_entry_points,
         }
     }
 
@@ -222,9 +293,17 @@ where
             sudo_fn: self.sudo_fn,
             reply_fn: Some(customize_permissioned_fn(reply_fn)),
             migrate_fn: self.migrate_fn,
+            ibc_entry_points: self.ibc_entry_points,
         }
     }
 
+    /// Declares the contract as having IBC entry points, so its instances get an IBC port like
+    /// on a real chain. The wrapper doesn't call these entry points.
+    pub fn with_ibc_entry_points(mut self) -> Self {
+        self.ibc_entry_points = true;
+        self
+    }
+
     pub fn with_migrate<T6A, E6A>(
         self,
         migrate_fn: PermissionedFn<T6A, C, E6A, Q>,
@@ -240,6 +319,27 @@ where
             sudo_fn: self.sudo_fn,
             reply_fn: self.reply_fn,
             migrate_fn: Some(Box::new(migrate_fn)),
+            ibc_entry_points: self.ibc_entry_points,
+        }
+    }
+
+    /// A correlate of new_with_closures
+    pub fn with_migrate_closure<T6A, E6A>(
+        self,
+        migrate_fn: PermissionedClosure<T6A, C, E6A, Q>,
+    ) -> ContractWrapper<T1, T2, T3, E1, E2, E3, C, Q, T4, E4, E5, T6A, E6A>
+    where
+        T6A: DeserializeOwned + 'static,
+        E6A: Display + Debug + Send + Sync + 'static,
+    {
+        ContractWrapper {
+            execute_fn: self.execute_fn,
+            instantiate_fn: self.instantiate_fn,
+            query_fn: self.query_fn,
+            sudo_fn: self.sudo_fn,
+            reply_fn: self.reply_fn,
+            migrate_fn: Some(migrate_fn),
+            ibc_entry_points: self.ibc_entry_points,
         }
     }
 
@@ -258,6 +358,7 @@ where
             sudo_fn: self.sudo_fn,
             reply_fn: self.reply_fn,
             migrate_fn: Some(customize_permissioned_fn(migrate_fn)),
+            ibc_entry_points: self.ibc_entry_points,
         }
     }
 }
@@ -434,4 +535,265 @@ where
             None => bail!("migrate not implemented for contract"),
         }
     }
+
+    fn has_ibc_entry_points(&self) -> bool {
+        self.ibc_entry_points
+    }
+}
+
+/// Adapts a contract written against the custom message and query types `C1` and `Q1` to an app
+/// using `C` and `Q`, like contracts using the bindings of another chain, or contracts whose
+/// custom types are a subset of the app ones.
+///
+/// Custom messages the contract sends are converted with `C: TryFrom<C1>`, custom queries it
+/// makes with `Q: TryFrom<Q1>`. Any `From` implementation provides these. Failed conversions
+/// fail the call, or the query.
+pub struct CustomAdapter<C1, Q1, C, Q>
+where
+    C1: Clone + fmt::Debug + PartialEq + JsonSchema,
+    Q1: CustomQuery,
+{
+    contract: Box<dyn Contract<C1, Q1>>,
+    _p: PhantomData<(C, Q)>,
+}
+
+impl<C1, Q1, C, Q> CustomAdapter<C1, Q1, C, Q>
+where
+    C1: Clone + fmt::Debug + PartialEq + JsonSchema,
+    Q1: CustomQuery,
+{
+    pub fn new(contract: Box<dyn Contract<C1, Q1>>) -> Self {
+        Self {
+            contract,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C1, Q1, C, Q> CustomAdapter<C1, Q1, C, Q>
+where
+    C1: Clone + fmt::Debug + PartialEq + JsonSchema,
+    Q1: CustomQuery + DeserializeOwned,
+    C: Clone + fmt::Debug + PartialEq + JsonSchema + TryFrom<C1>,
+    C::Error: Display,
+    Q: CustomQuery + TryFrom<Q1>,
+    Q::Error: Display,
+{
+    fn convert_msg(msg: C1) -> AnyResult<C> {
+        C::try_from(msg).map_err(|err| anyhow!("Cannot convert custom message: {}", err))
+    }
+
+    fn convert_query(query: Q1) -> AnyResult<Q> {
+        Q::try_from(query).map_err(|err| anyhow!("Cannot convert custom query: {}", err))
+    }
+}
+
+/// Calls `action` with `deps` whose querier accepts custom queries of type `Q1`, converting them
+/// to `Q` with `convert`
+pub(crate) fn with_converted_deps_mut<Q1, Q, T>(
+    deps: DepsMut<Q>,
+    convert: fn(Q1) -> AnyResult<Q>,
+    action: impl FnOnce(DepsMut<Q1>) -> AnyResult<T>,
+) -> AnyResult<T>
+where
+    Q1: CustomQuery + DeserializeOwned,
+    Q: CustomQuery,
+{
+    let querier = CustomQuerier::new(deps.querier.deref(), convert);
+    action(DepsMut {
+        storage: deps.storage,
+        api: deps.api,
+        querier: QuerierWrapper::new(&querier),
+    })
+}
+
+/// Same as `with_converted_deps_mut`, for queries
+pub(crate) fn with_converted_deps<Q1, Q, T>(
+    deps: Deps<Q>,
+    convert: fn(Q1) -> AnyResult<Q>,
+    action: impl FnOnce(Deps<Q1>) -> AnyResult<T>,
+) -> AnyResult<T>
+where
+    Q1: CustomQuery + DeserializeOwned,
+    Q: CustomQuery,
+{
+    let querier = CustomQuerier::new(deps.querier.deref(), convert);
+    action(Deps {
+        storage: deps.storage,
+        api: deps.api,
+        querier: QuerierWrapper::new(&querier),
+    })
+}
+
+/// Converts custom messages sent in `response` with `convert`
+pub(crate) fn convert_response<C1, C>(
+    response: Response<C1>,
+    convert: fn(C1) -> AnyResult<C>,
+) -> AnyResult<Response<C>>
+where
+    C1: fmt::Debug,
+{
+    let messages = response
+        .messages
+        .into_iter()
+        .map(|msg| {
+            Ok(SubMsg {
+                msg: convert_msg(msg.msg, convert)?,
+                id: msg.id,
+                gas_limit: msg.gas_limit,
+                reply_on: msg.reply_on,
+            })
+        })
+        .collect::<AnyResult<Vec<_>>>()?;
+    let mut converted = Response::<C>::new()
+        .add_submessages(messages)
+        .add_events(response.events)
+        .add_attributes(response.attributes);
+    converted.data = response.data;
+    Ok(converted)
+}
+
+/// Converts the custom message type of `msg` with `convert`, other messages are kept as they are
+pub(crate) fn convert_msg<C1, C>(
+    msg: CosmosMsg<C1>,
+    convert: impl FnOnce(C1) -> AnyResult<C>,
+) -> AnyResult<CosmosMsg<C>>
+where
+    C1: fmt::Debug,
+{
+    let converted = match msg {
+        CosmosMsg::Custom(custom) => CosmosMsg::Custom(convert(custom)?),
+        CosmosMsg::Wasm(wasm) => CosmosMsg::Wasm(wasm),
+        CosmosMsg::Bank(bank) => CosmosMsg::Bank(bank),
+        CosmosMsg::Staking(staking) => CosmosMsg::Staking(staking),
+        CosmosMsg::Distribution(distribution) => CosmosMsg::Distribution(distribution),
+        CosmosMsg::Ibc(ibc) => CosmosMsg::Ibc(ibc),
+        CosmosMsg::Gov(gov) => CosmosMsg::Gov(gov),
+        CosmosMsg::Stargate { type_url, value } => CosmosMsg::Stargate { type_url, value },
+        msg => bail!("Cannot convert unknown message variant {:?}", msg),
+    };
+    Ok(converted)
+}
+
+/// Converts the custom query type of `request` with `convert`, other queries are kept as they are
+pub(crate) fn convert_query_request<Q1, Q>(
+    request: QueryRequest<Q1>,
+    convert: impl FnOnce(Q1) -> AnyResult<Q>,
+) -> AnyResult<QueryRequest<Q>> {
+    let converted = match request {
+        QueryRequest::Custom(custom) => QueryRequest::Custom(convert(custom)?),
+        QueryRequest::Bank(bank) => QueryRequest::Bank(bank),
+        QueryRequest::Staking(staking) => QueryRequest::Staking(staking),
+        QueryRequest::Stargate { path, data } => QueryRequest::Stargate { path, data },
+        QueryRequest::Ibc(ibc) => QueryRequest::Ibc(ibc),
+        QueryRequest::Wasm(wasm) => QueryRequest::Wasm(wasm),
+        _ => bail!("Cannot convert unknown query variant"),
+    };
+    Ok(converted)
+}
+
+/// Querier passing queries made with the custom query type `Q1` to a querier expecting `Q`
+struct CustomQuerier<'a, Q1, Q> {
+    querier: &'a dyn Querier,
+    convert: fn(Q1) -> AnyResult<Q>,
+}
+
+impl<'a, Q1, Q> CustomQuerier<'a, Q1, Q> {
+    fn new(querier: &'a dyn Querier, convert: fn(Q1) -> AnyResult<Q>) -> Self {
+        Self { querier, convert }
+    }
+}
+
+impl<'a, Q1, Q> Querier for CustomQuerier<'a, Q1, Q>
+where
+    Q1: CustomQuery + DeserializeOwned,
+    Q: CustomQuery,
+{
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let invalid = |error: String| {
+            SystemResult::Err(SystemError::InvalidRequest {
+                error,
+                request: bin_request.into(),
+            })
+        };
+        let request: QueryRequest<Q1> = match from_slice(bin_request) {
+            Ok(request) => request,
+            Err(err) => return invalid(format!("Parsing query request: {}", err)),
+        };
+        let request = match convert_query_request(request, self.convert) {
+            Ok(request) => request,
+            Err(err) => return invalid(err.to_string()),
+        };
+        match to_vec(&request) {
+            Ok(bin_request) => self.querier.raw_query(&bin_request),
+            Err(err) => invalid(format!("Serializing query request: {}", err)),
+        }
+    }
+}
+
+impl<C1, Q1, C, Q> Contract<C, Q> for CustomAdapter<C1, Q1, C, Q>
+where
+    C1: Clone + fmt::Debug + PartialEq + JsonSchema,
+    Q1: CustomQuery + DeserializeOwned,
+    C: Clone + fmt::Debug + PartialEq + JsonSchema + TryFrom<C1>,
+    C::Error: Display,
+    Q: CustomQuery + TryFrom<Q1>,
+    Q::Error: Display,
+{
+    fn execute(
+        &self,
+        deps: DepsMut<Q>,
+        env: Env,
+        info: MessageInfo,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<C>> {
+        let response = with_converted_deps_mut(deps, Self::convert_query, |deps| {
+            self.contract.execute(deps, env, info, msg)
+        })?;
+        convert_response(response, Self::convert_msg)
+    }
+
+    fn instantiate(
+        &self,
+        deps: DepsMut<Q>,
+        env: Env,
+        info: MessageInfo,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<C>> {
+        let response = with_converted_deps_mut(deps, Self::convert_query, |deps| {
+            self.contract.instantiate(deps, env, info, msg)
+        })?;
+        convert_response(response, Self::convert_msg)
+    }
+
+    fn query(&self, deps: Deps<Q>, env: Env, msg: Vec<u8>) -> AnyResult<Binary> {
+        with_converted_deps(deps, Self::convert_query, |deps| {
+            self.contract.query(deps, env, msg)
+        })
+    }
+
+    fn sudo(&self, deps: DepsMut<Q>, env: Env, msg: Vec<u8>) -> AnyResult<Response<C>> {
+        let response = with_converted_deps_mut(deps, Self::convert_query, |deps| {
+            self.contract.sudo(deps, env, msg)
+        })?;
+        convert_response(response, Self::convert_msg)
+    }
+
+    fn reply(&self, deps: DepsMut<Q>, env: Env, msg: Reply) -> AnyResult<Response<C>> {
+        let response = with_converted_deps_mut(deps, Self::convert_query, |deps| {
+            self.contract.reply(deps, env, msg)
+        })?;
+        convert_response(response, Self::convert_msg)
+    }
+
+    fn migrate(&self, deps: DepsMut<Q>, env: Env, msg: Vec<u8>) -> AnyResult<Response<C>> {
+        let response = with_converted_deps_mut(deps, Self::convert_query, |deps| {
+            self.contract.migrate(deps, env, msg)
+        })?;
+        convert_response(response, Self::convert_msg)
+    }
+
+    fn has_ibc_entry_points(&self) -> bool {
+        self.contract.has_ibc_entry_points()
+    }
 }
@@ -0,0 +1,408 @@
+use std::any::{type_name, TypeId};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use anyhow::{bail, Result as AnyResult};
+use cosmwasm_std::{
+    from_slice, to_binary, Addr, Api, Binary, BlockInfo, CosmosMsg, CustomQuery, Deps, DepsMut,
+    Empty, Env, MessageInfo, Querier, QueryRequest, Reply, Response, StdResult, Storage,
+    TransactionInfo,
+};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{CosmosRouter, SudoMsg};
+use crate::contracts::{
+    convert_msg, convert_query_request, convert_response, with_converted_deps,
+    with_converted_deps_mut,
+};
+use crate::tracer::Tracer;
+use crate::{AppResponse, Contract, Module};
+
+/// Route of the custom type `T`, derived from its `TypeId` so it can be serialized along with
+/// custom queries
+fn route_of<T: 'static>() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    TypeId::of::<T>().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Custom message sent by a `RoutedContract`, tagged with its type
+#[derive(Clone, Debug, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct RoutedCustomMsg {
+    /// Route of the message type, which `CustomRouter` dispatches on
+    pub route: u64,
+    /// Name of the message type, for error messages
+    pub type_name: String,
+    pub msg: Binary,
+}
+
+impl RoutedCustomMsg {
+    pub fn new<C: Serialize + 'static>(msg: &C) -> StdResult<Self> {
+        Ok(Self {
+            route: route_of::<C>(),
+            type_name: type_name::<C>().to_owned(),
+            msg: to_binary(msg)?,
+        })
+    }
+}
+
+/// Custom query made by a `RoutedContract`, tagged with its type
+#[derive(Clone, Debug, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct RoutedCustomQuery {
+    /// Route of the query type, which `CustomRouter` dispatches on
+    pub route: u64,
+    /// Name of the query type, for error messages
+    pub type_name: String,
+    pub query: Binary,
+}
+
+impl RoutedCustomQuery {
+    pub fn new<Q: Serialize + 'static>(query: &Q) -> StdResult<Self> {
+        Ok(Self {
+            route: route_of::<Q>(),
+            type_name: type_name::<Q>().to_owned(),
+            query: to_binary(query)?,
+        })
+    }
+}
+
+impl CustomQuery for RoutedCustomQuery {}
+
+/// Contract declaring the custom message and query types `C` and `Q` it uses, so it can be
+/// stored in an app with `CustomRouter` as its custom module, next to contracts using other
+/// custom types.
+pub struct RoutedContract<C, Q>
+where
+    C: Clone + fmt::Debug + PartialEq + JsonSchema,
+    Q: CustomQuery,
+{
+    contract: Box<dyn Contract<C, Q>>,
+}
+
+impl<C, Q> RoutedContract<C, Q>
+where
+    C: Clone + fmt::Debug + PartialEq + JsonSchema + Serialize + 'static,
+    Q: CustomQuery + DeserializeOwned + 'static,
+{
+    pub fn new(contract: Box<dyn Contract<C, Q>>) -> Self {
+        Self { contract }
+    }
+
+    fn tag_msg(msg: C) -> AnyResult<RoutedCustomMsg> {
+        Ok(RoutedCustomMsg::new(&msg)?)
+    }
+
+    fn tag_query(query: Q) -> AnyResult<RoutedCustomQuery> {
+        Ok(RoutedCustomQuery::new(&query)?)
+    }
+}
+
+impl<C, Q> Contract<RoutedCustomMsg, RoutedCustomQuery> for RoutedContract<C, Q>
+where
+    C: Clone + fmt::Debug + PartialEq + JsonSchema + Serialize + 'static,
+    Q: CustomQuery + DeserializeOwned + 'static,
+{
+    fn execute(
+        &self,
+        deps: DepsMut<RoutedCustomQuery>,
+        env: Env,
+        info: MessageInfo,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<RoutedCustomMsg>> {
+        let response = with_converted_deps_mut(deps, Self::tag_query, |deps| {
+            self.contract.execute(deps, env, info, msg)
+        })?;
+        convert_response(response, Self::tag_msg)
+    }
+
+    fn instantiate(
+        &self,
+        deps: DepsMut<RoutedCustomQuery>,
+        env: Env,
+        info: MessageInfo,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<RoutedCustomMsg>> {
+        let response = with_converted_deps_mut(deps, Self::tag_query, |deps| {
+            self.contract.instantiate(deps, env, info, msg)
+        })?;
+        convert_response(response, Self::tag_msg)
+    }
+
+    fn query(&self, deps: Deps<RoutedCustomQuery>, env: Env, msg: Vec<u8>) -> AnyResult<Binary> {
+        with_converted_deps(deps, Self::tag_query, |deps| {
+            self.contract.query(deps, env, msg)
+        })
+    }
+
+    fn sudo(
+        &self,
+        deps: DepsMut<RoutedCustomQuery>,
+        env: Env,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<RoutedCustomMsg>> {
+        let response = with_converted_deps_mut(deps, Self::tag_query, |deps| {
+            self.contract.sudo(deps, env, msg)
+        })?;
+        convert_response(response, Self::tag_msg)
+    }
+
+    fn reply(
+        &self,
+        deps: DepsMut<RoutedCustomQuery>,
+        env: Env,
+        msg: Reply,
+    ) -> AnyResult<Response<RoutedCustomMsg>> {
+        let response = with_converted_deps_mut(deps, Self::tag_query, |deps| {
+            self.contract.reply(deps, env, msg)
+        })?;
+        convert_response(response, Self::tag_msg)
+    }
+
+    fn migrate(
+        &self,
+        deps: DepsMut<RoutedCustomQuery>,
+        env: Env,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<RoutedCustomMsg>> {
+        let response = with_converted_deps_mut(deps, Self::tag_query, |deps| {
+            self.contract.migrate(deps, env, msg)
+        })?;
+        convert_response(response, Self::tag_msg)
+    }
+
+    fn has_ibc_entry_points(&self) -> bool {
+        self.contract.has_ibc_entry_points()
+    }
+}
+
+/// Custom module handler with its message types erased, so handlers of different types can be
+/// kept together
+trait RoutedHandler {
+    fn execute(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        router: &dyn CosmosRouter<ExecC = Empty, QueryC = Empty>,
+        block: &BlockInfo,
+        sender: Addr,
+        msg: &[u8],
+    ) -> AnyResult<AppResponse>;
+
+    fn query(
+        &self,
+        api: &dyn Api,
+        storage: &dyn Storage,
+        querier: &dyn Querier,
+        block: &BlockInfo,
+        request: &[u8],
+    ) -> AnyResult<Binary>;
+}
+
+impl<M> RoutedHandler for M
+where
+    M: Module,
+    M::ExecT: DeserializeOwned,
+    M::QueryT: DeserializeOwned,
+{
+    fn execute(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        router: &dyn CosmosRouter<ExecC = Empty, QueryC = Empty>,
+        block: &BlockInfo,
+        sender: Addr,
+        msg: &[u8],
+    ) -> AnyResult<AppResponse> {
+        Module::execute(self, api, storage, router, block, sender, from_slice(msg)?)
+    }
+
+    fn query(
+        &self,
+        api: &dyn Api,
+        storage: &dyn Storage,
+        querier: &dyn Querier,
+        block: &BlockInfo,
+        request: &[u8],
+    ) -> AnyResult<Binary> {
+        Module::query(self, api, storage, querier, block, from_slice(request)?)
+    }
+}
+
+/// Custom module dispatching the custom messages and queries of `RoutedContract`s to the handler
+/// registered for their type, so an app can host contracts using different custom types:
+///
+///   let custom = CustomRouter::new()
+///       .with_handler(ChainAHandler::new())?
+///       .with_handler(ChainBHandler::new())?;
+///   let mut app = AppBuilder::new_custom().with_custom(custom).build(no_init);
+///   let code_id = app.store_code(Box::new(RoutedContract::new(chain_a_contract())));
+///
+/// Handlers receive a router without custom message types, so they can still send and query
+/// everything but custom messages and queries.
+#[derive(Default)]
+pub struct CustomRouter {
+    execs: HashMap<u64, Rc<dyn RoutedHandler>>,
+    queries: HashMap<u64, Rc<dyn RoutedHandler>>,
+}
+
+impl CustomRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes custom messages of type `M::ExecT` and custom queries of type `M::QueryT` to
+    /// `handler`. `Empty` types are not routed, so handlers which don't take messages or queries
+    /// don't conflict.
+    ///
+    /// Fails if another handler was registered for the same message or query type.
+    pub fn with_handler<M>(mut self, handler: M) -> AnyResult<Self>
+    where
+        M: Module + 'static,
+        M::ExecT: DeserializeOwned + 'static,
+        M::QueryT: DeserializeOwned + 'static,
+    {
+        let handler: Rc<dyn RoutedHandler> = Rc::new(handler);
+        Self::register::<M::ExecT>(&mut self.execs, handler.clone(), "messages")?;
+        Self::register::<M::QueryT>(&mut self.queries, handler, "queries")?;
+        Ok(self)
+    }
+
+    fn register<T: 'static>(
+        handlers: &mut HashMap<u64, Rc<dyn RoutedHandler>>,
+        handler: Rc<dyn RoutedHandler>,
+        kind: &str,
+    ) -> AnyResult<()> {
+        if TypeId::of::<T>() == TypeId::of::<Empty>() {
+            return Ok(());
+        }
+        match handlers.entry(route_of::<T>()) {
+            Entry::Occupied(_) => bail!(
+                "A custom handler is already registered for {} of type {}",
+                kind,
+                type_name::<T>()
+            ),
+            Entry::Vacant(entry) => {
+                entry.insert(handler);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Module for CustomRouter {
+    type ExecT = RoutedCustomMsg;
+    type QueryT = RoutedCustomQuery;
+    type SudoT = Empty;
+
+    fn execute<ExecC, QueryC>(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        block: &BlockInfo,
+        sender: Addr,
+        msg: Self::ExecT,
+    ) -> AnyResult<AppResponse>
+    where
+        ExecC: fmt::Debug + Clone + PartialEq + JsonSchema + DeserializeOwned + 'static,
+        QueryC: CustomQuery + DeserializeOwned + 'static,
+    {
+        let handler = match self.execs.get(&msg.route) {
+            Some(handler) => handler,
+            None => bail!("No custom handler for messages of type {}", msg.type_name),
+        };
+        let router = UncustomRouter { router };
+        handler.execute(api, storage, &router, block, sender, &msg.msg)
+    }
+
+    fn sudo<ExecC, QueryC>(
+        &self,
+        _api: &dyn Api,
+        _storage: &mut dyn Storage,
+        _router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        _block: &BlockInfo,
+        msg: Self::SudoT,
+    ) -> AnyResult<AppResponse> {
+        bail!("Unexpected sudo msg {:?}", msg)
+    }
+
+    fn query(
+        &self,
+        api: &dyn Api,
+        storage: &dyn Storage,
+        querier: &dyn Querier,
+        block: &BlockInfo,
+        request: Self::QueryT,
+    ) -> AnyResult<Binary> {
+        let handler = match self.queries.get(&request.route) {
+            Some(handler) => handler,
+            None => bail!(
+                "No custom handler for queries of type {}",
+                request.type_name
+            ),
+        };
+        handler.query(api, storage, querier, block, &request.query)
+    }
+}
+
+/// Router passed to handlers of `CustomRouter`, forwarding everything but custom messages and
+/// queries to the app router
+struct UncustomRouter<'a, ExecC, QueryC> {
+    router: &'a dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+}
+
+impl<'a, ExecC, QueryC> CosmosRouter for UncustomRouter<'a, ExecC, QueryC>
+where
+    QueryC: CustomQuery,
+{
+    type ExecC = Empty;
+    type QueryC = Empty;
+
+    fn execute(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        block: &BlockInfo,
+        sender: Addr,
+        msg: CosmosMsg<Empty>,
+    ) -> AnyResult<AppResponse> {
+        let msg = convert_msg(msg, |msg| bail!("Unexpected custom msg {:?}", msg))?;
+        self.router.execute(api, storage, block, sender, msg)
+    }
+
+    fn query(
+        &self,
+        api: &dyn Api,
+        storage: &dyn Storage,
+        block: &BlockInfo,
+        request: QueryRequest<Empty>,
+    ) -> AnyResult<Binary> {
+        let request = convert_query_request(request, |request| {
+            bail!("Unexpected custom query {:?}", request)
+        })?;
+        self.router.query(api, storage, block, request)
+    }
+
+    fn sudo(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        block: &BlockInfo,
+        msg: SudoMsg,
+    ) -> AnyResult<AppResponse> {
+        self.router.sudo(api, storage, block, msg)
+    }
+
+    fn transaction_info(&self) -> Option<TransactionInfo> {
+        self.router.transaction_info()
+    }
+
+    fn tracer(&self) -> Option<&Tracer> {
+        self.router.tracer()
+    }
+}
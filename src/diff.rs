@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use cosmwasm_std::Addr;
+
+use crate::transactions::RepLog;
+use crate::wasm::NAMESPACE_WASM;
+
+const CONTRACT_DATA_PREFIX: &str = "contract_data/";
+
+/// All the writes a transaction committed to the storage, grouped by module namespace.
+///
+/// Module state lives under its namespace, like `bank` or `staking`, while every contract gets
+/// its own `wasm/contract_data/<addr>` namespace. Keys outside of any namespace are grouped under
+/// the empty one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageDiff {
+    pub namespaces: BTreeMap<String, Vec<StorageWrite>>,
+}
+
+/// Final state of a single key written by a transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageWrite {
+    /// Key relative to its namespace, as seen by the module or contract
+    pub key: Vec<u8>,
+    /// Readable form of the key if it can be decoded, e.g. `balances/addr` for a `cw-storage-plus`
+    /// map entry. Length-prefixed segments are joined with `/`, non-printable last segments are
+    /// shown as hex.
+    pub decoded_key: Option<String>,
+    /// New value of the key, `None` if it was removed
+    pub value: Option<Vec<u8>>,
+}
+
+impl StorageDiff {
+    pub(crate) fn from_rep_log(rep_log: &RepLog) -> Self {
+        // only the last write of every key makes it to the storage
+        let mut writes: BTreeMap<&[u8], Option<&[u8]>> = BTreeMap::new();
+        for op in rep_log.ops() {
+            writes.insert(op.key(), op.value());
+        }
+
+        let mut namespaces: BTreeMap<String, Vec<StorageWrite>> = BTreeMap::new();
+        for (raw_key, value) in writes {
+            let (namespace, key) = split_namespace(raw_key);
+            namespaces.entry(namespace).or_default().push(StorageWrite {
+                key: key.to_vec(),
+                decoded_key: decode_key(key),
+                value: value.map(<[u8]>::to_vec),
+            });
+        }
+        StorageDiff { namespaces }
+    }
+
+    /// Returns true if nothing was written
+    pub fn is_empty(&self) -> bool {
+        self.namespaces.is_empty()
+    }
+
+    /// Returns all writes to the given namespace, e.g. `bank`
+    pub fn writes(&self, namespace: &str) -> &[StorageWrite] {
+        self.namespaces
+            .get(namespace)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns all writes to the storage of the given contract
+    pub fn contract_writes(&self, contract: &Addr) -> &[StorageWrite] {
+        self.writes(&format!(
+            "{}/{}{}",
+            String::from_utf8_lossy(NAMESPACE_WASM),
+            CONTRACT_DATA_PREFIX,
+            contract
+        ))
+    }
+}
+
+/// Splits the next length-prefixed segment off the key, if there is a valid one
+fn split_segment(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    if key.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([key[0], key[1]]) as usize;
+    if len == 0 || key.len() < 2 + len {
+        return None;
+    }
+    Some((&key[2..2 + len], &key[2 + len..]))
+}
+
+fn printable(bytes: &[u8]) -> Option<&str> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .filter(|s| !s.is_empty() && !s.chars().any(char::is_control))
+}
+
+fn split_namespace(key: &[u8]) -> (String, &[u8]) {
+    let (module, rest) = match split_segment(key) {
+        Some((module, rest)) => match printable(module) {
+            Some(module) => (module, rest),
+            None => return (String::new(), key),
+        },
+        None => return (String::new(), key),
+    };
+
+    if module.as_bytes() == NAMESPACE_WASM {
+        if let Some((sub, contract_key)) = split_segment(rest) {
+            if let Some(sub) = printable(sub).filter(|s| s.starts_with(CONTRACT_DATA_PREFIX)) {
+                return (format!("{}/{}", module, sub), contract_key);
+            }
+        }
+    }
+    (module.to_string(), rest)
+}
+
+fn decode_key(key: &[u8]) -> Option<String> {
+    let mut parts = vec![];
+    let mut rest = key;
+    while let Some((segment, tail)) = split_segment(rest) {
+        match printable(segment) {
+            Some(segment) if !tail.is_empty() => parts.push(segment.to_string()),
+            _ => break,
+        }
+        rest = tail;
+    }
+
+    match printable(rest) {
+        Some(last) => parts.push(last.to_string()),
+        // raw keys like big endian integers only make sense within a known namespace
+        None if !parts.is_empty() && !rest.is_empty() => {
+            let hex: String = rest.iter().map(|b| format!("{:02x}", b)).collect();
+            parts.push(format!("0x{}", hex));
+        }
+        None => return None,
+    }
+    Some(parts.join("/"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use cosmwasm_std::{MemoryStorage, Storage};
+    use cw_storage_plus::{Item, Map};
+
+    use crate::prefixed_storage::{prefixed, PrefixedStorage};
+    use crate::transactions::StorageTransaction;
+
+    #[test]
+    fn decodes_storage_plus_keys() {
+        let storage = MemoryStorage::new();
+        let mut cache = StorageTransaction::new(&storage);
+
+        let mut contract =
+            PrefixedStorage::multilevel(&mut cache, &[NAMESPACE_WASM, b"contract_data/contract"]);
+        Item::<u32>::new("config").save(&mut contract, &1).unwrap();
+        Map::<&str, u32>::new("balances")
+            .save(&mut contract, "owner", &2)
+            .unwrap();
+        Map::<u64, u32>::new("counts")
+            .save(&mut contract, 42, &3)
+            .unwrap();
+        Map::<(&str, &str), u32>::new("allowances")
+            .save(&mut contract, ("owner", "spender"), &4)
+            .unwrap();
+        contract.set(&[0xff, 0x00], b"5");
+
+        let mut bank = prefixed(&mut cache, b"bank");
+        bank.set(b"removed", b"1");
+        bank.remove(b"removed");
+
+        let diff = StorageDiff::from_rep_log(&cache.prepare());
+        assert_eq!(
+            diff.namespaces.keys().collect::<Vec<_>>(),
+            vec!["bank", "wasm/contract_data/contract"]
+        );
+        assert_eq!(
+            diff.writes("bank"),
+            &[StorageWrite {
+                key: b"removed".to_vec(),
+                decoded_key: Some("removed".to_string()),
+                value: None,
+            }]
+        );
+
+        let decoded: Vec<_> = diff
+            .contract_writes(&Addr::unchecked("contract"))
+            .iter()
+            .map(|write| write.decoded_key.as_deref())
+            .collect();
+        assert_eq!(
+            decoded,
+            vec![
+                Some("counts/0x000000000000002a"),
+                Some("balances/owner"),
+                Some("allowances/owner/spender"),
+                Some("config"),
+                None,
+            ]
+        );
+        assert!(diff.writes("staking").is_empty());
+    }
+}
@@ -1,4 +1,11 @@
-use cosmwasm_std::{WasmMsg, WasmQuery};
+use std::fmt;
+
+use cosmwasm_std::{
+    Addr, BankMsg, BankQuery, Binary, Coin, Decimal, DistributionMsg, StakingMsg, StakingQuery,
+    WasmMsg, WasmQuery,
+};
+
+use crate::wasm::WasmAdminSudo;
 use thiserror::Error;
 
 #[derive(Debug, Error, PartialEq, Eq)]
@@ -23,6 +30,9 @@ pub enum Error {
 
     #[error("Unregistered code id")]
     UnregisteredCodeId(usize),
+
+    #[error("Unsupported stargate query: {0}")]
+    UnsupportedStargateQuery(String),
 }
 
 impl Error {
@@ -44,3 +54,304 @@ impl Error {
         Self::EventTypeTooShort(ty.into())
     }
 }
+
+/// Errors returned by `BankKeeper`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BankError {
+    #[error("Cannot transfer empty coins amount")]
+    EmptyAmount,
+
+    #[error("Insufficient funds: needed {needed}, available {available}")]
+    InsufficientFunds { needed: Coin, available: Coin },
+
+    #[error("Unsupported bank message: {0:?}")]
+    UnsupportedMsg(BankMsg),
+
+    #[error("Unsupported bank query: {0:?}")]
+    UnsupportedQuery(BankQuery),
+}
+
+/// Errors returned by `StakeKeeper`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StakingError {
+    #[error("Cannot add validator {0}, since a validator with that address already exists")]
+    ValidatorExists(Addr),
+
+    #[error("validator does not exist")]
+    ValidatorNotFound(Addr),
+
+    #[error("no delegation for (address, validator) tuple")]
+    NoDelegation { delegator: Addr, validator: Addr },
+
+    #[error("invalid delegation amount")]
+    InvalidDelegationAmount,
+
+    #[error("invalid shares amount")]
+    InvalidSharesAmount,
+
+    #[error("cannot delegate coins of denominator {denom}, only of {bonded_denom}")]
+    InvalidDenom { denom: String, bonded_denom: String },
+
+    #[error("expected percentage")]
+    InvalidPercentage(Decimal),
+
+    #[error("Unsupported staking message: {0:?}")]
+    UnsupportedMsg(StakingMsg),
+
+    #[error("Unsupported staking sudo message: {0:?}")]
+    UnsupportedQuery(StakingQuery),
+}
+
+/// Errors returned by `DistributionKeeper`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DistributionError {
+    #[error("Unsupported distribution message: {0:?}")]
+    UnsupportedMsg(DistributionMsg),
+
+    #[error("Something went wrong - Distribution doesn't have sudo messages")]
+    NoSudoMessages,
+
+    #[error("Something went wrong - Distribution doesn't have query messages")]
+    NoQueryMessages,
+}
+
+/// Errors returned by `WasmKeeper` when handling messages, on top of the ones in `Error`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WasmError {
+    #[error("Only admin can change the contract, {sender} is not its admin: {admin:?}")]
+    Unauthorized { sender: Addr, admin: Option<Addr> },
+
+    #[error("Label is required on all contracts")]
+    EmptyLabel,
+
+    #[error("{0} is not allowed to store code")]
+    UploadUnauthorized(Addr),
+
+    #[error("{sender} is not allowed to instantiate code {code_id}")]
+    InstantiateUnauthorized { code_id: usize, sender: Addr },
+
+    #[error("Contract address {0} already exists")]
+    DuplicateContractAddress(Addr),
+
+    #[error("Label {label} is already used by contract {contract}")]
+    DuplicateLabel { label: String, contract: Addr },
+
+    #[error("No contract with label {0}")]
+    UnknownLabel(String),
+
+    #[error("Label {label} is used by multiple contracts: {contracts:?}")]
+    AmbiguousLabel { label: String, contracts: Vec<Addr> },
+
+    #[error("Code name {0} is already used by another code")]
+    DuplicateCodeName(String),
+
+    #[error("No code with name {0}")]
+    UnknownCodeName(String),
+
+    #[error("Unsupported wasm admin sudo: {0:?}")]
+    UnsupportedAdminSudo(WasmAdminSudo),
+
+    #[error("Invalid pagination key {0}")]
+    InvalidPaginationKey(Binary),
+
+    #[error("Maximum contract call depth of {max} exceeded")]
+    CallDepthExceeded { max: usize },
+
+    #[error("Query ran out of gas, used {used} of {limit}")]
+    QueryGasExceeded { limit: u64, used: u64 },
+
+    #[error("Contract {contract} re-entered with {entry_point}, call stack: {}", format_path(.path))]
+    Reentrancy {
+        contract: Addr,
+        entry_point: EntryPoint,
+        /// Contracts on the call stack, from the outermost one
+        path: Vec<Addr>,
+    },
+}
+
+fn format_path(path: &[Addr]) -> String {
+    path.iter()
+        .map(Addr::as_str)
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// Contract entry point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryPoint {
+    Instantiate,
+    Execute,
+    Migrate,
+    Sudo,
+    Reply,
+}
+
+impl fmt::Display for EntryPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EntryPoint::Instantiate => "instantiate",
+            EntryPoint::Execute => "execute",
+            EntryPoint::Migrate => "migrate",
+            EntryPoint::Sudo => "sudo",
+            EntryPoint::Reply => "reply",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Single contract call on the path to a failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractCall {
+    pub contract: Addr,
+    pub entry_point: EntryPoint,
+    /// Sender of the message, `None` for calls made by the chain like `sudo` and `reply`
+    pub sender: Option<Addr>,
+}
+
+impl fmt::Display for ContractCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on contract {}", self.entry_point, self.contract)?;
+        if let Some(sender) = &self.sender {
+            write!(f, " (sender: {})", sender)?;
+        }
+        Ok(())
+    }
+}
+
+/// Context attached to the error of every failed contract call, so the original error stays
+/// downcastable while the call path to it is still known.
+///
+/// Every contract call on the path adds its own context, downcasting the error to `CallContext`
+/// returns the outermost one with the complete path:
+///
+///   let path = &err.downcast_ref::<CallContext>().unwrap().calls;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallContext {
+    /// Contract calls from the one this context is attached to, down to the one which failed
+    pub calls: Vec<ContractCall>,
+}
+
+impl CallContext {
+    /// Attaches the context of `call` to an error returned by it
+    pub(crate) fn attach(call: ContractCall, err: anyhow::Error) -> anyhow::Error {
+        let mut calls = vec![call];
+        if let Some(inner) = err.downcast_ref::<CallContext>() {
+            calls.extend(inner.calls.iter().cloned());
+        }
+        err.context(CallContext { calls })
+    }
+}
+
+impl fmt::Display for CallContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.calls.first() {
+            Some(call) => write!(f, "error executing {}", call),
+            None => f.write_str("error executing contract"),
+        }
+    }
+}
+
+/// ABCI error code a Cosmos SDK chain reports in place of the error message, e.g. to `reply`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AbciCode {
+    pub codespace: String,
+    pub code: u32,
+}
+
+impl AbciCode {
+    pub fn new(codespace: impl Into<String>, code: u32) -> Self {
+        Self {
+            codespace: codespace.into(),
+            code,
+        }
+    }
+
+    /// Code wasmd would report for the given error.
+    ///
+    /// Errors of the built-in modules map to the codes of their Cosmos SDK counterparts, errors
+    /// returned by contracts map to the failure of the entry point that returned them, like
+    /// `ErrExecuteFailed`. Anything else, like errors of custom modules, is undefined.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<BankError>() {
+            return match err {
+                BankError::EmptyAmount => Self::sdk(10),
+                BankError::InsufficientFunds { .. } => Self::sdk(5),
+                BankError::UnsupportedMsg(_) | BankError::UnsupportedQuery(_) => Self::sdk(6),
+            };
+        }
+        if let Some(err) = err.downcast_ref::<StakingError>() {
+            return match err {
+                StakingError::ValidatorExists(_) => Self::new("staking", 4),
+                StakingError::ValidatorNotFound(_) => Self::new("staking", 3),
+                StakingError::NoDelegation { .. } => Self::new("staking", 19),
+                StakingError::InvalidDelegationAmount => Self::sdk(10),
+                StakingError::InvalidSharesAmount => Self::new("staking", 22),
+                StakingError::InvalidDenom { .. } | StakingError::InvalidPercentage(_) => {
+                    Self::sdk(18)
+                }
+                StakingError::UnsupportedMsg(_) | StakingError::UnsupportedQuery(_) => Self::sdk(6),
+            };
+        }
+        if err.downcast_ref::<DistributionError>().is_some() {
+            return Self::sdk(6);
+        }
+        if let Some(err) = err.downcast_ref::<WasmError>() {
+            return match err {
+                WasmError::Unauthorized { .. } => Self::sdk(4),
+                WasmError::EmptyLabel => Self::wasm(12),
+                WasmError::UploadUnauthorized(_) | WasmError::InstantiateUnauthorized { .. } => {
+                    Self::sdk(4)
+                }
+                WasmError::DuplicateContractAddress(_) => Self::wasm(15),
+                WasmError::DuplicateLabel { .. } => Self::wasm(14),
+                WasmError::UnknownLabel(_) => Self::wasm(22),
+                // lookups by label and code names are not available on chain
+                WasmError::AmbiguousLabel { .. }
+                | WasmError::DuplicateCodeName(_)
+                | WasmError::UnknownCodeName(_) => Self::new("undefined", 1),
+                WasmError::UnsupportedAdminSudo(_) => Self::sdk(6),
+                WasmError::InvalidPaginationKey(_) => Self::sdk(18),
+                WasmError::CallDepthExceeded { .. } => Self::wasm(13),
+                WasmError::QueryGasExceeded { .. } => Self::sdk(11),
+                // there is no such check on chain, it's only an opt-in testing aid
+                WasmError::Reentrancy { .. } => Self::new("undefined", 1),
+            };
+        }
+        if let Some(err) = err.downcast_ref::<Error>() {
+            return match err {
+                Error::EmptyAttributeKey { .. }
+                | Error::EmptyAttributeValue { .. }
+                | Error::ReservedAttributeKey(_)
+                | Error::EventTypeTooShort(_) => Self::wasm(21),
+                Error::UnsupportedWasmQuery(_)
+                | Error::UnsupportedWasmMsg(_)
+                | Error::UnsupportedStargateQuery(_) => Self::sdk(6),
+                Error::UnregisteredCodeId(_) => Self::wasm(8),
+            };
+        }
+        // the last call on the path is the contract which returned the error
+        let failed_call = err
+            .downcast_ref::<CallContext>()
+            .and_then(|context| context.calls.last());
+        match failed_call.map(|call| call.entry_point) {
+            Some(EntryPoint::Instantiate) => Self::wasm(4),
+            Some(EntryPoint::Migrate) => Self::wasm(11),
+            Some(EntryPoint::Execute | EntryPoint::Sudo | EntryPoint::Reply) => Self::wasm(5),
+            None => Self::new("undefined", 1),
+        }
+    }
+
+    fn sdk(code: u32) -> Self {
+        Self::new("sdk", code)
+    }
+
+    fn wasm(code: u32) -> Self {
+        Self::new("wasm", code)
+    }
+}
+
+impl fmt::Display for AbciCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "codespace: {}, code: {}", self.codespace, self.code)
+    }
+}
@@ -0,0 +1,165 @@
+use std::cell::Cell;
+
+use cosmwasm_std::Storage;
+#[cfg(feature = "iterator")]
+use cosmwasm_std::{Order, Record};
+
+/// Gas charged for every call into a contract, wasmd's default instance cost
+pub const INSTANCE_COST: u64 = 60_000;
+/// Gas charged for every storage read, as configured in the Cosmos SDK
+pub const READ_COST_FLAT: u64 = 1_000;
+/// Gas charged for every byte of key and value read
+pub const READ_COST_PER_BYTE: u64 = 3;
+/// Gas charged for every record returned by a range scan
+pub const ITER_NEXT_COST_FLAT: u64 = 30;
+
+/// Gas meter of a query, shared by all the storage it reads and all the queries nested in it
+#[derive(Default)]
+pub struct GasMeter {
+    used: Cell<u64>,
+    /// No limit if `None`
+    limit: Option<u64>,
+}
+
+impl GasMeter {
+    pub fn new(limit: Option<u64>) -> Self {
+        GasMeter {
+            used: Cell::new(0),
+            limit,
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.get()
+    }
+
+    pub fn reset(&self) {
+        self.used.set(0);
+    }
+
+    /// Charges `gas`, returning false if the limit is passed
+    pub fn consume(&self, gas: u64) -> bool {
+        self.used.set(self.used.get().saturating_add(gas));
+        !self.exceeded()
+    }
+
+    pub fn exceeded(&self) -> bool {
+        matches!(self.limit, Some(limit) if self.used.get() > limit)
+    }
+}
+
+/// Read-only storage charging gas for every read to the meter it is given.
+/// Once the meter runs out of gas, reads return nothing, so the query stops at its next read
+/// like the VM would abort it. Panics when it is written to.
+pub struct GasMeteredStorage<'a> {
+    storage: Box<dyn Storage + 'a>,
+    meter: &'a GasMeter,
+}
+
+impl<'a> GasMeteredStorage<'a> {
+    pub fn new(storage: Box<dyn Storage + 'a>, meter: &'a GasMeter) -> Self {
+        GasMeteredStorage { storage, meter }
+    }
+}
+
+fn bytes_cost(len: usize) -> u64 {
+    READ_COST_PER_BYTE.saturating_mul(len as u64)
+}
+
+impl<'a> Storage for GasMeteredStorage<'a> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if self.meter.exceeded() {
+            return None;
+        }
+        let value = self.storage.get(key);
+        let len = key.len() + value.as_ref().map_or(0, Vec::len);
+        if self.meter.consume(READ_COST_FLAT + bytes_cost(len)) {
+            value
+        } else {
+            None
+        }
+    }
+
+    #[cfg(feature = "iterator")]
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        if !self.meter.consume(READ_COST_FLAT) {
+            return Box::new(std::iter::empty());
+        }
+        let records = self
+            .storage
+            .range(start, end, order)
+            .take_while(move |record| {
+                self.meter
+                    .consume(ITER_NEXT_COST_FLAT + bytes_cost(record.0.len() + record.1.len()))
+            });
+        Box::new(records)
+    }
+
+    fn set(&mut self, _key: &[u8], _value: &[u8]) {
+        panic!("Contract storage is read-only in queries, cannot set a value");
+    }
+
+    fn remove(&mut self, _key: &[u8]) {
+        panic!("Contract storage is read-only in queries, cannot remove a value");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use cosmwasm_std::MemoryStorage;
+
+    #[test]
+    fn charges_reads() {
+        let mut storage = MemoryStorage::new();
+        storage.set(b"key", b"value");
+        storage.set(b"other", b"v");
+
+        let meter = GasMeter::default();
+        let metered = GasMeteredStorage::new(Box::new(storage), &meter);
+
+        metered.get(b"key");
+        assert_eq!(meter.used(), READ_COST_FLAT + 8 * READ_COST_PER_BYTE);
+        metered.get(b"missing");
+        assert_eq!(meter.used(), 2 * READ_COST_FLAT + 15 * READ_COST_PER_BYTE);
+
+        #[cfg(feature = "iterator")]
+        {
+            meter.reset();
+            assert_eq!(metered.range(None, None, Order::Ascending).count(), 2);
+            assert_eq!(
+                meter.used(),
+                READ_COST_FLAT + 2 * ITER_NEXT_COST_FLAT + 14 * READ_COST_PER_BYTE
+            );
+        }
+    }
+
+    #[test]
+    fn stops_reading_out_of_gas() {
+        let mut storage = MemoryStorage::new();
+        storage.set(b"key", b"value");
+        storage.set(b"other", b"v");
+
+        let meter = GasMeter::new(Some(READ_COST_FLAT + 10 * READ_COST_PER_BYTE));
+        let metered = GasMeteredStorage::new(Box::new(storage), &meter);
+
+        assert_eq!(metered.get(b"key"), Some(b"value".to_vec()));
+        assert!(!meter.exceeded());
+        // the read passing the limit returns nothing, like all reads after it
+        assert_eq!(metered.get(b"key"), None);
+        assert!(meter.exceeded());
+        assert_eq!(metered.get(b"other"), None);
+        #[cfg(feature = "iterator")]
+        assert_eq!(metered.range(None, None, Order::Ascending).count(), 0);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "iterator")]
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result as AnyResult};
+use cosmwasm_std::{BlockInfo, Storage};
+#[cfg(feature = "iterator")]
+use cosmwasm_std::{Order, Record};
+
+#[cfg(feature = "iterator")]
+use crate::transactions::range_bounds;
+use crate::transactions::RepLog;
+
+/// Versions of the whole storage, keyed by block height.
+///
+/// Instead of keeping snapshots, only the committed writes are recorded for every key. The value of
+/// a key at some height is the last value written up to that height, and keys which were never
+/// touched since versioning started simply keep their current value. This relies on every write
+/// to the app storage being recorded, `App` commits all of them through `transact`, including
+/// the ones made with `init_modules`.
+pub struct StorageHistory {
+    /// Block in which versioning started, older state cannot be restored
+    since: BlockInfo,
+    /// Info of every block the app was at since versioning started, as last seen at its height
+    blocks: BTreeMap<u64, BlockInfo>,
+    /// Recorded writes for every key touched since versioning started
+    keys: BTreeMap<Vec<u8>, KeyHistory>,
+}
+
+struct KeyHistory {
+    /// Value the key had before its first recorded write
+    initial: Option<Vec<u8>>,
+    /// Value left at the end of every height at which the key was written
+    writes: BTreeMap<u64, Option<Vec<u8>>>,
+}
+
+impl StorageHistory {
+    pub fn new(block: &BlockInfo) -> Self {
+        StorageHistory {
+            since: block.clone(),
+            blocks: BTreeMap::from([(block.height, block.clone())]),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Records the info of a block the app moved to
+    pub fn record_block(&mut self, block: &BlockInfo) {
+        self.blocks.insert(block.height, block.clone());
+    }
+
+    /// Records all the writes from `rep_log`, which is about to be committed to `storage` at
+    /// the given block. Must be called before the log is committed, so initial values of keys
+    /// written for the first time can still be read.
+    pub fn record(&mut self, storage: &dyn Storage, block: &BlockInfo, rep_log: &RepLog) {
+        for op in rep_log.ops() {
+            let key = op.key();
+            if !self.keys.contains_key(key) {
+                let initial = storage.get(key);
+                self.keys.insert(
+                    key.to_vec(),
+                    KeyHistory {
+                        initial,
+                        writes: BTreeMap::new(),
+                    },
+                );
+            }
+            // unwrap is safe, the entry was created above
+            let history = self.keys.get_mut(key).unwrap();
+            history
+                .writes
+                .insert(block.height, op.value().map(<[u8]>::to_vec));
+        }
+        self.blocks.insert(block.height, block.clone());
+    }
+
+    /// Returns the block info for the given height, as it was when the state was committed.
+    /// Fails if the height is before versioning started or after the `current` block, or if the
+    /// app skipped the height.
+    pub fn block_at(&self, height: u64, current: &BlockInfo) -> AnyResult<BlockInfo> {
+        if height < self.since.height {
+            bail!(
+                "State at height {} is not available, versioning started at height {}",
+                height,
+                self.since.height
+            );
+        }
+        if height > current.height {
+            bail!(
+                "Cannot query state at future height {}, current height is {}",
+                height,
+                current.height
+            );
+        }
+        if height == current.height {
+            return Ok(current.clone());
+        }
+        match self.blocks.get(&height) {
+            Some(block) => Ok(block.clone()),
+            None => bail!("No block was produced at height {}", height),
+        }
+    }
+
+    fn value_at(&self, storage: &dyn Storage, key: &[u8], height: u64) -> Option<Vec<u8>> {
+        match self.keys.get(key) {
+            Some(history) => match history.writes.range(..=height).next_back() {
+                Some((_, value)) => value.clone(),
+                None => history.initial.clone(),
+            },
+            None => storage.get(key),
+        }
+    }
+}
+
+/// Read-only view of the storage at the end of the given block height.
+/// Panics when it is written to.
+pub struct StorageAtHeight<'a> {
+    storage: &'a dyn Storage,
+    history: &'a StorageHistory,
+    height: u64,
+}
+
+impl<'a> StorageAtHeight<'a> {
+    pub fn new(storage: &'a dyn Storage, history: &'a StorageHistory, height: u64) -> Self {
+        StorageAtHeight {
+            storage,
+            history,
+            height,
+        }
+    }
+}
+
+impl<'a> Storage for StorageAtHeight<'a> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.history.value_at(self.storage, key, self.height)
+    }
+
+    fn set(&mut self, _key: &[u8], _value: &[u8]) {
+        panic!("Storage at a past height is read-only, cannot set a value");
+    }
+
+    fn remove(&mut self, _key: &[u8]) {
+        panic!("Storage at a past height is read-only, cannot remove a value");
+    }
+
+    #[cfg(feature = "iterator")]
+    /// range allows iteration over a set of keys, either forwards or backwards
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        // keys which exist now and keys which were touched since versioning started together
+        // contain every key which may have existed at the requested height
+        let mut keys: BTreeSet<Vec<u8>> = self
+            .storage
+            .range(start, end, Order::Ascending)
+            .map(|(key, _)| key)
+            .collect();
+        keys.extend(
+            self.history
+                .keys
+                .range(range_bounds(start, end))
+                .map(|(key, _)| key.clone()),
+        );
+
+        let records = keys.into_iter().filter_map(move |key| {
+            let value = self.get(&key)?;
+            Some((key, value))
+        });
+        match order {
+            Order::Ascending => Box::new(records.collect::<Vec<_>>().into_iter()),
+            Order::Descending => Box::new(records.collect::<Vec<_>>().into_iter().rev()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use cosmwasm_std::testing::mock_env;
+    use cosmwasm_std::MemoryStorage;
+
+    use crate::transactions::StorageTransaction;
+
+    fn commit_at(
+        storage: &mut MemoryStorage,
+        history: &mut StorageHistory,
+        height: u64,
+        action: impl FnOnce(&mut StorageTransaction),
+    ) {
+        let mut cache = StorageTransaction::new(storage);
+        action(&mut cache);
+        let rep_log = cache.prepare();
+        let block = BlockInfo {
+            height,
+            ..mock_env().block
+        };
+        history.record(storage, &block, &rep_log);
+        rep_log.commit(storage);
+    }
+
+    #[test]
+    fn reads_values_at_height() {
+        let mut storage = MemoryStorage::new();
+        storage.set(b"genesis", b"1");
+        storage.set(b"removed", b"1");
+
+        let since = BlockInfo {
+            height: 10,
+            ..mock_env().block
+        };
+        let mut history = StorageHistory::new(&since);
+
+        commit_at(&mut storage, &mut history, 10, |s| s.set(b"genesis", b"2"));
+        commit_at(&mut storage, &mut history, 12, |s| {
+            s.set(b"genesis", b"3");
+            s.set(b"created", b"1");
+            s.remove(b"removed");
+        });
+
+        let at = |height| StorageAtHeight::new(&storage, &history, height);
+        assert_eq!(at(10).get(b"genesis"), Some(b"2".to_vec()));
+        assert_eq!(at(10).get(b"removed"), Some(b"1".to_vec()));
+        assert_eq!(at(10).get(b"created"), None);
+        // nothing written at 11, so state is the same as at 10
+        assert_eq!(at(11).get(b"genesis"), Some(b"2".to_vec()));
+        assert_eq!(at(12).get(b"genesis"), Some(b"3".to_vec()));
+        assert_eq!(at(12).get(b"removed"), None);
+        assert_eq!(at(12).get(b"created"), Some(b"1".to_vec()));
+
+        #[cfg(feature = "iterator")]
+        {
+            let keys = |height, order| -> Vec<Vec<u8>> {
+                at(height)
+                    .range(None, None, order)
+                    .map(|(key, _)| key)
+                    .collect()
+            };
+            assert_eq!(
+                keys(11, Order::Ascending),
+                vec![b"genesis".to_vec(), b"removed".to_vec()]
+            );
+            assert_eq!(
+                keys(12, Order::Descending),
+                vec![b"genesis".to_vec(), b"created".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn block_at_checks_bounds() {
+        let current = mock_env().block;
+        let since = BlockInfo {
+            height: current.height - 2,
+            ..current.clone()
+        };
+        let mut history = StorageHistory::new(&since);
+
+        history.block_at(since.height - 1, &current).unwrap_err();
+        history.block_at(current.height + 1, &current).unwrap_err();
+        assert_eq!(history.block_at(since.height, &current).unwrap(), since);
+        assert_eq!(history.block_at(current.height, &current).unwrap(), current);
+        // heights the app never was at are not made up
+        history.block_at(since.height + 1, &current).unwrap_err();
+
+        // blocks without any commit keep their own time
+        let skipped = BlockInfo {
+            height: since.height + 1,
+            time: since.time.plus_seconds(5),
+            ..since.clone()
+        };
+        history.record_block(&skipped);
+        assert_eq!(history.block_at(skipped.height, &current).unwrap(), skipped);
+    }
+}
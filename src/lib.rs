@@ -11,23 +11,34 @@ mod bank;
 #[allow(clippy::type_complexity)]
 mod contracts;
 pub mod custom_handler;
+mod custom_router;
+mod diff;
 pub mod error;
 mod executor;
+mod gas;
 mod gov;
+mod history;
 mod ibc;
+pub mod mock_contract;
 mod module;
 mod prefixed_storage;
 mod staking;
 mod test_helpers;
+mod tracer;
 mod transactions;
 mod wasm;
 
 pub use crate::app::{
-    custom_app, next_block, App, AppBuilder, BasicApp, BasicAppBuilder, CosmosRouter, Router,
-    SudoMsg,
+    custom_app, next_block, App, AppBuilder, BasicApp, BasicAppBuilder, CosmosRouter,
+    HistoricalQuerier, Router, SudoMsg,
 };
 pub use crate::bank::{Bank, BankKeeper, BankSudo};
-pub use crate::contracts::{Contract, ContractWrapper};
+pub use crate::contracts::{
+    Contract, ContractClosure, ContractWrapper, CustomAdapter, PermissionedClosure, QueryClosure,
+    ReplyClosure,
+};
+pub use crate::custom_router::{CustomRouter, RoutedContract, RoutedCustomMsg, RoutedCustomQuery};
+pub use crate::diff::{StorageDiff, StorageWrite};
 pub use crate::executor::{AppResponse, Executor};
 pub use crate::gov::Gov;
 pub use crate::ibc::{Ibc, IbcAcceptingModule};
@@ -35,4 +46,9 @@ pub use crate::module::{FailingModule, Module};
 pub use crate::staking::{
     Distribution, DistributionKeeper, StakeKeeper, Staking, StakingInfo, StakingSudo,
 };
-pub use crate::wasm::{AddressGenerator, Wasm, WasmKeeper, WasmSudo};
+pub use crate::tracer::{StorageOps, Trace, TraceKind, TraceNode, Tracer};
+pub use crate::wasm::{
+    AccessConfig, AddressGenerator, CodeData, ContractHistoryEntry, ContractHistoryOperation,
+    ReentrancyPolicy, Wasm, WasmAdminSudo, WasmKeeper, WasmSudo, DEFAULT_CODE_CREATOR,
+    DEFAULT_MAX_CALL_DEPTH, DEFAULT_MAX_QUERY_DEPTH,
+};
@@ -0,0 +1,369 @@
+use std::cell::{Ref, RefCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use anyhow::{bail, Result as AnyResult};
+use cosmwasm_std::{
+    Addr, Binary, Coin, CustomQuery, Deps, DepsMut, Empty, Env, MessageInfo, Reply, Response,
+    StdError,
+};
+use derivative::Derivative;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Contract;
+
+/// Entry point of `MockContract` which received a call
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockEntryPoint {
+    Instantiate,
+    Execute,
+    Query,
+    Sudo,
+    Reply,
+    Migrate,
+}
+
+/// Single call received by `MockContract`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockCall {
+    pub entry_point: MockEntryPoint,
+    /// Address of the mock contract instance which was called
+    pub contract: Addr,
+    /// Sender of the message, `None` for entry points without one, like queries and `sudo`
+    pub sender: Option<Addr>,
+    pub funds: Vec<Coin>,
+    /// JSON message the call was made with, the serialized `Reply` for replies
+    pub msg: Value,
+}
+
+/// Calls received by `MockContract`, shared with the contract so they can be read after it is
+/// passed to the app.
+#[derive(Clone, Debug, Default)]
+pub struct MockContractCalls {
+    calls: Rc<RefCell<Vec<MockCall>>>,
+}
+
+impl MockContractCalls {
+    pub fn calls(&self) -> impl Deref<Target = [MockCall]> + '_ {
+        Ref::map(self.calls.borrow(), Vec::as_slice)
+    }
+
+    /// Calls made to the given entry point only
+    pub fn calls_to(&self, entry_point: MockEntryPoint) -> Vec<MockCall> {
+        self.calls
+            .borrow()
+            .iter()
+            .filter(|call| call.entry_point == entry_point)
+            .cloned()
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.calls.borrow_mut().clear();
+    }
+
+    fn record(&self, call: MockCall) {
+        self.calls.borrow_mut().push(call);
+    }
+}
+
+/// Contract stub answering queries and executes with canned responses, recording every call it
+/// receives. Messages are matched by their JSON representation, messages without a canned
+/// response fail. Instantiation, migration, `sudo` and `reply` always succeed with an empty
+/// response.
+///
+///   let mock = MockContract::new()
+///       .on_query(&QueryMsg::Price {}, &PriceResponse { price: 5 })
+///       .on_execute_error(&ExecuteMsg::Swap {}, "Pool is empty");
+///   let calls = mock.calls();
+///   let code_id = app.store_code(Box::new(mock));
+#[derive(Derivative)]
+#[derivative(Default(bound = "", new = "true"))]
+pub struct MockContract<ExecC = Empty, QueryC = Empty> {
+    queries: Vec<(Value, Result<Binary, String>)>,
+    executes: Vec<(Value, Result<Response<ExecC>, String>)>,
+    calls: MockContractCalls,
+    _q: PhantomData<QueryC>,
+}
+
+impl<ExecC, QueryC> MockContract<ExecC, QueryC> {
+    /// Answers queries equal to `msg` with `response`
+    pub fn on_query(mut self, msg: &impl Serialize, response: &impl Serialize) -> Self {
+        let response = serde_json::to_vec(response).expect("query response must serialize");
+        self.queries.push((to_json(msg), Ok(response.into())));
+        self
+    }
+
+    /// Fails queries equal to `msg` with a generic `StdError` carrying `err`
+    pub fn on_query_error(mut self, msg: &impl Serialize, err: impl Into<String>) -> Self {
+        self.queries.push((to_json(msg), Err(err.into())));
+        self
+    }
+
+    /// Answers executes with messages equal to `msg` with `response`
+    pub fn on_execute(mut self, msg: &impl Serialize, response: Response<ExecC>) -> Self {
+        self.executes.push((to_json(msg), Ok(response)));
+        self
+    }
+
+    /// Fails executes with messages equal to `msg` with a generic `StdError` carrying `err`
+    pub fn on_execute_error(mut self, msg: &impl Serialize, err: impl Into<String>) -> Self {
+        self.executes.push((to_json(msg), Err(err.into())));
+        self
+    }
+
+    /// Calls received by the contract, kept up to date after it is passed to the app
+    pub fn calls(&self) -> MockContractCalls {
+        self.calls.clone()
+    }
+
+    fn record(
+        &self,
+        entry_point: MockEntryPoint,
+        env: &Env,
+        info: Option<&MessageInfo>,
+        msg: Value,
+    ) {
+        self.calls.record(MockCall {
+            entry_point,
+            contract: env.contract.address.clone(),
+            sender: info.map(|info| info.sender.clone()),
+            funds: info.map(|info| info.funds.clone()).unwrap_or_default(),
+            msg,
+        });
+    }
+}
+
+fn to_json(msg: &impl Serialize) -> Value {
+    serde_json::to_value(msg).expect("message must serialize to JSON")
+}
+
+/// Parses a received message, keeping messages which aren't JSON as a string
+fn parse_msg(msg: &[u8]) -> Value {
+    serde_json::from_slice(msg)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(msg).into_owned()))
+}
+
+/// Finds the canned result for `msg`, failing if there is none
+fn canned<T: Clone>(
+    responses: &[(Value, Result<T, String>)],
+    msg: &Value,
+    entry_point: &str,
+) -> AnyResult<T> {
+    match responses.iter().find(|(expected, _)| expected == msg) {
+        Some((_, Ok(response))) => Ok(response.clone()),
+        Some((_, Err(err))) => Err(StdError::generic_err(err).into()),
+        None => bail!(
+            "MockContract has no response for {} message {}",
+            entry_point,
+            msg
+        ),
+    }
+}
+
+impl<ExecC, QueryC> Contract<ExecC, QueryC> for MockContract<ExecC, QueryC>
+where
+    ExecC: Clone + fmt::Debug + PartialEq + JsonSchema,
+    QueryC: CustomQuery,
+{
+    fn execute(
+        &self,
+        _deps: DepsMut<QueryC>,
+        env: Env,
+        info: MessageInfo,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<ExecC>> {
+        let msg = parse_msg(&msg);
+        self.record(MockEntryPoint::Execute, &env, Some(&info), msg.clone());
+        canned(&self.executes, &msg, "execute")
+    }
+
+    fn instantiate(
+        &self,
+        _deps: DepsMut<QueryC>,
+        env: Env,
+        info: MessageInfo,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<ExecC>> {
+        self.record(
+            MockEntryPoint::Instantiate,
+            &env,
+            Some(&info),
+            parse_msg(&msg),
+        );
+        Ok(Response::default())
+    }
+
+    fn query(&self, _deps: Deps<QueryC>, env: Env, msg: Vec<u8>) -> AnyResult<Binary> {
+        let msg = parse_msg(&msg);
+        self.record(MockEntryPoint::Query, &env, None, msg.clone());
+        canned(&self.queries, &msg, "query")
+    }
+
+    fn sudo(&self, _deps: DepsMut<QueryC>, env: Env, msg: Vec<u8>) -> AnyResult<Response<ExecC>> {
+        self.record(MockEntryPoint::Sudo, &env, None, parse_msg(&msg));
+        Ok(Response::default())
+    }
+
+    fn reply(&self, _deps: DepsMut<QueryC>, env: Env, msg: Reply) -> AnyResult<Response<ExecC>> {
+        let msg = serde_json::to_value(&msg)?;
+        self.record(MockEntryPoint::Reply, &env, None, msg);
+        Ok(Response::default())
+    }
+
+    fn migrate(
+        &self,
+        _deps: DepsMut<QueryC>,
+        env: Env,
+        msg: Vec<u8>,
+    ) -> AnyResult<Response<ExecC>> {
+        self.record(MockEntryPoint::Migrate, &env, None, parse_msg(&msg));
+        Ok(Response::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cosmwasm_std::{coins, BankMsg};
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use crate::{App, Executor};
+
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "snake_case")]
+    enum ExecuteMsg {
+        Swap { amount: u32 },
+        Withdraw {},
+    }
+
+    #[derive(Serialize, Debug)]
+    #[serde(rename_all = "snake_case")]
+    enum QueryMsg {
+        Price { denom: String },
+        Pool {},
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct PriceResponse {
+        price: u32,
+    }
+
+    #[test]
+    fn answers_with_canned_responses_and_records_calls() {
+        let owner = Addr::unchecked("owner");
+        let mut app = App::new(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &owner, coins(100, "eth"))
+                .unwrap();
+        });
+
+        let mock = MockContract::new()
+            .on_query(
+                &QueryMsg::Price {
+                    denom: "eth".to_owned(),
+                },
+                &PriceResponse { price: 5 },
+            )
+            .on_query_error(&QueryMsg::Pool {}, "Pool is empty")
+            .on_execute(
+                &ExecuteMsg::Swap { amount: 10 },
+                Response::new()
+                    .add_attribute("action", "swap")
+                    .add_message(BankMsg::Send {
+                        to_address: owner.to_string(),
+                        amount: coins(10, "eth"),
+                    }),
+            )
+            .on_execute_error(&ExecuteMsg::Withdraw {}, "Nothing to withdraw");
+        let calls = mock.calls();
+        let code_id = app.store_code(Box::new(mock));
+        let contract = app
+            .instantiate_contract(code_id, owner.clone(), &json!({}), &[], "Mock", None)
+            .unwrap();
+
+        let price: PriceResponse = app
+            .wrap()
+            .query_wasm_smart(
+                &contract,
+                &QueryMsg::Price {
+                    denom: "eth".to_owned(),
+                },
+            )
+            .unwrap();
+        assert_eq!(price, PriceResponse { price: 5 });
+        let err = app
+            .wrap()
+            .query_wasm_smart::<PriceResponse>(&contract, &QueryMsg::Pool {})
+            .unwrap_err();
+        assert!(err.to_string().contains("Pool is empty"));
+
+        let res = app
+            .execute_contract(
+                owner.clone(),
+                contract.clone(),
+                &ExecuteMsg::Swap { amount: 10 },
+                &coins(10, "eth"),
+            )
+            .unwrap();
+        assert_eq!(res.custom_attrs(1), [("action", "swap")]);
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                contract.clone(),
+                &ExecuteMsg::Withdraw {},
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            StdError::generic_err("Nothing to withdraw"),
+            err.downcast().unwrap()
+        );
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                contract.clone(),
+                &ExecuteMsg::Swap { amount: 7 },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            r#"MockContract has no response for execute message {"swap":{"amount":7}}"#
+        );
+
+        let entry_points: Vec<_> = calls.calls().iter().map(|call| call.entry_point).collect();
+        assert_eq!(
+            entry_points,
+            vec![
+                MockEntryPoint::Instantiate,
+                MockEntryPoint::Query,
+                MockEntryPoint::Query,
+                MockEntryPoint::Execute,
+                MockEntryPoint::Execute,
+                MockEntryPoint::Execute,
+            ]
+        );
+        let executes = calls.calls_to(MockEntryPoint::Execute);
+        assert_eq!(
+            executes[0],
+            MockCall {
+                entry_point: MockEntryPoint::Execute,
+                contract,
+                sender: Some(owner),
+                funds: coins(10, "eth"),
+                msg: json!({ "swap": { "amount": 10 } }),
+            }
+        );
+
+        calls.reset();
+        assert!(calls.calls().is_empty());
+    }
+}
@@ -13,6 +13,7 @@ use cw_storage_plus::{Deque, Item, Map};
 use serde::{Deserialize, Serialize};
 
 use crate::app::CosmosRouter;
+use crate::error::{DistributionError, StakingError};
 use crate::executor::AppResponse;
 use crate::prefixed_storage::{prefixed, prefixed_read};
 use crate::{BankSudo, Module};
@@ -161,10 +162,7 @@ impl StakeKeeper {
 
         let val_addr = api.addr_validate(&validator.address)?;
         if VALIDATOR_MAP.may_load(&storage, &val_addr)?.is_some() {
-            bail!(
-                "Cannot add validator {}, since a validator with that address already exists",
-                val_addr
-            );
+            bail!(StakingError::ValidatorExists(val_addr));
         }
 
         VALIDATOR_MAP.save(&mut storage, &val_addr, &validator)?;
@@ -189,7 +187,10 @@ impl StakeKeeper {
 
         let validator_obj = match self.get_validator(&staking_storage, validator)? {
             Some(validator) => validator,
-            None => bail!("validator {} not found", validator),
+            None => {
+                return Err(anyhow!(StakingError::ValidatorNotFound(validator.clone()))
+                    .context(format!("validator {} not found", validator)))
+            }
         };
         // calculate rewards using fixed ratio
         let shares = match STAKES.load(&staking_storage, (delegator, validator)) {
@@ -273,7 +274,7 @@ impl StakeKeeper {
         let mut validator_info = VALIDATOR_INFO
             .may_load(staking_storage, validator)?
             // https://github.com/cosmos/cosmos-sdk/blob/3c5387048f75d7e78b40c5b8d2421fdb8f5d973a/x/staking/types/errors.go#L15
-            .ok_or_else(|| anyhow!("validator does not exist"))?;
+            .ok_or_else(|| StakingError::ValidatorNotFound(validator.clone()))?;
 
         let validator_obj = VALIDATOR_MAP.load(staking_storage, validator)?;
 
@@ -410,7 +411,10 @@ impl StakeKeeper {
         let mut shares = if sub {
             // see https://github.com/cosmos/cosmos-sdk/blob/3c5387048f75d7e78b40c5b8d2421fdb8f5d973a/x/staking/keeper/delegation.go#L1005-L1007
             // and https://github.com/cosmos/cosmos-sdk/blob/3c5387048f75d7e78b40c5b8d2421fdb8f5d973a/x/staking/types/errors.go#L31
-            shares.ok_or_else(|| anyhow!("no delegation for (address, validator) tuple"))?
+            shares.ok_or_else(|| StakingError::NoDelegation {
+                delegator: delegator.clone(),
+                validator: validator.clone(),
+            })?
         } else {
             shares.unwrap_or_default()
         };
@@ -419,7 +423,7 @@ impl StakeKeeper {
         if sub {
             // see https://github.com/cosmos/cosmos-sdk/blob/3c5387048f75d7e78b40c5b8d2421fdb8f5d973a/x/staking/keeper/delegation.go#L1019-L1022
             if amount_dec > shares.stake {
-                bail!("invalid shares amount");
+                bail!(StakingError::InvalidSharesAmount);
             }
             shares.stake -= amount_dec;
             validator_info.stake = validator_info.stake.checked_sub(amount)?;
@@ -507,18 +511,20 @@ impl StakeKeeper {
         ensure_eq!(
             amount.denom,
             staking_info.bonded_denom,
-            anyhow!(
-                "cannot delegate coins of denominator {}, only of {}",
-                amount.denom,
-                staking_info.bonded_denom
-            )
+            StakingError::InvalidDenom {
+                denom: amount.denom.clone(),
+                bonded_denom: staking_info.bonded_denom.clone(),
+            }
         );
         Ok(())
     }
 
     // Asserts that the given coin has the proper denominator
     fn validate_percentage(&self, percentage: Decimal) -> AnyResult<()> {
-        ensure!(percentage <= Decimal::one(), anyhow!("expected percentage"));
+        ensure!(
+            percentage <= Decimal::one(),
+            StakingError::InvalidPercentage(percentage)
+        );
         Ok(())
     }
 }
@@ -546,7 +552,7 @@ impl Module for StakeKeeper {
 
                 // see https://github.com/cosmos/cosmos-sdk/blob/3c5387048f75d7e78b40c5b8d2421fdb8f5d973a/x/staking/types/msg.go#L202-L207
                 if amount.amount.is_zero() {
-                    bail!("invalid delegation amount");
+                    bail!(StakingError::InvalidDelegationAmount);
                 }
 
                 // see https://github.com/cosmos/cosmos-sdk/blob/v0.46.1/x/staking/keeper/msg_server.go#L251-L256
@@ -582,7 +588,7 @@ impl Module for StakeKeeper {
 
                 // see https://github.com/cosmos/cosmos-sdk/blob/3c5387048f75d7e78b40c5b8d2421fdb8f5d973a/x/staking/types/msg.go#L292-L297
                 if amount.amount.is_zero() {
-                    bail!("invalid shares amount");
+                    bail!(StakingError::InvalidSharesAmount);
                 }
 
                 // see https://github.com/cosmos/cosmos-sdk/blob/v0.46.1/x/staking/keeper/msg_server.go#L378-L383
@@ -644,7 +650,7 @@ impl Module for StakeKeeper {
 
                 Ok(AppResponse { events, data: None })
             }
-            m => bail!("Unsupported staking message: {:?}", m),
+            m => bail!(StakingError::UnsupportedMsg(m)),
         }
     }
 
@@ -785,7 +791,12 @@ impl Module for StakeKeeper {
                 let validator_addr = Addr::unchecked(&validator);
                 let validator_obj = match self.get_validator(&staking_storage, &validator_addr)? {
                     Some(validator) => validator,
-                    None => bail!("non-existent validator {}", validator),
+                    None => {
+                        return Err(anyhow!(StakingError::ValidatorNotFound(
+                            validator_addr.clone()
+                        ))
+                        .context(format!("non-existent validator {}", validator_addr)))
+                    }
                 };
                 let delegator = api.addr_validate(&delegator)?;
 
@@ -836,7 +847,7 @@ impl Module for StakeKeeper {
             StakingQuery::Validator { address } => Ok(to_binary(&ValidatorResponse {
                 validator: self.get_validator(&staking_storage, &Addr::unchecked(address))?,
             })?),
-            q => bail!("Unsupported staking sudo message: {:?}", q),
+            q => bail!(StakingError::UnsupportedQuery(q)),
         }
     }
 }
@@ -961,7 +972,7 @@ impl Module for DistributionKeeper {
                         .add_attribute("withdraw_address", address)],
                 })
             }
-            m => bail!("Unsupported distribution message: {:?}", m),
+            m => bail!(DistributionError::UnsupportedMsg(m)),
         }
     }
 
@@ -973,7 +984,7 @@ impl Module for DistributionKeeper {
         _block: &BlockInfo,
         _msg: Empty,
     ) -> AnyResult<AppResponse> {
-        bail!("Something went wrong - Distribution doesn't have sudo messages")
+        bail!(DistributionError::NoSudoMessages)
     }
 
     fn query(
@@ -984,7 +995,7 @@ impl Module for DistributionKeeper {
         _block: &BlockInfo,
         _request: Empty,
     ) -> AnyResult<Binary> {
-        bail!("Something went wrong - Distribution doesn't have query messages")
+        bail!(DistributionError::NoQueryMessages)
     }
 }
 
@@ -1020,6 +1031,8 @@ mod test {
             distribution: DistributionKeeper::new(),
             ibc: FailingModule::new(),
             gov: FailingModule::new(),
+            transaction: None,
+            tracer: Default::default(),
         }
     }
 
@@ -1873,6 +1886,10 @@ mod test {
                 )
                 .unwrap_err();
             assert_eq!(e.to_string(), "validator does not exist");
+            assert_eq!(
+                e.downcast::<StakingError>().unwrap(),
+                StakingError::ValidatorNotFound(Addr::unchecked("nonexistingvaloper"))
+            );
         }
 
         #[test]
@@ -1901,6 +1918,10 @@ mod test {
             )
             .unwrap_err();
             assert_eq!(err.to_string(), "validator does not exist");
+            assert_eq!(
+                err.downcast::<StakingError>().unwrap(),
+                StakingError::ValidatorNotFound(Addr::unchecked(validator))
+            );
 
             // try to undelegate
             let err = execute_stake(
@@ -1913,6 +1934,10 @@ mod test {
             )
             .unwrap_err();
             assert_eq!(err.to_string(), "validator does not exist");
+            assert_eq!(
+                err.downcast::<StakingError>().unwrap(),
+                StakingError::ValidatorNotFound(Addr::unchecked(validator))
+            );
         }
 
         #[test]
@@ -1933,6 +1958,10 @@ mod test {
             )
             .unwrap_err();
             assert_eq!(err.to_string(), "invalid delegation amount");
+            assert_eq!(
+                err.downcast::<StakingError>().unwrap(),
+                StakingError::InvalidDelegationAmount
+            );
 
             // undelegate 0
             let err = execute_stake(
@@ -1945,6 +1974,69 @@ mod test {
             )
             .unwrap_err();
             assert_eq!(err.to_string(), "invalid shares amount");
+            assert_eq!(
+                err.downcast::<StakingError>().unwrap(),
+                StakingError::InvalidSharesAmount
+            );
+        }
+
+        #[test]
+        fn typed_errors() {
+            let (mut test_env, validator) =
+                TestEnv::wrap(setup_test_env(Decimal::percent(10), Decimal::percent(10)));
+            let delegator = Addr::unchecked("delegator1");
+
+            // undelegate without delegation
+            let err = execute_stake(
+                &mut test_env,
+                delegator.clone(),
+                StakingMsg::Undelegate {
+                    validator: validator.to_string(),
+                    amount: coin(100, "TOKEN"),
+                },
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.downcast::<StakingError>().unwrap(),
+                StakingError::NoDelegation {
+                    delegator: delegator.clone(),
+                    validator: validator.clone(),
+                }
+            );
+
+            // query delegation at a validator which doesn't exist
+            let err = query_stake::<DelegationResponse>(
+                &test_env,
+                StakingQuery::Delegation {
+                    delegator: delegator.to_string(),
+                    validator: "nonexistingvaloper".to_string(),
+                },
+            )
+            .unwrap_err();
+            assert_eq!(err.to_string(), "non-existent validator nonexistingvaloper");
+            assert_eq!(
+                err.downcast::<StakingError>().unwrap(),
+                StakingError::ValidatorNotFound(Addr::unchecked("nonexistingvaloper"))
+            );
+
+            // distribution has no queries
+            let err = test_env
+                .router
+                .distribution
+                .query(
+                    &test_env.api,
+                    &test_env.store,
+                    &test_env
+                        .router
+                        .querier(&test_env.api, &test_env.store, &test_env.block),
+                    &test_env.block,
+                    Empty {},
+                )
+                .unwrap_err();
+            assert_eq!(
+                err.downcast::<DistributionError>().unwrap(),
+                DistributionError::NoQueryMessages
+            );
         }
 
         #[test]
@@ -2,8 +2,10 @@
 
 pub mod caller;
 pub mod echo;
+pub mod env;
 pub mod error;
 pub mod hackatom;
 pub mod payout;
+pub mod recurse;
 pub mod reflect;
 pub mod stargate;
@@ -0,0 +1,52 @@
+//! Contract remembering the `Env` it was executed with, so tests can verify what the chain passes
+//! to contracts
+
+use cosmwasm_std::{to_binary, Binary, Deps, DepsMut, Empty, Env, MessageInfo, Response, StdError};
+use cw_storage_plus::Item;
+use serde::{Deserialize, Serialize};
+
+use crate::test_helpers::EmptyMsg;
+use crate::{Contract, ContractWrapper};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryMsg {
+    /// Returns the `Env` of the last instantiate or execute call
+    Executed {},
+    /// Returns the `Env` this query is called with
+    Current {},
+}
+
+const ENV: Item<Env> = Item::new("env");
+
+fn instantiate(
+    deps: DepsMut,
+    env: Env,
+    _info: MessageInfo,
+    _msg: EmptyMsg,
+) -> Result<Response, StdError> {
+    ENV.save(deps.storage, &env)?;
+    Ok(Response::new())
+}
+
+fn execute(
+    deps: DepsMut,
+    env: Env,
+    _info: MessageInfo,
+    _msg: EmptyMsg,
+) -> Result<Response, StdError> {
+    ENV.save(deps.storage, &env)?;
+    Ok(Response::new())
+}
+
+fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, StdError> {
+    match msg {
+        QueryMsg::Executed {} => to_binary(&ENV.load(deps.storage)?),
+        QueryMsg::Current {} => to_binary(&env),
+    }
+}
+
+pub fn contract() -> Box<dyn Contract<Empty>> {
+    let contract = ContractWrapper::new(execute, instantiate, query);
+    Box::new(contract)
+}
@@ -88,3 +88,14 @@ where
         ContractWrapper::new_with_empty(execute, instantiate, query).with_sudo_empty(sudo);
     Box::new(contract)
 }
+
+/// Same contract, declared as having IBC entry points
+pub fn contract_with_ibc_entry_points<C>() -> Box<dyn Contract<C>>
+where
+    C: Clone + fmt::Debug + PartialEq + JsonSchema + 'static,
+{
+    let contract = ContractWrapper::new_with_empty(execute, instantiate, query)
+        .with_sudo_empty(sudo)
+        .with_ibc_entry_points();
+    Box::new(contract)
+}
@@ -0,0 +1,67 @@
+//! Contract calling or querying itself recursively, to test nesting limits
+
+use std::fmt;
+
+use cosmwasm_std::{
+    to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdError, WasmMsg,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{test_helpers::EmptyMsg, Contract, ContractWrapper};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    /// How many more times the contract calls or queries itself
+    pub depth: u32,
+}
+
+fn instantiate(
+    _deps: DepsMut,
+    _env: Env,
+    _info: MessageInfo,
+    _msg: EmptyMsg,
+) -> Result<Response, StdError> {
+    Ok(Response::default())
+}
+
+fn execute(
+    _deps: DepsMut,
+    env: Env,
+    _info: MessageInfo,
+    msg: Message,
+) -> Result<Response, StdError> {
+    if msg.depth == 0 {
+        return Ok(Response::default());
+    }
+    let message = WasmMsg::Execute {
+        contract_addr: env.contract.address.into(),
+        msg: to_binary(&Message {
+            depth: msg.depth - 1,
+        })?,
+        funds: vec![],
+    };
+    Ok(Response::new().add_message(message))
+}
+
+/// Returns the number of nested queries made
+fn query(deps: Deps, env: Env, msg: Message) -> Result<Binary, StdError> {
+    if msg.depth == 0 {
+        return to_binary(&0u32);
+    }
+    let nested: u32 = deps.querier.query_wasm_smart(
+        env.contract.address,
+        &Message {
+            depth: msg.depth - 1,
+        },
+    )?;
+    to_binary(&(nested + 1))
+}
+
+pub fn contract<C>() -> Box<dyn Contract<C>>
+where
+    C: Clone + fmt::Debug + PartialEq + JsonSchema + 'static,
+{
+    let contract = ContractWrapper::new_with_empty(execute, instantiate, query);
+    Box::new(contract)
+}
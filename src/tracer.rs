@@ -0,0 +1,496 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+use anyhow::Result as AnyResult;
+use cosmwasm_std::{
+    Addr, Binary, CosmosMsg, CustomQuery, Empty, Event, QueryRequest, Reply, Storage, SubMsg,
+    WasmMsg, WasmQuery,
+};
+#[cfg(feature = "iterator")]
+use cosmwasm_std::{Order, Record};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::app::SudoMsg;
+use crate::executor::AppResponse;
+
+/// Kind of a single call recorded in a `Trace`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceKind {
+    /// `CosmosMsg` dispatched to a module, either top-level or sent by a contract
+    Msg,
+    /// `SubMsg` with a reply sent by a contract, the dispatched message and the reply are its
+    /// children. Messages sent without reply are only recorded as `Msg`.
+    SubMsg,
+    /// Reply to a `SubMsg`, called on the contract which sent it
+    Reply,
+    /// Query sent to a module, either top-level or by a contract
+    Query,
+    /// Privileged message run by the chain itself, like `App::sudo` and `App::wasm_sudo`
+    Sudo,
+}
+
+/// Number of storage operations performed by a call, including all its nested calls
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StorageOps {
+    /// Number of single key reads and range scans
+    pub reads: u64,
+    pub writes: u64,
+    pub removes: u64,
+}
+
+/// Single recorded call with all the calls it made nested in `children`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TraceNode {
+    pub kind: TraceKind,
+    /// Account sending the message, or contract sending the query. `None` for calls made by the
+    /// chain itself, like replies and top-level queries.
+    pub sender: Option<String>,
+    /// Contract address, or name of the module handling the call
+    pub target: String,
+    /// Name of the code run by the call, if the contract code was stored with a name
+    pub code_name: Option<String>,
+    /// JSON representation of the message, custom messages are shown in their debug form
+    pub msg: Value,
+    /// Data returned on success
+    pub data: Option<Binary>,
+    /// Full error chain on failure
+    pub error: Option<String>,
+    /// Events emitted, including the ones of all nested calls
+    pub events: Vec<Event>,
+    pub storage_ops: StorageOps,
+    /// Suspicious behaviour detected during the call, like contract re-entrancy
+    pub warnings: Vec<String>,
+    pub children: Vec<TraceNode>,
+}
+
+impl TraceNode {
+    fn new(kind: TraceKind, sender: Option<String>, target: String, msg: Value) -> Self {
+        TraceNode {
+            kind,
+            sender,
+            target,
+            code_name: None,
+            msg,
+            data: None,
+            error: None,
+            events: vec![],
+            storage_ops: StorageOps::default(),
+            warnings: vec![],
+            children: vec![],
+        }
+    }
+
+    pub(crate) fn msg<T: fmt::Debug>(sender: &Addr, msg: &CosmosMsg<T>) -> Self {
+        let target = match msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, .. })
+            | CosmosMsg::Wasm(WasmMsg::Migrate { contract_addr, .. })
+            | CosmosMsg::Wasm(WasmMsg::UpdateAdmin { contract_addr, .. })
+            | CosmosMsg::Wasm(WasmMsg::ClearAdmin { contract_addr }) => contract_addr.clone(),
+            CosmosMsg::Wasm(_) => "wasm".to_string(),
+            CosmosMsg::Bank(_) => "bank".to_string(),
+            CosmosMsg::Custom(_) => "custom".to_string(),
+            CosmosMsg::Staking(_) => "staking".to_string(),
+            CosmosMsg::Distribution(_) => "distribution".to_string(),
+            CosmosMsg::Stargate { .. } => "stargate".to_string(),
+            CosmosMsg::Ibc(_) => "ibc".to_string(),
+            CosmosMsg::Gov(_) => "gov".to_string(),
+            _ => "unknown".to_string(),
+        };
+        TraceNode::new(
+            TraceKind::Msg,
+            Some(sender.to_string()),
+            target,
+            msg_to_json(msg),
+        )
+    }
+
+    pub(crate) fn submsg<T: fmt::Debug>(contract: &Addr, msg: &SubMsg<T>) -> Self {
+        let target = TraceNode::msg(contract, &msg.msg).target;
+        TraceNode::new(
+            TraceKind::SubMsg,
+            Some(contract.to_string()),
+            target,
+            json!({
+                "id": msg.id,
+                "reply_on": msg.reply_on,
+                "gas_limit": msg.gas_limit,
+            }),
+        )
+    }
+
+    pub(crate) fn reply(contract: &Addr, reply: &Reply) -> Self {
+        TraceNode::new(
+            TraceKind::Reply,
+            None,
+            contract.to_string(),
+            serde_json::to_value(reply).unwrap_or_default(),
+        )
+    }
+
+    pub(crate) fn query<Q: CustomQuery>(sender: Option<String>, request: &QueryRequest<Q>) -> Self {
+        let target = match request {
+            QueryRequest::Wasm(WasmQuery::Smart { contract_addr, .. })
+            | QueryRequest::Wasm(WasmQuery::Raw { contract_addr, .. })
+            | QueryRequest::Wasm(WasmQuery::ContractInfo { contract_addr }) => {
+                contract_addr.clone()
+            }
+            QueryRequest::Wasm(_) => "wasm".to_string(),
+            QueryRequest::Bank(_) => "bank".to_string(),
+            QueryRequest::Custom(_) => "custom".to_string(),
+            QueryRequest::Staking(_) => "staking".to_string(),
+            QueryRequest::Stargate { .. } => "stargate".to_string(),
+            QueryRequest::Ibc(_) => "ibc".to_string(),
+            _ => "unknown".to_string(),
+        };
+        TraceNode::new(
+            TraceKind::Query,
+            sender,
+            target,
+            serde_json::to_value(request).unwrap_or_default(),
+        )
+    }
+
+    pub(crate) fn sudo(msg: &SudoMsg) -> Self {
+        let (target, msg) = match msg {
+            SudoMsg::Wasm(msg) => (
+                msg.contract_addr.to_string(),
+                serde_json::from_slice(&msg.msg).unwrap_or_default(),
+            ),
+            SudoMsg::Bank(msg) => ("bank".to_string(), debug_to_json(msg)),
+            SudoMsg::Custom(msg) => ("custom".to_string(), debug_to_json(msg)),
+            SudoMsg::Staking(msg) => ("staking".to_string(), debug_to_json(msg)),
+            SudoMsg::WasmAdmin(msg) => ("wasm".to_string(), debug_to_json(msg)),
+        };
+        TraceNode::new(TraceKind::Sudo, None, target, msg)
+    }
+
+    fn write_tree(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let kind = match self.kind {
+            TraceKind::Msg => "msg",
+            TraceKind::SubMsg => "submsg",
+            TraceKind::Reply => "reply",
+            TraceKind::Query => "query",
+            TraceKind::Sudo => "sudo",
+        };
+        write!(f, "{:indent$}{} ", "", kind, indent = depth * 2)?;
+        if let Some(sender) = &self.sender {
+            write!(f, "{} ", sender)?;
+        }
+        write!(f, "-> {} ", self.target)?;
+        if let Some(code_name) = &self.code_name {
+            write!(f, "({}) ", code_name)?;
+        }
+        write!(f, "{}", self.msg)?;
+        match &self.error {
+            Some(err) => write!(f, " [error: {}]", err)?,
+            None => write!(f, " [ok, {} events]", self.events.len())?,
+        }
+        writeln!(
+            f,
+            " [storage: {} reads, {} writes, {} removes]",
+            self.storage_ops.reads, self.storage_ops.writes, self.storage_ops.removes
+        )?;
+        for warning in &self.warnings {
+            writeln!(
+                f,
+                "{:indent$}warning: {}",
+                "",
+                warning,
+                indent = depth * 2 + 2
+            )?;
+        }
+        for child in &self.children {
+            child.write_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Call tree recorded by the tracer for a single transaction.
+///
+/// It is printed as an indented tree with `Display`, or can be exported with `to_json`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Trace {
+    /// Top-level calls, in execution order
+    pub calls: Vec<TraceNode>,
+}
+
+impl Trace {
+    pub fn to_json(&self) -> String {
+        // serializing plain data structures cannot fail
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for call in &self.calls {
+            call.write_tree(f, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records a call tree of everything the router executes while a transaction is being traced.
+/// Disabled by default, as converting all messages to JSON slows down execution.
+#[derive(Default)]
+pub struct Tracer {
+    enabled: bool,
+    recording: Cell<bool>,
+    /// Calls in progress, innermost last
+    stack: RefCell<Vec<TraceNode>>,
+    /// Finished top-level calls
+    calls: RefCell<Vec<TraceNode>>,
+}
+
+impl Tracer {
+    pub(crate) fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Starts recording a new transaction
+    pub(crate) fn start(&self) {
+        self.stack.borrow_mut().clear();
+        self.calls.borrow_mut().clear();
+        self.recording.set(self.enabled);
+    }
+
+    /// Stops recording, returning the trace if tracing is enabled
+    pub(crate) fn finish(&self) -> Option<Trace> {
+        if !self.recording.replace(false) {
+            return None;
+        }
+        self.stack.borrow_mut().clear();
+        Some(Trace {
+            calls: self.calls.take(),
+        })
+    }
+
+    pub(crate) fn is_recording(&self) -> bool {
+        self.recording.get()
+    }
+
+    /// Target of the innermost call in progress, which is the sender of queries it makes
+    pub(crate) fn current_target(&self) -> Option<String> {
+        self.stack.borrow().last().map(|node| node.target.clone())
+    }
+
+    /// Adds a warning to the innermost call in progress
+    pub(crate) fn warn(&self, warning: String) {
+        if let Some(node) = self.stack.borrow_mut().last_mut() {
+            node.warnings.push(warning);
+        }
+    }
+
+    /// Records the name of the code run by the innermost call in progress
+    pub(crate) fn set_code_name(&self, name: String) {
+        if let Some(node) = self.stack.borrow_mut().last_mut() {
+            node.code_name = Some(name);
+        }
+    }
+
+    fn begin(&self, node: TraceNode) {
+        self.stack.borrow_mut().push(node);
+    }
+
+    fn end(
+        &self,
+        data: Option<Binary>,
+        events: Vec<Event>,
+        error: Option<String>,
+        ops: StorageOps,
+    ) {
+        let mut stack = self.stack.borrow_mut();
+        let mut node = match stack.pop() {
+            Some(node) => node,
+            None => return,
+        };
+        node.data = data;
+        node.events = events;
+        node.error = error;
+        node.storage_ops = ops;
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => self.calls.borrow_mut().push(node),
+        }
+    }
+}
+
+/// Runs `call`, recording it in the tracer if one is given along with its node
+pub(crate) fn traced_call<F>(
+    trace: Option<(&Tracer, TraceNode)>,
+    storage: &mut dyn Storage,
+    call: F,
+) -> AnyResult<AppResponse>
+where
+    F: FnOnce(&mut dyn Storage) -> AnyResult<AppResponse>,
+{
+    let (tracer, node) = match trace {
+        Some(trace) => trace,
+        None => return call(storage),
+    };
+
+    tracer.begin(node);
+    let mut storage = CountingStorage::new(Writable(storage));
+    let res = call(&mut storage);
+    let ops = storage.ops.get();
+    match &res {
+        Ok(res) => tracer.end(res.data.clone(), res.events.clone(), None, ops),
+        Err(err) => tracer.end(None, vec![], Some(format!("{:#}", err)), ops),
+    }
+    res
+}
+
+/// Runs `query`, recording it in the tracer if one is given along with its node
+pub(crate) fn traced_query<F>(
+    trace: Option<(&Tracer, TraceNode)>,
+    storage: &dyn Storage,
+    query: F,
+) -> AnyResult<Binary>
+where
+    F: FnOnce(&dyn Storage) -> AnyResult<Binary>,
+{
+    let (tracer, node) = match trace {
+        Some(trace) => trace,
+        None => return query(storage),
+    };
+
+    tracer.begin(node);
+    let storage = CountingStorage::new(ReadOnly(storage));
+    let res = query(&storage);
+    let ops = storage.ops.get();
+    match &res {
+        Ok(data) => tracer.end(Some(data.clone()), vec![], None, ops),
+        Err(err) => tracer.end(None, vec![], Some(format!("{:#}", err)), ops),
+    }
+    res
+}
+
+/// JSON representation of messages which are not serializable
+fn debug_to_json(msg: &impl fmt::Debug) -> Value {
+    Value::String(format!("{:?}", msg))
+}
+
+fn msg_to_json<T: fmt::Debug>(msg: &CosmosMsg<T>) -> Value {
+    // custom messages are not required to be serializable
+    let msg: CosmosMsg<Empty> = match msg {
+        CosmosMsg::Custom(msg) => return json!({ "custom": format!("{:?}", msg) }),
+        CosmosMsg::Bank(msg) => CosmosMsg::Bank(msg.clone()),
+        CosmosMsg::Staking(msg) => CosmosMsg::Staking(msg.clone()),
+        CosmosMsg::Distribution(msg) => CosmosMsg::Distribution(msg.clone()),
+        CosmosMsg::Stargate { type_url, value } => CosmosMsg::Stargate {
+            type_url: type_url.clone(),
+            value: value.clone(),
+        },
+        CosmosMsg::Ibc(msg) => CosmosMsg::Ibc(msg.clone()),
+        CosmosMsg::Wasm(msg) => CosmosMsg::Wasm(msg.clone()),
+        CosmosMsg::Gov(msg) => CosmosMsg::Gov(msg.clone()),
+        msg => return Value::String(format!("{:?}", msg)),
+    };
+    serde_json::to_value(msg).unwrap_or_default()
+}
+
+/// Storage to be wrapped in `CountingStorage`
+struct Writable<'a>(&'a mut dyn Storage);
+
+impl<'a> Storage for Writable<'a> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key)
+    }
+
+    #[cfg(feature = "iterator")]
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        self.0.range(start, end, order)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.0.set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.0.remove(key)
+    }
+}
+
+/// Read-only storage to be wrapped in `CountingStorage`, panics when written to
+struct ReadOnly<'a>(&'a dyn Storage);
+
+impl<'a> Storage for ReadOnly<'a> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key)
+    }
+
+    #[cfg(feature = "iterator")]
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        self.0.range(start, end, order)
+    }
+
+    fn set(&mut self, _key: &[u8], _value: &[u8]) {
+        panic!("Traced query storage is read-only, cannot set a value");
+    }
+
+    fn remove(&mut self, _key: &[u8]) {
+        panic!("Traced query storage is read-only, cannot remove a value");
+    }
+}
+
+/// Storage wrapper counting all operations made through it
+struct CountingStorage<S> {
+    storage: S,
+    ops: Cell<StorageOps>,
+}
+
+impl<S> CountingStorage<S> {
+    fn new(storage: S) -> Self {
+        CountingStorage {
+            storage,
+            ops: Cell::new(StorageOps::default()),
+        }
+    }
+
+    fn count(&self, update: impl FnOnce(&mut StorageOps)) {
+        let mut ops = self.ops.get();
+        update(&mut ops);
+        self.ops.set(ops);
+    }
+}
+
+impl<S: Storage> Storage for CountingStorage<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.count(|ops| ops.reads += 1);
+        self.storage.get(key)
+    }
+
+    #[cfg(feature = "iterator")]
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        self.count(|ops| ops.reads += 1);
+        self.storage.range(start, end, order)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.count(|ops| ops.writes += 1);
+        self.storage.set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.count(|ops| ops.removes += 1);
+        self.storage.remove(key)
+    }
+}
@@ -127,6 +127,11 @@ impl RepLog {
         self.ops_log.push(op);
     }
 
+    /// returns the list of changes in the order they were made
+    pub fn ops(&self) -> &[Op] {
+        &self.ops_log
+    }
+
     /// applies the stored list of `Op`s to the provided `Storage`
     pub fn commit(self, storage: &mut dyn Storage) {
         for op in self.ops_log {
@@ -137,7 +142,7 @@ impl RepLog {
 
 /// Op is the user operation, which can be stored in the RepLog.
 /// Currently Set or Delete.
-enum Op {
+pub enum Op {
     /// represents the `Set` operation for setting a key-value pair in storage
     Set {
         key: Vec<u8>,
@@ -149,6 +154,22 @@ enum Op {
 }
 
 impl Op {
+    /// returns the key this `Op` changes
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Op::Set { key, .. } => key,
+            Op::Delete { key } => key,
+        }
+    }
+
+    /// returns the value stored under the key after applying this `Op`
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            Op::Set { value, .. } => Some(value),
+            Op::Delete { .. } => None,
+        }
+    }
+
     /// applies this `Op` to the provided storage
     pub fn apply(&self, storage: &mut dyn Storage) {
         match self {
@@ -158,7 +179,7 @@ impl Op {
     }
 
     /// converts the Op to a delta, which can be stored in a local cache
-    pub fn to_delta(&self) -> Delta {
+    fn to_delta(&self) -> Delta {
         match self {
             Op::Set { value, .. } => Delta::Set {
                 value: value.clone(),
@@ -258,7 +279,7 @@ where
 }
 
 #[cfg(feature = "iterator")]
-fn range_bounds(start: Option<&[u8]>, end: Option<&[u8]>) -> impl RangeBounds<Vec<u8>> {
+pub(crate) fn range_bounds(start: Option<&[u8]>, end: Option<&[u8]>) -> impl RangeBounds<Vec<u8>> {
     (
         start.map_or(Bound::Unbounded, |x| Bound::Included(x.to_vec())),
         end.map_or(Bound::Unbounded, |x| Bound::Excluded(x.to_vec())),
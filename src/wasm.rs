@@ -1,32 +1,59 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::fmt;
-use std::ops::Deref;
 
 use cosmwasm_std::{
     to_binary, Addr, Api, Attribute, BankMsg, Binary, BlockInfo, Coin, ContractInfo,
-    ContractInfoResponse, CustomQuery, Deps, DepsMut, Env, Event, MessageInfo, Order, Querier,
-    QuerierWrapper, Record, Reply, ReplyOn, Response, StdResult, Storage, SubMsg, SubMsgResponse,
-    SubMsgResult, TransactionInfo, WasmMsg, WasmQuery,
+    ContractInfoResponse, CustomQuery, Deps, DepsMut, Empty, Env, Event, HexBinary, MessageInfo,
+    Order, Querier, QuerierWrapper, Record, Reply, ReplyOn, Response, StdResult, Storage, SubMsg,
+    SubMsgResponse, SubMsgResult, SystemError, TransactionInfo, WasmMsg, WasmQuery,
 };
 use prost::Message;
 use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use cw_storage_plus::Map;
+use cw_storage_plus::{Item, Map};
 
 use crate::app::{CosmosRouter, RouterQuerier};
 use crate::contracts::Contract;
-use crate::error::Error;
+use crate::error::{AbciCode, CallContext, ContractCall, EntryPoint, Error, WasmError};
 use crate::executor::AppResponse;
+use crate::gas::{GasMeter, GasMeteredStorage, INSTANCE_COST};
 use crate::prefixed_storage::{prefixed, prefixed_read, PrefixedStorage, ReadonlyPrefixedStorage};
+use crate::tracer::{traced_call, TraceNode};
 use crate::transactions::transactional;
 use cosmwasm_std::testing::mock_wasmd_attr;
 
-use anyhow::{bail, Context, Result as AnyResult};
+use anyhow::{anyhow, bail, Result as AnyResult};
 
 // Contract state is kept in Storage, separate from the contracts themselves
 const CONTRACTS: Map<&Addr, ContractData> = Map::new("contracts");
+/// Ids of codes pinned to the cache
+const PINNED_CODES: Map<u64, Empty> = Map::new("pinned_codes");
+/// Code history of contracts, in the order of changes
+const CONTRACT_HISTORY: Map<&Addr, Vec<ContractHistoryEntry>> = Map::new("contract_history");
+/// Index of contracts by their labels
+const CONTRACTS_BY_LABEL: Map<(&str, &Addr), Empty> = Map::new("contracts_by_label");
+/// Index of contracts by the codes they currently use, in the order they started using them
+const CONTRACTS_BY_CODE: Map<(u64, u64), Addr> = Map::new("contracts_by_code");
+/// Index of contracts by the accounts which instantiated them, in the order of instantiation
+const CONTRACTS_BY_CREATOR: Map<(&Addr, u64), Addr> = Map::new("contracts_by_creator");
+/// Positions of contracts in the indexes above
+const CONTRACT_POSITIONS: Map<&Addr, ContractPositions> = Map::new("contract_positions");
+/// Next position in the indexes, incremented with every contract created or migrated
+const NEXT_POSITION: Item<u64> = Item::new("next_position");
+
+/// Positions of a contract in the indexes by code and by creator, which keep contracts in the
+/// order wasmd lists them
+#[derive(Serialize, Deserialize)]
+struct ContractPositions {
+    /// Position at instantiation
+    created: u64,
+    /// Position at which the contract started using its current code
+    code_set: u64,
+}
 
 pub const NAMESPACE_WASM: &[u8] = b"wasm";
 const CONTRACT_ATTR: &str = "_contract_addr";
@@ -46,6 +73,23 @@ impl WasmSudo {
     }
 }
 
+/// Privileged wasm operations, done by governance proposals in wasmd
+#[derive(Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub enum WasmAdminSudo {
+    /// Pins codes to the cache
+    PinCodes { code_ids: Vec<u64> },
+    /// Removes codes from the cache
+    UnpinCodes { code_ids: Vec<u64> },
+    /// Changes the label of a contract
+    UpdateContractLabel {
+        contract_addr: String,
+        new_label: String,
+    },
+    /// Makes a contract use another code without migrating it, like an upgrade the contract
+    /// itself was never told about. It is not recorded in the contract history.
+    SetContractCode { contract_addr: String, code_id: u64 },
+}
+
 /// Contract Data includes information about contract, equivalent of `ContractInfo` in wasmd
 /// interface.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
@@ -60,6 +104,67 @@ pub struct ContractData {
     pub label: String,
     /// Blockchain height in the moment of instantiating the contract
     pub created: u64,
+    /// Name of the contract code, if it was stored with `store_code_with_name`. Only kept in
+    /// memory, like the code itself, and filled in when loading the contract.
+    #[serde(skip)]
+    pub code_name: Option<String>,
+}
+
+/// Single change of the code of a contract, equivalent of `ContractCodeHistoryEntry` in wasmd
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct ContractHistoryEntry {
+    pub operation: ContractHistoryOperation,
+    /// Code used by the contract since this change
+    pub code_id: usize,
+    /// Height of the block the change was made in
+    pub block_height: u64,
+    /// Index of the transaction which made the change within its block, 0 for genesis
+    pub tx_index: u32,
+    /// Message passed to the contract while instantiating or migrating it, empty for genesis
+    pub msg: Binary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum ContractHistoryOperation {
+    /// Contract instantiated with a message
+    Init,
+    /// Contract migrated to a new code
+    Migrate,
+    /// Contract registered directly in the storage, without instantiation
+    Genesis,
+}
+
+/// Code Data includes information about stored code, equivalent of `CodeInfo` in wasmd
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodeData {
+    /// Address of account who stored the code
+    pub creator: Addr,
+    /// Checksum standing in for the hash of the wasm blob, unique for every code
+    pub checksum: HexBinary,
+    /// Who may instantiate the code
+    pub instantiate_permission: AccessConfig,
+    /// Name the code was stored with, unique among all codes
+    pub name: Option<String>,
+}
+
+/// Access rules for storing or instantiating code, equivalent of `AccessConfig` in wasmd
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum AccessConfig {
+    Nobody,
+    #[default]
+    Everybody,
+    AnyOfAddresses(Vec<Addr>),
+}
+
+impl AccessConfig {
+    /// Returns true if `addr` is allowed access
+    pub fn allows(&self, addr: &Addr) -> bool {
+        match self {
+            AccessConfig::Nobody => false,
+            AccessConfig::Everybody => true,
+            AccessConfig::AnyOfAddresses(addresses) => addresses.contains(addr),
+        }
+    }
 }
 
 pub trait Wasm<ExecC, QueryC> {
@@ -94,19 +199,106 @@ pub trait Wasm<ExecC, QueryC> {
         block: &BlockInfo,
         msg: Binary,
     ) -> AnyResult<AppResponse>;
+
+    /// Privileged operations on the module itself, cannot be called via CosmosMsg
+    fn admin_sudo(
+        &self,
+        _api: &dyn Api,
+        _storage: &mut dyn Storage,
+        _router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        _block: &BlockInfo,
+        msg: WasmAdminSudo,
+    ) -> AnyResult<AppResponse> {
+        bail!(WasmError::UnsupportedAdminSudo(msg))
+    }
+
+    /// Handles Stargate queries of the `cosmwasm.wasm.v1.Query` service
+    fn query_stargate(
+        &self,
+        _api: &dyn Api,
+        _storage: &dyn Storage,
+        _block: &BlockInfo,
+        path: String,
+        _data: Binary,
+    ) -> AnyResult<Binary> {
+        bail!(Error::UnsupportedStargateQuery(path))
+    }
+}
+
+/// Default limit of nested contract calls. Deep enough for sane contract designs, while keeping
+/// clear of stack overflows in unoptimized builds running on the 2 MiB stack of test threads.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 50;
+
+/// Creator recorded for code stored without one, like with `App::store_code`
+pub const DEFAULT_CODE_CREATOR: &str = "creator";
+
+/// Default limit of smart queries nested in each other, the same as wasmd's maximum query stack
+/// size
+pub const DEFAULT_MAX_QUERY_DEPTH: usize = 10;
+
+/// What happens when a contract is called, directly or indirectly, while one of its entry points
+/// is still on the call stack. Replies to the contract which sent the submessage are not
+/// considered re-entrancy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReentrancyPolicy {
+    /// Re-entrancy is allowed silently, like on chain
+    #[default]
+    Allow,
+    /// Re-entrancy is allowed, but recorded as a warning on the call in the trace, if tracing
+    /// is enabled
+    Warn,
+    /// Re-entrant calls fail with `WasmError::Reentrancy`
+    Fail,
 }
 
+/// Maps an error to the code reported in its place
+type ErrorRedaction = dyn Fn(&anyhow::Error) -> AbciCode;
+
 pub struct WasmKeeper<ExecC, QueryC> {
     /// code is in-memory lookup that stands in for wasm code
     /// this can only be edited on the WasmRouter, and just read in caches
     codes: HashMap<usize, Box<dyn Contract<ExecC, QueryC>>>,
+    code_data: HashMap<usize, CodeData>,
     /// Just markers to make type elision fork when using it as `Wasm` trait
     _p: std::marker::PhantomData<QueryC>,
     generator: Box<dyn AddressGenerator>,
+    /// Transaction of the contract execution in progress, so queries sent while processing it
+    /// see the same transaction info in their `Env` as the calling contract
+    transaction: RefCell<Option<TransactionInfo>>,
+    /// Maps errors of failed submessages to the code passed to `reply` instead of the full
+    /// error chain, like wasmd does. Disabled if `None`.
+    error_redaction: Option<Box<ErrorRedaction>>,
+    /// Contract calls in progress, from the outermost one down to the current one
+    call_stack: RefCell<Vec<ContractCall>>,
+    /// Calls nested deeper than that fail instead of overflowing the stack
+    max_call_depth: usize,
+    reentrancy_policy: ReentrancyPolicy,
+    /// Who may store code with `upload_code`
+    upload_access: AccessConfig,
+    /// Rejects contracts with labels already used by other contracts
+    unique_labels: bool,
+    /// Number of smart queries in progress, nested in each other
+    query_depth: Cell<usize>,
+    max_query_depth: usize,
+    /// Gas used by the outermost smart query in progress, including all nested queries. Smart
+    /// queries using more gas than its limit fail.
+    query_gas: GasMeter,
 }
 
 pub trait AddressGenerator {
     fn next_address(&self, storage: &mut dyn Storage) -> Addr;
+
+    /// Address of a contract instantiated with `WasmMsg::Instantiate2`, which only depends on
+    /// the code checksum, the creator and the salt, so it can be known before instantiation
+    fn predictable_address(&self, checksum: &[u8], creator: &Addr, salt: &[u8]) -> Addr {
+        let mut hasher = Sha256::new();
+        for part in [checksum, creator.as_bytes(), salt] {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        let hash = HexBinary::from(&hasher.finalize()[..16]);
+        Addr::unchecked(format!("contract{}", hash.to_hex()))
+    }
 }
 
 #[derive(Debug)]
@@ -130,8 +322,19 @@ impl<ExecC, QueryC> Default for WasmKeeper<ExecC, QueryC> {
     fn default() -> Self {
         Self {
             codes: HashMap::default(),
+            code_data: HashMap::default(),
             _p: std::marker::PhantomData,
             generator: Box::new(SimpleAddressGenerator()),
+            transaction: RefCell::new(None),
+            error_redaction: None,
+            call_stack: RefCell::new(vec![]),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            reentrancy_policy: ReentrancyPolicy::default(),
+            upload_access: AccessConfig::default(),
+            unique_labels: false,
+            query_depth: Cell::new(0),
+            max_query_depth: DEFAULT_MAX_QUERY_DEPTH,
+            query_gas: GasMeter::default(),
         }
     }
 }
@@ -161,10 +364,27 @@ where
             WasmQuery::ContractInfo { contract_addr } => {
                 let addr = api.addr_validate(&contract_addr)?;
                 let contract = self.load_contract(storage, &addr)?;
+                let handler = self
+                    .codes
+                    .get(&contract.code_id)
+                    .ok_or(Error::UnregisteredCodeId(contract.code_id))?;
                 let mut res = ContractInfoResponse::default();
                 res.code_id = contract.code_id as u64;
                 res.creator = contract.creator.to_string();
                 res.admin = contract.admin.map(|x| x.into());
+                res.pinned = self.is_pinned(storage, contract.code_id);
+                if handler.has_ibc_entry_points() {
+                    res.ibc_port = Some(format!("wasm.{}", addr));
+                }
+                to_binary(&res).map_err(Into::into)
+            }
+            #[cfg(feature = "cosmwasm_1_2")]
+            WasmQuery::CodeInfo { code_id } => {
+                let code_data = self.code_data(code_id as usize)?;
+                let mut res = cosmwasm_std::CodeInfoResponse::default();
+                res.code_id = code_id;
+                res.creator = code_data.creator.to_string();
+                res.checksum = code_data.checksum;
                 to_binary(&res).map_err(Into::into)
             }
             query => bail!(Error::UnsupportedWasmQuery(query)),
@@ -181,10 +401,17 @@ where
         msg: WasmMsg,
     ) -> AnyResult<AppResponse> {
         self.execute_wasm(api, storage, router, block, sender.clone(), msg.clone())
-            .context(format!(
-                "error executing WasmMsg:\nsender: {}\n{:?}",
-                sender, msg
-            ))
+            .map_err(|err| {
+                // failed contract calls carry their `CallContext` already
+                if err.downcast_ref::<CallContext>().is_some() {
+                    err
+                } else {
+                    err.context(format!(
+                        "error executing WasmMsg:\nsender: {}\n{:?}",
+                        sender, msg
+                    ))
+                }
+            })
     }
 
     fn sudo(
@@ -197,26 +424,393 @@ where
         msg: Binary,
     ) -> AnyResult<AppResponse> {
         let custom_event = Event::new("sudo").add_attribute(CONTRACT_ATTR, &contract);
+        let call = ContractCall {
+            contract: contract.clone(),
+            entry_point: EntryPoint::Sudo,
+            sender: None,
+        };
+
+        self.contract_call(router, call, || {
+            let res =
+                self.call_sudo(contract.clone(), api, storage, router, block, msg.to_vec())?;
+            let (res, msgs) = self.build_app_response(&contract, custom_event, res);
+            self.process_response(api, router, storage, block, contract, res, msgs)
+        })
+    }
+
+    fn admin_sudo(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        _router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        _block: &BlockInfo,
+        msg: WasmAdminSudo,
+    ) -> AnyResult<AppResponse> {
+        let mut events = vec![];
+        match msg {
+            WasmAdminSudo::PinCodes { code_ids } => {
+                for code_id in code_ids {
+                    self.pin_code(storage, code_id as usize)?;
+                    events
+                        .push(Event::new("pin_code").add_attribute("code_id", code_id.to_string()));
+                }
+            }
+            WasmAdminSudo::UnpinCodes { code_ids } => {
+                for code_id in code_ids {
+                    self.unpin_code(storage, code_id as usize)?;
+                    events.push(
+                        Event::new("unpin_code").add_attribute("code_id", code_id.to_string()),
+                    );
+                }
+            }
+            WasmAdminSudo::UpdateContractLabel {
+                contract_addr,
+                new_label,
+            } => {
+                let contract_addr = api.addr_validate(&contract_addr)?;
+                self.update_contract_label(storage, &contract_addr, new_label.clone())?;
+                events.push(
+                    Event::new("update_contract_label")
+                        .add_attribute(CONTRACT_ATTR, contract_addr)
+                        .add_attribute("new_label", new_label),
+                );
+            }
+            WasmAdminSudo::SetContractCode {
+                contract_addr,
+                code_id,
+            } => {
+                let contract_addr = api.addr_validate(&contract_addr)?;
+                self.set_contract_code(storage, &contract_addr, code_id as usize)?;
+                events.push(
+                    Event::new("set_contract_code")
+                        .add_attribute(CONTRACT_ATTR, contract_addr)
+                        .add_attribute("code_id", code_id.to_string()),
+                );
+            }
+        }
+        Ok(AppResponse { events, data: None })
+    }
 
-        let res = self.call_sudo(contract.clone(), api, storage, router, block, msg.to_vec())?;
-        let (res, msgs) = self.build_app_response(&contract, custom_event, res);
-        self.process_response(api, router, storage, block, contract, res, msgs)
+    fn query_stargate(
+        &self,
+        _api: &dyn Api,
+        storage: &dyn Storage,
+        _block: &BlockInfo,
+        path: String,
+        data: Binary,
+    ) -> AnyResult<Binary> {
+        match path.as_str() {
+            "/cosmwasm.wasm.v1.Query/ContractHistory" => {
+                let request = QueryContractHistoryRequest::decode(data.as_slice())?;
+                let addr = Addr::unchecked(request.address);
+                let entries = self
+                    .contract_history(storage, &addr)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, entry)| (index as u64, ContractCodeHistoryEntryJson::from(entry)))
+                    .collect();
+                let (entries, pagination) = paginate(entries, request.pagination)?;
+                to_binary(&QueryContractHistoryResponseJson {
+                    entries,
+                    pagination,
+                })
+                .map_err(Into::into)
+            }
+            "/cosmwasm.wasm.v1.Query/ContractsByCode" => {
+                let request = QueryContractsByCodeRequest::decode(data.as_slice())?;
+                let contracts =
+                    self.contracts_by_code_positions(storage, request.code_id as usize)?;
+                let (contracts, pagination) = paginate(contracts, request.pagination)?;
+                to_binary(&QueryContractsByCodeResponseJson {
+                    contracts: contracts.into_iter().map(String::from).collect(),
+                    pagination,
+                })
+                .map_err(Into::into)
+            }
+            "/cosmwasm.wasm.v1.Query/ContractsByCreator" => {
+                let request = QueryContractsByCreatorRequest::decode(data.as_slice())?;
+                let creator = Addr::unchecked(request.creator_address);
+                let contracts = self.contracts_by_creator_positions(storage, &creator)?;
+                let (contracts, pagination) = paginate(contracts, request.pagination)?;
+                to_binary(&QueryContractsByCreatorResponseJson {
+                    contract_addresses: contracts.into_iter().map(String::from).collect(),
+                    pagination,
+                })
+                .map_err(Into::into)
+            }
+            _ => bail!(Error::UnsupportedStargateQuery(path)),
+        }
     }
 }
 
 impl<ExecC, QueryC> WasmKeeper<ExecC, QueryC> {
     pub fn store_code(&mut self, code: Box<dyn Contract<ExecC, QueryC>>) -> usize {
+        self.store_code_with_creator(Addr::unchecked(DEFAULT_CODE_CREATOR), code)
+    }
+
+    /// Stores the code like `store_code`, recording `creator` as the account which stored it
+    pub fn store_code_with_creator(
+        &mut self,
+        creator: Addr,
+        code: Box<dyn Contract<ExecC, QueryC>>,
+    ) -> usize {
+        self.insert_code(creator, None, code)
+    }
+
+    /// Stores the code like `store_code`, under a name it can be found by with `code_id_by_name`.
+    /// Fails if another code was already stored with the same name.
+    pub fn store_code_with_name(
+        &mut self,
+        name: impl Into<String>,
+        code: Box<dyn Contract<ExecC, QueryC>>,
+    ) -> AnyResult<usize> {
+        let name = name.into();
+        if self.code_id_by_name(&name).is_ok() {
+            bail!(WasmError::DuplicateCodeName(name));
+        }
+        Ok(self.insert_code(Addr::unchecked(DEFAULT_CODE_CREATOR), Some(name), code))
+    }
+
+    /// Returns the id of the code stored with the given name
+    pub fn code_id_by_name(&self, name: &str) -> AnyResult<usize> {
+        self.code_data
+            .iter()
+            .find(|(_, data)| data.name.as_deref() == Some(name))
+            .map(|(code_id, _)| *code_id)
+            .ok_or_else(|| WasmError::UnknownCodeName(name.to_owned()).into())
+    }
+
+    fn insert_code(
+        &mut self,
+        creator: Addr,
+        name: Option<String>,
+        code: Box<dyn Contract<ExecC, QueryC>>,
+    ) -> usize {
         let idx = self.codes.len() + 1;
         self.codes.insert(idx, code);
+        let checksum = Sha256::digest(format!("contract code {}", idx)).to_vec();
+        self.code_data.insert(
+            idx,
+            CodeData {
+                creator,
+                checksum: checksum.into(),
+                instantiate_permission: AccessConfig::default(),
+                name,
+            },
+        );
         idx
     }
 
-    pub fn load_contract(&self, storage: &dyn Storage, address: &Addr) -> AnyResult<ContractData> {
-        CONTRACTS
-            .load(&prefixed_read(storage, NAMESPACE_WASM), address)
+    /// Stores the code on behalf of `sender`, like `MsgStoreCode` does in wasmd. Fails if the
+    /// upload access rules don't allow the sender to store code. Returns the new code id along
+    /// with the `store_code` event and data wasmd responds with.
+    pub fn upload_code(
+        &mut self,
+        sender: Addr,
+        code: Box<dyn Contract<ExecC, QueryC>>,
+    ) -> AnyResult<(usize, AppResponse)> {
+        if !self.upload_access.allows(&sender) {
+            bail!(WasmError::UploadUnauthorized(sender));
+        }
+        let code_id = self.store_code_with_creator(sender, code);
+        let checksum = self.code_data(code_id)?.checksum;
+
+        let event = Event::new("store_code")
+            .add_attribute("code_checksum", checksum.to_hex())
+            .add_attribute("code_id", code_id.to_string());
+        let res = AppResponse {
+            events: vec![event],
+            data: Some(store_code_response(code_id, &checksum)),
+        };
+        Ok((code_id, res))
+    }
+
+    pub fn code_data(&self, code_id: usize) -> AnyResult<CodeData> {
+        self.code_data
+            .get(&code_id)
+            .cloned()
+            .ok_or_else(|| Error::UnregisteredCodeId(code_id).into())
+    }
+
+    /// Sets who may instantiate the code, everybody can by default
+    pub fn set_instantiate_permission(
+        &mut self,
+        code_id: usize,
+        permission: AccessConfig,
+    ) -> AnyResult<()> {
+        let code_data = self
+            .code_data
+            .get_mut(&code_id)
+            .ok_or(Error::UnregisteredCodeId(code_id))?;
+        code_data.instantiate_permission = permission;
+        Ok(())
+    }
+
+    /// Replaces the implementation behind the code id, keeping its metadata like the checksum.
+    /// All contracts using the code run the new implementation from now on, without being
+    /// migrated, which allows injecting faults or simulating a dependency changing mid-scenario.
+    pub fn replace_code(
+        &mut self,
+        code_id: usize,
+        code: Box<dyn Contract<ExecC, QueryC>>,
+    ) -> AnyResult<()> {
+        let current = self
+            .codes
+            .get_mut(&code_id)
+            .ok_or(Error::UnregisteredCodeId(code_id))?;
+        *current = code;
+        Ok(())
+    }
+
+    /// Pins the code to the cache, making its contracts report being pinned
+    pub fn pin_code(&self, storage: &mut dyn Storage, code_id: usize) -> AnyResult<()> {
+        if !self.codes.contains_key(&code_id) {
+            bail!(Error::UnregisteredCodeId(code_id));
+        }
+        PINNED_CODES
+            .save(
+                &mut prefixed(storage, NAMESPACE_WASM),
+                code_id as u64,
+                &Empty {},
+            )
+            .map_err(Into::into)
+    }
+
+    /// Removes the code from the cache
+    pub fn unpin_code(&self, storage: &mut dyn Storage, code_id: usize) -> AnyResult<()> {
+        if !self.codes.contains_key(&code_id) {
+            bail!(Error::UnregisteredCodeId(code_id));
+        }
+        PINNED_CODES.remove(&mut prefixed(storage, NAMESPACE_WASM), code_id as u64);
+        Ok(())
+    }
+
+    /// Returns the code history of the contract, starting with its instantiation. Contracts
+    /// registered without instantiating them start with a genesis entry.
+    pub fn contract_history(
+        &self,
+        storage: &dyn Storage,
+        address: &Addr,
+    ) -> AnyResult<Vec<ContractHistoryEntry>> {
+        let history =
+            CONTRACT_HISTORY.may_load(&prefixed_read(storage, NAMESPACE_WASM), address)?;
+        match history {
+            Some(history) => Ok(history),
+            None => {
+                let contract = self.load_contract(storage, address)?;
+                Ok(vec![ContractHistoryEntry {
+                    operation: ContractHistoryOperation::Genesis,
+                    code_id: contract.code_id,
+                    block_height: contract.created,
+                    tx_index: 0,
+                    msg: Binary::default(),
+                }])
+            }
+        }
+    }
+
+    /// Returns the addresses of all contracts currently using the code, in the order they
+    /// started using it, like wasmd does
+    pub fn contracts_by_code(&self, storage: &dyn Storage, code_id: usize) -> AnyResult<Vec<Addr>> {
+        let contracts = self.contracts_by_code_positions(storage, code_id)?;
+        Ok(contracts.into_iter().map(|(_, addr)| addr).collect())
+    }
+
+    /// Returns the addresses of all contracts instantiated by the creator, in the order of
+    /// instantiation, like wasmd does
+    pub fn contracts_by_creator(
+        &self,
+        storage: &dyn Storage,
+        creator: &Addr,
+    ) -> AnyResult<Vec<Addr>> {
+        let contracts = self.contracts_by_creator_positions(storage, creator)?;
+        Ok(contracts.into_iter().map(|(_, addr)| addr).collect())
+    }
+
+    fn contracts_by_code_positions(
+        &self,
+        storage: &dyn Storage,
+        code_id: usize,
+    ) -> AnyResult<Vec<(u64, Addr)>> {
+        CONTRACTS_BY_CODE
+            .prefix(code_id as u64)
+            .range(
+                &prefixed_read(storage, NAMESPACE_WASM),
+                None,
+                None,
+                Order::Ascending,
+            )
+            .collect::<StdResult<_>>()
             .map_err(Into::into)
     }
 
+    fn contracts_by_creator_positions(
+        &self,
+        storage: &dyn Storage,
+        creator: &Addr,
+    ) -> AnyResult<Vec<(u64, Addr)>> {
+        CONTRACTS_BY_CREATOR
+            .prefix(creator)
+            .range(
+                &prefixed_read(storage, NAMESPACE_WASM),
+                None,
+                None,
+                Order::Ascending,
+            )
+            .collect::<StdResult<_>>()
+            .map_err(Into::into)
+    }
+
+    /// Returns the address of the only contract with the given label
+    pub fn contract_by_label(&self, storage: &dyn Storage, label: &str) -> AnyResult<Addr> {
+        let storage = prefixed_read(storage, NAMESPACE_WASM);
+        let mut contracts = CONTRACTS_BY_LABEL
+            .prefix(label)
+            .keys(&storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        match contracts.len() {
+            0 => bail!(WasmError::UnknownLabel(label.to_owned())),
+            1 => Ok(contracts.remove(0)),
+            _ => bail!(WasmError::AmbiguousLabel {
+                label: label.to_owned(),
+                contracts,
+            }),
+        }
+    }
+
+    fn append_history(
+        &self,
+        storage: &mut dyn Storage,
+        address: &Addr,
+        entry: ContractHistoryEntry,
+    ) -> AnyResult<()> {
+        let mut history = match entry.operation {
+            ContractHistoryOperation::Init => vec![],
+            _ => self.contract_history(storage, address)?,
+        };
+        history.push(entry);
+        CONTRACT_HISTORY
+            .save(&mut prefixed(storage, NAMESPACE_WASM), address, &history)
+            .map_err(Into::into)
+    }
+
+    /// Returns true if the code is pinned to the cache
+    pub fn is_pinned(&self, storage: &dyn Storage, code_id: usize) -> bool {
+        PINNED_CODES.has(&prefixed_read(storage, NAMESPACE_WASM), code_id as u64)
+    }
+
+    pub fn load_contract(&self, storage: &dyn Storage, address: &Addr) -> AnyResult<ContractData> {
+        let mut contract = CONTRACTS.load(&prefixed_read(storage, NAMESPACE_WASM), address)?;
+        contract.code_name = self.code_name(contract.code_id);
+        Ok(contract)
+    }
+
+    fn code_name(&self, code_id: usize) -> Option<String> {
+        self.code_data
+            .get(&code_id)
+            .and_then(|code| code.name.clone())
+    }
+
     pub fn dump_wasm_raw(&self, storage: &dyn Storage, address: &Addr) -> Vec<Record> {
         let storage = self.contract_storage_readonly(storage, address);
         storage.range(None, None, Order::Ascending).collect()
@@ -228,7 +822,7 @@ impl<ExecC, QueryC> WasmKeeper<ExecC, QueryC> {
         name
     }
 
-    fn contract_storage<'a>(
+    pub(crate) fn contract_storage<'a>(
         &self,
         storage: &'a mut dyn Storage,
         address: &Addr,
@@ -241,7 +835,7 @@ impl<ExecC, QueryC> WasmKeeper<ExecC, QueryC> {
     }
 
     // fails RUNTIME if you try to write. please don't
-    fn contract_storage_readonly<'a>(
+    pub(crate) fn contract_storage_readonly<'a>(
         &self,
         storage: &'a dyn Storage,
         address: &Addr,
@@ -305,11 +899,86 @@ where
         let default = Self::default();
         Self {
             codes: default.codes,
+            code_data: default.code_data,
             _p: default._p,
             generator: Box::new(generator),
+            transaction: default.transaction,
+            error_redaction: default.error_redaction,
+            call_stack: default.call_stack,
+            max_call_depth: default.max_call_depth,
+            reentrancy_policy: default.reentrancy_policy,
+            upload_access: default.upload_access,
+            unique_labels: default.unique_labels,
+            query_depth: default.query_depth,
+            max_query_depth: default.max_query_depth,
+            query_gas: default.query_gas,
         }
     }
 
+    /// When enabled, failed submessages pass only a generic error like
+    /// "codespace: wasm, code: 5" to `reply`, just like wasmd does. Otherwise the reply gets the
+    /// full error chain, which is easier to debug but can't be relied upon on a real chain.
+    pub fn with_error_redaction(mut self, redact: bool) -> Self {
+        self.error_redaction = if redact {
+            Some(Box::new(AbciCode::of))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Sets how deep contract calls may nest, including calls to `reply`, before failing with
+    /// `WasmError::CallDepthExceeded`
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// Sets how deep smart queries may nest, including the outermost one. Deeper queries fail
+    /// with a `SystemError`, like a contract querying itself in a cycle.
+    pub fn with_max_query_depth(mut self, max_query_depth: usize) -> Self {
+        self.max_query_depth = max_query_depth;
+        self
+    }
+
+    /// Limits the gas a smart query may use, including all queries nested in it. Every query
+    /// costs wasmd's instance cost, plus the Cosmos SDK costs of the storage reads it does.
+    /// Queries running out of gas fail with `WasmError::QueryGasExceeded`.
+    pub fn with_query_gas_limit(mut self, limit: u64) -> Self {
+        self.query_gas = GasMeter::new(Some(limit));
+        self
+    }
+
+    /// Sets who may store code with `upload_code`, everybody can by default
+    pub fn with_upload_access(mut self, access: AccessConfig) -> Self {
+        self.upload_access = access;
+        self
+    }
+
+    /// When enabled, registering a contract or updating its label fails with
+    /// `WasmError::DuplicateLabel` if another contract already uses the label
+    pub fn with_unique_labels(mut self, unique_labels: bool) -> Self {
+        self.unique_labels = unique_labels;
+        self
+    }
+
+    /// Sets how contract re-entrancy is handled, it is allowed by default
+    pub fn with_reentrancy_policy(mut self, policy: ReentrancyPolicy) -> Self {
+        self.reentrancy_policy = policy;
+        self
+    }
+
+    /// Redacts errors passed to `reply` with a custom mapping, e.g. to give errors of custom
+    /// modules the codes of their chain counterparts. The mapper can fall back to
+    /// `AbciCode::of` for all the other errors.
+    pub fn with_custom_error_redaction(
+        mut self,
+        redaction: impl Fn(&anyhow::Error) -> AbciCode + 'static,
+    ) -> Self {
+        self.error_redaction = Some(Box::new(redaction));
+        self
+    }
+
     pub fn query_smart(
         &self,
         address: Addr,
@@ -319,14 +988,48 @@ where
         block: &BlockInfo,
         msg: Vec<u8>,
     ) -> AnyResult<Binary> {
-        self.with_storage_readonly(
+        let depth = self.query_depth.get();
+        if depth >= self.max_query_depth {
+            // no dedicated `SystemError` for it in this version of cosmwasm-std
+            bail!(SystemError::InvalidRequest {
+                error: format!(
+                    "Exceeded recursion limit of {} nested queries",
+                    self.max_query_depth
+                ),
+                request: msg.into(),
+            });
+        }
+        if depth == 0 {
+            self.query_gas.reset();
+        }
+        self.query_gas.consume(INSTANCE_COST);
+        self.check_query_gas()?;
+
+        self.query_depth.set(depth + 1);
+        let res = self.with_storage_readonly(
             api,
             storage,
             querier,
             block,
             address,
             |handler, deps, env| handler.query(deps, env, msg),
-        )
+        );
+        self.query_depth.set(depth);
+        // running out of gas takes precedence over the result, which may come from reads the
+        // storage refused to serve
+        self.check_query_gas()?;
+        res
+    }
+
+    /// Fails if the current query went over its gas limit
+    fn check_query_gas(&self) -> AnyResult<()> {
+        match self.query_gas.limit() {
+            Some(limit) if self.query_gas.exceeded() => bail!(WasmError::QueryGasExceeded {
+                limit,
+                used: self.query_gas.used(),
+            }),
+            _ => Ok(()),
+        }
     }
 
     pub fn query_raw(&self, address: Addr, storage: &dyn Storage, key: &[u8]) -> Binary {
@@ -375,8 +1078,14 @@ where
 
         // check admin status
         let mut data = self.load_contract(storage, &contract_addr)?;
-        if data.admin != Some(sender) {
-            bail!("Only admin can update the contract admin: {:?}", data.admin);
+        if data.admin.as_ref() != Some(&sender) {
+            let admin = data.admin.clone();
+            return Err(
+                anyhow!(WasmError::Unauthorized { sender, admin }).context(format!(
+                    "Only admin can update the contract admin: {:?}",
+                    data.admin
+                )),
+            );
         }
         // update admin field
         data.admin = admin;
@@ -406,37 +1115,15 @@ where
                 funds,
             } => {
                 let contract_addr = api.addr_validate(&contract_addr)?;
-                // first move the cash
-                self.send(
-                    api,
-                    storage,
-                    router,
-                    block,
-                    sender.clone(),
-                    contract_addr.clone().into(),
-                    &funds,
-                )?;
-
-                // then call the contract
+                let call = ContractCall {
+                    contract: contract_addr.clone(),
+                    entry_point: EntryPoint::Execute,
+                    sender: Some(sender.clone()),
+                };
                 let info = MessageInfo { sender, funds };
-                let res = self.call_execute(
-                    api,
-                    storage,
-                    contract_addr.clone(),
-                    router,
-                    block,
-                    info,
-                    msg.to_vec(),
-                )?;
-
-                let custom_event =
-                    Event::new("execute").add_attribute(CONTRACT_ATTR, &contract_addr);
-
-                let (res, msgs) = self.build_app_response(&contract_addr, custom_event, res);
-                let mut res =
-                    self.process_response(api, router, storage, block, contract_addr, res, msgs)?;
-                res.data = execute_response(res.data);
-                Ok(res)
+                self.contract_call(router, call, || {
+                    self.execute_contract(api, storage, router, block, contract_addr, info, msg)
+                })
             }
             WasmMsg::Instantiate {
                 admin,
@@ -446,7 +1133,7 @@ where
                 label,
             } => {
                 if label.is_empty() {
-                    bail!("Label is required on all contracts");
+                    bail!(WasmError::EmptyLabel);
                 }
 
                 let contract_addr = self.register_contract(
@@ -458,45 +1145,72 @@ where
                     block.height,
                 )?;
 
-                // move the cash
-                self.send(
-                    api,
+                self.append_history(
                     storage,
-                    router,
-                    block,
-                    sender.clone(),
-                    contract_addr.clone().into(),
-                    &funds,
+                    &contract_addr,
+                    ContractHistoryEntry {
+                        operation: ContractHistoryOperation::Init,
+                        code_id: code_id as usize,
+                        block_height: block.height,
+                        tx_index: router.transaction_info().map_or(0, |tx| tx.index),
+                        msg: msg.clone(),
+                    },
                 )?;
 
-                // then call the contract
+                let call = ContractCall {
+                    contract: contract_addr.clone(),
+                    entry_point: EntryPoint::Instantiate,
+                    sender: Some(sender.clone()),
+                };
                 let info = MessageInfo { sender, funds };
-                let res = self.call_instantiate(
-                    contract_addr.clone(),
-                    api,
+                self.contract_call(router, call, || {
+                    self.instantiate_contract(api, storage, router, block, contract_addr, info, msg)
+                })
+            }
+            #[cfg(feature = "cosmwasm_1_2")]
+            WasmMsg::Instantiate2 {
+                admin,
+                code_id,
+                label,
+                msg,
+                funds,
+                salt,
+            } => {
+                if label.is_empty() {
+                    bail!(WasmError::EmptyLabel);
+                }
+
+                let contract_addr = self.register_contract_with_salt(
                     storage,
-                    router,
-                    block,
-                    info,
-                    msg.to_vec(),
+                    code_id as usize,
+                    sender.clone(),
+                    admin.map(Addr::unchecked),
+                    label,
+                    block.height,
+                    &salt,
                 )?;
 
-                let custom_event = Event::new("instantiate")
-                    .add_attribute(CONTRACT_ATTR, &contract_addr)
-                    .add_attribute("code_id", code_id.to_string());
-
-                let (res, msgs) = self.build_app_response(&contract_addr, custom_event, res);
-                let mut res = self.process_response(
-                    api,
-                    router,
+                self.append_history(
                     storage,
-                    block,
-                    contract_addr.clone(),
-                    res,
-                    msgs,
+                    &contract_addr,
+                    ContractHistoryEntry {
+                        operation: ContractHistoryOperation::Init,
+                        code_id: code_id as usize,
+                        block_height: block.height,
+                        tx_index: router.transaction_info().map_or(0, |tx| tx.index),
+                        msg: msg.clone(),
+                    },
                 )?;
-                res.data = Some(instantiate_response(res.data, &contract_addr));
-                Ok(res)
+
+                let call = ContractCall {
+                    contract: contract_addr.clone(),
+                    entry_point: EntryPoint::Instantiate,
+                    sender: Some(sender.clone()),
+                };
+                let info = MessageInfo { sender, funds };
+                self.contract_call(router, call, || {
+                    self.instantiate_contract(api, storage, router, block, contract_addr, info, msg)
+                })
             }
             WasmMsg::Migrate {
                 contract_addr,
@@ -508,33 +1222,45 @@ where
                 // check admin status and update the stored code_id
                 let new_code_id = new_code_id as usize;
                 if !self.codes.contains_key(&new_code_id) {
-                    bail!("Cannot migrate contract to unregistered code id");
+                    return Err(anyhow!(Error::UnregisteredCodeId(new_code_id))
+                        .context("Cannot migrate contract to unregistered code id"));
                 }
                 let mut data = self.load_contract(storage, &contract_addr)?;
-                if data.admin != Some(sender) {
-                    bail!("Only admin can migrate contract: {:?}", data.admin);
+                if data.admin.as_ref() != Some(&sender) {
+                    let admin = data.admin.clone();
+                    return Err(anyhow!(WasmError::Unauthorized { sender, admin })
+                        .context(format!("Only admin can migrate contract: {:?}", data.admin)));
                 }
-                data.code_id = new_code_id;
-                self.save_contract(storage, &contract_addr, &data)?;
-
-                // then call migrate
-                let res = self.call_migrate(
-                    contract_addr.clone(),
-                    api,
+                self.append_history(
                     storage,
-                    router,
-                    block,
-                    msg.to_vec(),
+                    &contract_addr,
+                    ContractHistoryEntry {
+                        operation: ContractHistoryOperation::Migrate,
+                        code_id: new_code_id,
+                        block_height: block.height,
+                        tx_index: router.transaction_info().map_or(0, |tx| tx.index),
+                        msg: msg.clone(),
+                    },
                 )?;
+                data.code_id = new_code_id;
+                self.save_contract(storage, &contract_addr, &data)?;
 
-                let custom_event = Event::new("migrate")
-                    .add_attribute(CONTRACT_ATTR, &contract_addr)
-                    .add_attribute("code_id", new_code_id.to_string());
-                let (res, msgs) = self.build_app_response(&contract_addr, custom_event, res);
-                let mut res =
-                    self.process_response(api, router, storage, block, contract_addr, res, msgs)?;
-                res.data = execute_response(res.data);
-                Ok(res)
+                let call = ContractCall {
+                    contract: contract_addr.clone(),
+                    entry_point: EntryPoint::Migrate,
+                    sender: Some(sender),
+                };
+                self.contract_call(router, call, || {
+                    self.migrate_contract(
+                        api,
+                        storage,
+                        router,
+                        block,
+                        contract_addr,
+                        new_code_id,
+                        msg,
+                    )
+                })
             }
             WasmMsg::UpdateAdmin {
                 contract_addr,
@@ -547,6 +1273,181 @@ where
         }
     }
 
+    /// Runs a call into a contract along with processing of its response, keeping track of the
+    /// call stack, and attaches the context of the call to its error
+    fn contract_call<T>(
+        &self,
+        router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        call: ContractCall,
+        action: impl FnOnce() -> AnyResult<T>,
+    ) -> AnyResult<T> {
+        if self.call_stack.borrow().len() >= self.max_call_depth {
+            let err = WasmError::CallDepthExceeded {
+                max: self.max_call_depth,
+            };
+            return Err(CallContext::attach(call, err.into()));
+        }
+        if let Some(err) = self.check_reentrancy(&call) {
+            match self.reentrancy_policy {
+                ReentrancyPolicy::Allow => {}
+                ReentrancyPolicy::Warn => {
+                    if let Some(tracer) = router.tracer() {
+                        tracer.warn(err.to_string());
+                    }
+                }
+                ReentrancyPolicy::Fail => return Err(CallContext::attach(call, err.into())),
+            }
+        }
+
+        self.call_stack.borrow_mut().push(call.clone());
+        let res = action();
+        self.call_stack.borrow_mut().pop();
+        res.map_err(|err| CallContext::attach(call, err))
+    }
+
+    /// Returns the error describing the re-entrancy if `call` re-enters a contract
+    fn check_reentrancy(&self, call: &ContractCall) -> Option<WasmError> {
+        if self.reentrancy_policy == ReentrancyPolicy::Allow {
+            return None;
+        }
+        let stack = self.call_stack.borrow();
+        let mut callers = stack.iter().rev();
+        // the reply is sent back to the contract on top of the stack, which sent the submessage
+        if call.entry_point == EntryPoint::Reply {
+            callers.next();
+        }
+        if !callers.any(|caller| caller.contract == call.contract) {
+            return None;
+        }
+        Some(WasmError::Reentrancy {
+            contract: call.contract.clone(),
+            entry_point: call.entry_point,
+            path: stack.iter().map(|caller| caller.contract.clone()).collect(),
+        })
+    }
+
+    fn execute_contract(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        block: &BlockInfo,
+        contract_addr: Addr,
+        info: MessageInfo,
+        msg: Binary,
+    ) -> AnyResult<AppResponse> {
+        // first move the cash
+        self.send(
+            api,
+            storage,
+            router,
+            block,
+            info.sender.clone(),
+            contract_addr.clone().into(),
+            &info.funds,
+        )?;
+
+        // then call the contract
+        let res = self.call_execute(
+            api,
+            storage,
+            contract_addr.clone(),
+            router,
+            block,
+            info,
+            msg.to_vec(),
+        )?;
+
+        let custom_event = Event::new("execute").add_attribute(CONTRACT_ATTR, &contract_addr);
+
+        let (res, msgs) = self.build_app_response(&contract_addr, custom_event, res);
+        let mut res =
+            self.process_response(api, router, storage, block, contract_addr, res, msgs)?;
+        res.data = execute_response(res.data);
+        Ok(res)
+    }
+
+    fn instantiate_contract(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        block: &BlockInfo,
+        contract_addr: Addr,
+        info: MessageInfo,
+        msg: Binary,
+    ) -> AnyResult<AppResponse> {
+        // move the cash
+        self.send(
+            api,
+            storage,
+            router,
+            block,
+            info.sender.clone(),
+            contract_addr.clone().into(),
+            &info.funds,
+        )?;
+
+        // then call the contract
+        let res = self.call_instantiate(
+            contract_addr.clone(),
+            api,
+            storage,
+            router,
+            block,
+            info,
+            msg.to_vec(),
+        )?;
+
+        let code_id = self.load_contract(storage, &contract_addr)?.code_id;
+        let custom_event = Event::new("instantiate")
+            .add_attribute(CONTRACT_ATTR, &contract_addr)
+            .add_attribute("code_id", code_id.to_string());
+
+        let (res, msgs) = self.build_app_response(&contract_addr, custom_event, res);
+        let mut res = self.process_response(
+            api,
+            router,
+            storage,
+            block,
+            contract_addr.clone(),
+            res,
+            msgs,
+        )?;
+        res.data = Some(instantiate_response(res.data, &contract_addr));
+        Ok(res)
+    }
+
+    fn migrate_contract(
+        &self,
+        api: &dyn Api,
+        storage: &mut dyn Storage,
+        router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        block: &BlockInfo,
+        contract_addr: Addr,
+        new_code_id: usize,
+        msg: Binary,
+    ) -> AnyResult<AppResponse> {
+        // then call migrate
+        let res = self.call_migrate(
+            contract_addr.clone(),
+            api,
+            storage,
+            router,
+            block,
+            msg.to_vec(),
+        )?;
+
+        let custom_event = Event::new("migrate")
+            .add_attribute(CONTRACT_ATTR, &contract_addr)
+            .add_attribute("code_id", new_code_id.to_string());
+        let (res, msgs) = self.build_app_response(&contract_addr, custom_event, res);
+        let mut res =
+            self.process_response(api, router, storage, block, contract_addr, res, msgs)?;
+        res.data = execute_response(res.data);
+        Ok(res)
+    }
+
     /// This will execute the given messages, making all changes to the local cache.
     /// This *will* write some data to the cache if the message fails half-way through.
     /// All sequential calls to RouterCache will be one atomic unit (all commit or all fail).
@@ -564,6 +1465,25 @@ where
         block: &BlockInfo,
         contract: Addr,
         msg: SubMsg<ExecC>,
+    ) -> AnyResult<AppResponse> {
+        // plain messages don't need the submessage wrapper, the dispatched message is enough
+        let trace = router
+            .tracer()
+            .filter(|_| msg.reply_on != ReplyOn::Never)
+            .map(|tracer| (tracer, TraceNode::submsg(&contract, &msg)));
+        traced_call(trace, storage, |storage| {
+            self.run_submsg(api, router, storage, block, contract, msg)
+        })
+    }
+
+    fn run_submsg(
+        &self,
+        api: &dyn Api,
+        router: &dyn CosmosRouter<ExecC = ExecC, QueryC = QueryC>,
+        storage: &mut dyn Storage,
+        block: &BlockInfo,
+        contract: Addr,
+        msg: SubMsg<ExecC>,
     ) -> AnyResult<AppResponse> {
         let SubMsg {
             msg, id, reply_on, ..
@@ -600,7 +1520,7 @@ where
             if matches!(reply_on, ReplyOn::Always | ReplyOn::Error) {
                 let reply = Reply {
                     id,
-                    result: SubMsgResult::Err(e.to_string()),
+                    result: SubMsgResult::Err(self.reply_error(&e)),
                 };
                 self._reply(api, router, storage, block, contract, reply)
             } else {
@@ -611,6 +1531,15 @@ where
         }
     }
 
+    /// Error message passed to `reply` for a failed submessage
+    fn reply_error(&self, err: &anyhow::Error) -> String {
+        match &self.error_redaction {
+            // wasmd only passes the ABCI codespace and code of the error
+            Some(redaction) => redaction(err).to_string(),
+            None => format!("{:#}", err),
+        }
+    }
+
     fn _reply(
         &self,
         api: &dyn Api,
@@ -629,9 +1558,21 @@ where
             .add_attribute(CONTRACT_ATTR, &contract)
             .add_attribute("mode", ok_attr);
 
-        let res = self.call_reply(contract.clone(), api, storage, router, block, reply)?;
-        let (res, msgs) = self.build_app_response(&contract, custom_event, res);
-        self.process_response(api, router, storage, block, contract, res, msgs)
+        let trace = router
+            .tracer()
+            .map(|tracer| (tracer, TraceNode::reply(&contract, &reply)));
+        let call = ContractCall {
+            contract: contract.clone(),
+            entry_point: EntryPoint::Reply,
+            sender: None,
+        };
+        traced_call(trace, storage, |storage| {
+            self.contract_call(router, call, || {
+                let res = self.call_reply(contract.clone(), api, storage, router, block, reply)?;
+                let (res, msgs) = self.build_app_response(&contract, custom_event, res);
+                self.process_response(api, router, storage, block, contract, res, msgs)
+            })
+        })
     }
 
     // this captures all the events and data from the contract call.
@@ -715,9 +1656,7 @@ where
         label: String,
         created: u64,
     ) -> AnyResult<Addr> {
-        if !self.codes.contains_key(&code_id) {
-            bail!("Cannot init contract with unregistered code id");
-        }
+        self.check_instantiate_permission(code_id, &creator)?;
 
         let addr = self.generator.next_address(storage);
 
@@ -727,11 +1666,64 @@ where
             admin: admin.into(),
             label,
             created,
+            code_name: self.code_name(code_id),
+        };
+        self.save_contract(storage, &addr, &info)?;
+        Ok(addr)
+    }
+
+    /// Registers a contract like `register_contract`, at the predictable address given by the
+    /// code, creator and salt, like `WasmMsg::Instantiate2` does
+    #[allow(clippy::too_many_arguments)]
+    pub fn register_contract_with_salt(
+        &self,
+        storage: &mut dyn Storage,
+        code_id: usize,
+        creator: Addr,
+        admin: impl Into<Option<Addr>>,
+        label: String,
+        created: u64,
+        salt: &[u8],
+    ) -> AnyResult<Addr> {
+        self.check_instantiate_permission(code_id, &creator)?;
+
+        let checksum = self.code_data(code_id)?.checksum;
+        let addr = self
+            .generator
+            .predictable_address(&checksum, &creator, salt);
+        if CONTRACTS.has(&prefixed_read(storage, NAMESPACE_WASM), &addr) {
+            bail!(WasmError::DuplicateContractAddress(addr));
+        }
+
+        let info = ContractData {
+            code_id,
+            creator,
+            admin: admin.into(),
+            label,
+            created,
+            code_name: self.code_name(code_id),
         };
         self.save_contract(storage, &addr, &info)?;
         Ok(addr)
     }
 
+    fn check_instantiate_permission(&self, code_id: usize, sender: &Addr) -> AnyResult<()> {
+        let code_data = match self.code_data.get(&code_id) {
+            Some(code_data) => code_data,
+            None => {
+                return Err(anyhow!(Error::UnregisteredCodeId(code_id))
+                    .context("Cannot init contract with unregistered code id"))
+            }
+        };
+        if !code_data.instantiate_permission.allows(sender) {
+            bail!(WasmError::InstantiateUnauthorized {
+                code_id,
+                sender: sender.clone(),
+            });
+        }
+        Ok(())
+    }
+
     pub fn call_execute(
         &self,
         api: &dyn Api,
@@ -829,13 +1821,18 @@ where
         )?)
     }
 
-    fn get_env<T: Into<Addr>>(&self, address: T, block: &BlockInfo) -> Env {
+    fn get_env<T: Into<Addr>>(
+        &self,
+        address: T,
+        block: &BlockInfo,
+        transaction: Option<TransactionInfo>,
+    ) -> Env {
         Env {
             block: block.clone(),
             contract: ContractInfo {
                 address: address.into(),
             },
-            transaction: Some(TransactionInfo { index: 0 }),
+            transaction,
         }
     }
 
@@ -856,12 +1853,18 @@ where
             .codes
             .get(&contract.code_id)
             .ok_or(Error::UnregisteredCodeId(contract.code_id))?;
-        let storage = self.contract_storage_readonly(storage, &address);
-        let env = self.get_env(address, block);
+        let storage = GasMeteredStorage::new(
+            self.contract_storage_readonly(storage, &address),
+            &self.query_gas,
+        );
+        // queries outside of any execution (like the ones sent directly from `App`) are not part
+        // of a transaction, just like on chain
+        let transaction = self.transaction.borrow().clone();
+        let env = self.get_env(address, block, transaction);
 
         let deps = Deps {
-            storage: storage.as_ref(),
-            api: api.deref(),
+            storage: &storage,
+            api,
             querier: QuerierWrapper::new(querier),
         };
         action(handler, deps, env)
@@ -885,23 +1888,62 @@ where
             .codes
             .get(&contract.code_id)
             .ok_or(Error::UnregisteredCodeId(contract.code_id))?;
+        if let (Some(tracer), Some(name)) = (router.tracer(), self.code_name(contract.code_id)) {
+            tracer.set_code_name(name);
+        }
 
         // We don't actually need a transaction here, as it is already embedded in a transactional.
         // execute_submsg or App.execute_multi.
         // However, we need to get write and read access to the same storage in two different objects,
         // and this is the only way I know how to do so.
-        transactional(storage, |write_cache, read_store| {
+        let transaction = router.transaction_info();
+        let outer_transaction = self.transaction.replace(transaction.clone());
+
+        let res = transactional(storage, |write_cache, read_store| {
             let mut contract_storage = self.contract_storage(write_cache, &address);
             let querier = RouterQuerier::new(router, api, read_store, block);
-            let env = self.get_env(address, block);
+            let env = self.get_env(address, block, transaction);
 
             let deps = DepsMut {
                 storage: contract_storage.as_mut(),
-                api: api.deref(),
+                api,
                 querier: QuerierWrapper::new(&querier),
             };
             action(handler, deps, env)
-        })
+        });
+
+        self.transaction.replace(outer_transaction);
+        res
+    }
+
+    /// Makes the contract use another code without migrating it
+    pub fn set_contract_code(
+        &self,
+        storage: &mut dyn Storage,
+        address: &Addr,
+        code_id: usize,
+    ) -> AnyResult<()> {
+        if !self.codes.contains_key(&code_id) {
+            bail!(Error::UnregisteredCodeId(code_id));
+        }
+        let mut data = self.load_contract(storage, address)?;
+        data.code_id = code_id;
+        self.save_contract(storage, address, &data)
+    }
+
+    /// Changes the label of the contract, keeping labels unique if required
+    pub fn update_contract_label(
+        &self,
+        storage: &mut dyn Storage,
+        address: &Addr,
+        label: String,
+    ) -> AnyResult<()> {
+        if label.is_empty() {
+            bail!(WasmError::EmptyLabel);
+        }
+        let mut data = self.load_contract(storage, address)?;
+        data.label = label;
+        self.save_contract(storage, address, &data)
     }
 
     pub fn save_contract(
@@ -910,12 +1952,128 @@ where
         address: &Addr,
         contract: &ContractData,
     ) -> AnyResult<()> {
+        let mut storage = prefixed(storage, NAMESPACE_WASM);
+        let old = CONTRACTS.may_load(&storage, address)?;
+        let old_label = old.as_ref().map(|old| &old.label);
+        if old_label != Some(&contract.label) {
+            if self.unique_labels {
+                let other = CONTRACTS_BY_LABEL
+                    .prefix(&contract.label)
+                    .keys(&storage, None, None, Order::Ascending)
+                    .next()
+                    .transpose()?;
+                if let Some(other) = other {
+                    bail!(WasmError::DuplicateLabel {
+                        label: contract.label.clone(),
+                        contract: other,
+                    });
+                }
+            }
+            if let Some(old_label) = old_label {
+                CONTRACTS_BY_LABEL.remove(&mut storage, (old_label, address));
+            }
+            CONTRACTS_BY_LABEL.save(&mut storage, (&contract.label, address), &Empty {})?;
+        }
+        let mut positions = match CONTRACT_POSITIONS.may_load(&storage, address)? {
+            Some(positions) => positions,
+            None => {
+                let position = next_position(&mut storage)?;
+                ContractPositions {
+                    created: position,
+                    code_set: position,
+                }
+            }
+        };
+        if let Some(old) = &old {
+            if old.code_id != contract.code_id {
+                CONTRACTS_BY_CODE.remove(&mut storage, (old.code_id as u64, positions.code_set));
+                positions.code_set = next_position(&mut storage)?;
+            }
+            if old.creator != contract.creator {
+                CONTRACTS_BY_CREATOR.remove(&mut storage, (&old.creator, positions.created));
+            }
+        }
+        CONTRACTS_BY_CODE.save(
+            &mut storage,
+            (contract.code_id as u64, positions.code_set),
+            address,
+        )?;
+        CONTRACTS_BY_CREATOR.save(
+            &mut storage,
+            (&contract.creator, positions.created),
+            address,
+        )?;
+        CONTRACT_POSITIONS.save(&mut storage, address, &positions)?;
         CONTRACTS
-            .save(&mut prefixed(storage, NAMESPACE_WASM), address, contract)
+            .save(&mut storage, address, contract)
             .map_err(Into::into)
     }
 }
 
+fn next_position(storage: &mut dyn Storage) -> StdResult<u64> {
+    let position = NEXT_POSITION.may_load(storage)?.unwrap_or_default();
+    NEXT_POSITION.save(storage, &(position + 1))?;
+    Ok(position)
+}
+
+/// Applies the pagination of a wasmd list query to all the listed items, along with their
+/// positions in the list, which are used as the pagination keys
+fn paginate<T>(
+    mut items: Vec<(u64, T)>,
+    page: Option<PageRequest>,
+) -> AnyResult<(Vec<T>, PageResponseJson)> {
+    let page = page.unwrap_or_default();
+    let total = items.len();
+    if page.reverse {
+        items.reverse();
+    }
+    let start = if page.key.is_empty() {
+        page.offset as usize
+    } else {
+        let key: [u8; 8] = match page.key.as_slice().try_into() {
+            Ok(key) => key,
+            Err(_) => bail!(WasmError::InvalidPaginationKey(page.key.into())),
+        };
+        let key = u64::from_be_bytes(key);
+        items
+            .iter()
+            .position(|(position, _)| {
+                if page.reverse {
+                    *position <= key
+                } else {
+                    *position >= key
+                }
+            })
+            .unwrap_or(items.len())
+    };
+    // same default as the Cosmos SDK
+    let limit = match page.limit {
+        0 => 100,
+        limit => limit as usize,
+    };
+    let mut page_items = items.into_iter().skip(start);
+    let listed = page_items
+        .by_ref()
+        .take(limit)
+        .map(|(_, item)| item)
+        .collect();
+    let next_key = page_items
+        .next()
+        .map(|(position, _)| Binary::from(position.to_be_bytes()));
+    let total = if page.count_total && page.key.is_empty() {
+        total
+    } else {
+        0
+    };
+    Ok((
+        listed,
+        PageResponseJson {
+            next_key,
+            total: total.to_string(),
+        },
+    ))
+}
+
 // TODO: replace with code in utils
 
 #[derive(Clone, PartialEq, Message)]
@@ -939,6 +2097,124 @@ fn instantiate_response(data: Option<Binary>, contact_address: &Addr) -> Binary
     new_data.into()
 }
 
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct QueryContractHistoryRequest {
+    #[prost(string, tag = "1")]
+    pub address: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pagination: Option<PageRequest>,
+}
+
+/// `cosmos.base.query.v1beta1.PageRequest`
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct PageRequest {
+    #[prost(bytes, tag = "1")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+    #[prost(uint64, tag = "3")]
+    pub limit: u64,
+    #[prost(bool, tag = "4")]
+    pub count_total: bool,
+    #[prost(bool, tag = "5")]
+    pub reverse: bool,
+}
+
+/// `cosmos.base.query.v1beta1.PageResponse` in the JSON form wasmd returns for Stargate queries
+#[derive(Serialize)]
+struct PageResponseJson {
+    next_key: Option<Binary>,
+    total: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct QueryContractsByCodeRequest {
+    #[prost(uint64, tag = "1")]
+    pub code_id: u64,
+    #[prost(message, optional, tag = "2")]
+    pub pagination: Option<PageRequest>,
+}
+
+#[derive(Serialize)]
+struct QueryContractsByCodeResponseJson {
+    contracts: Vec<String>,
+    pagination: PageResponseJson,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct QueryContractsByCreatorRequest {
+    #[prost(string, tag = "1")]
+    pub creator_address: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pagination: Option<PageRequest>,
+}
+
+#[derive(Serialize)]
+struct QueryContractsByCreatorResponseJson {
+    contract_addresses: Vec<String>,
+    pagination: PageResponseJson,
+}
+
+/// `QueryContractHistoryResponse` in the JSON form wasmd returns for Stargate queries
+#[derive(Serialize)]
+struct QueryContractHistoryResponseJson {
+    entries: Vec<ContractCodeHistoryEntryJson>,
+    pagination: PageResponseJson,
+}
+
+#[derive(Serialize)]
+struct ContractCodeHistoryEntryJson {
+    operation: &'static str,
+    code_id: String,
+    updated: AbsoluteTxPositionJson,
+    msg: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct AbsoluteTxPositionJson {
+    block_height: String,
+    tx_index: String,
+}
+
+impl From<ContractHistoryEntry> for ContractCodeHistoryEntryJson {
+    fn from(entry: ContractHistoryEntry) -> Self {
+        let operation = match entry.operation {
+            ContractHistoryOperation::Init => "CONTRACT_CODE_HISTORY_OPERATION_TYPE_INIT",
+            ContractHistoryOperation::Migrate => "CONTRACT_CODE_HISTORY_OPERATION_TYPE_MIGRATE",
+            ContractHistoryOperation::Genesis => "CONTRACT_CODE_HISTORY_OPERATION_TYPE_GENESIS",
+        };
+        ContractCodeHistoryEntryJson {
+            operation,
+            code_id: entry.code_id.to_string(),
+            updated: AbsoluteTxPositionJson {
+                block_height: entry.block_height.to_string(),
+                tx_index: entry.tx_index.to_string(),
+            },
+            // messages are raw JSON, like wasmd's `RawContractMessage`
+            msg: serde_json::from_slice(&entry.msg).unwrap_or(serde_json::Value::Null),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct StoreCodeResponse {
+    #[prost(uint64, tag = "1")]
+    pub code_id: u64,
+    #[prost(bytes, tag = "2")]
+    pub checksum: ::prost::alloc::vec::Vec<u8>,
+}
+
+fn store_code_response(code_id: usize, checksum: &HexBinary) -> Binary {
+    let store_data = StoreCodeResponse {
+        code_id: code_id as u64,
+        checksum: checksum.to_vec(),
+    };
+    let mut new_data = Vec::<u8>::with_capacity(store_data.encoded_len());
+    // the data must encode successfully
+    store_data.encode(&mut new_data).unwrap();
+    new_data.into()
+}
+
 #[derive(Clone, PartialEq, Message)]
 struct ExecuteResponse {
     #[prost(bytes, tag = "1")]
@@ -994,6 +2270,8 @@ mod test {
             distribution: DistributionKeeper::new(),
             ibc: FailingModule::new(),
             gov: FailingModule::new(),
+            transaction: None,
+            tracer: Default::default(),
         }
     }
 
@@ -1042,6 +2320,7 @@ mod test {
                 admin: Some(Addr::unchecked("admin")),
                 label: "label".to_owned(),
                 created: 1000,
+                code_name: None,
             }
         );
 
@@ -1123,6 +2402,101 @@ mod test {
         assert_eq!(expected, from_slice(&info).unwrap());
     }
 
+    #[test]
+    fn query_contract_info_of_pinned_ibc_contract() {
+        let api = MockApi::default();
+        let mut keeper = WasmKeeper::<Empty, Empty>::new();
+        let block = mock_env().block;
+        let code_id = keeper.store_code(payout::contract_with_ibc_entry_points());
+
+        let mut wasm_storage = MockStorage::new();
+        PINNED_CODES
+            .save(
+                &mut prefixed(&mut wasm_storage, NAMESPACE_WASM),
+                code_id as u64,
+                &Empty {},
+            )
+            .unwrap();
+        let contract_addr = keeper
+            .register_contract(
+                &mut wasm_storage,
+                code_id,
+                Addr::unchecked("foobar"),
+                None,
+                "label".to_owned(),
+                1000,
+            )
+            .unwrap();
+
+        let querier: MockQuerier<Empty> = MockQuerier::new(&[]);
+        let query = WasmQuery::ContractInfo {
+            contract_addr: contract_addr.to_string(),
+        };
+        let info: ContractInfoResponse = from_slice(
+            &keeper
+                .query(&api, &wasm_storage, &querier, &block, query)
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(info.pinned);
+        assert_eq!(info.ibc_port, Some(format!("wasm.{}", contract_addr)));
+
+        // contracts without IBC entry points get no port
+        let code_id = keeper.store_code(payout::contract());
+        let contract_addr = keeper
+            .register_contract(
+                &mut wasm_storage,
+                code_id,
+                Addr::unchecked("foobar"),
+                None,
+                "label".to_owned(),
+                1000,
+            )
+            .unwrap();
+        let query = WasmQuery::ContractInfo {
+            contract_addr: contract_addr.to_string(),
+        };
+        let info: ContractInfoResponse = from_slice(
+            &keeper
+                .query(&api, &wasm_storage, &querier, &block, query)
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(!info.pinned);
+        assert_eq!(info.ibc_port, None);
+    }
+
+    #[cfg(feature = "cosmwasm_1_2")]
+    #[test]
+    fn query_code_info() {
+        let api = MockApi::default();
+        let mut keeper = WasmKeeper::<Empty, Empty>::new();
+        let block = mock_env().block;
+        let first = keeper.store_code(payout::contract());
+        let second =
+            keeper.store_code_with_creator(Addr::unchecked("uploader"), payout::contract());
+
+        let wasm_storage = MockStorage::new();
+        let querier: MockQuerier<Empty> = MockQuerier::new(&[]);
+        let query_code_info = |code_id| -> AnyResult<cosmwasm_std::CodeInfoResponse> {
+            let query = WasmQuery::CodeInfo { code_id };
+            let res = keeper.query(&api, &wasm_storage, &querier, &block, query)?;
+            Ok(from_slice(&res)?)
+        };
+
+        let first_info = query_code_info(first as u64).unwrap();
+        assert_eq!(first_info.code_id, first as u64);
+        assert_eq!(first_info.creator, DEFAULT_CODE_CREATOR);
+        assert_eq!(first_info.checksum.len(), 32);
+
+        let second_info = query_code_info(second as u64).unwrap();
+        assert_eq!(second_info.creator, "uploader");
+        assert_ne!(second_info.checksum, first_info.checksum);
+
+        let err = query_code_info(3).unwrap_err();
+        assert_eq!(Error::UnregisteredCodeId(3), err.downcast().unwrap());
+    }
+
     #[test]
     fn can_dump_raw_wasm_state() {
         let api = MockApi::default();
@@ -1492,7 +2866,7 @@ mod test {
         assert_admin(&wasm_storage, &keeper, &contract_addr, Some(admin.clone()));
 
         // non-admin should not be allowed to become admin on their own
-        keeper
+        let err = keeper
             .execute_wasm(
                 &api,
                 &mut wasm_storage,
@@ -1505,6 +2879,20 @@ mod test {
                 },
             )
             .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Only admin can update the contract admin: {:?}",
+                Some(admin.clone())
+            )
+        );
+        assert_eq!(
+            err.downcast::<WasmError>().unwrap(),
+            WasmError::Unauthorized {
+                sender: normal_user,
+                admin: Some(admin.clone()),
+            }
+        );
 
         // should still be admin
         assert_admin(&wasm_storage, &keeper, &contract_addr, Some(admin.clone()));
@@ -1552,6 +2940,67 @@ mod test {
         assert_admin(&wasm_storage, &keeper, &contract_addr, None);
     }
 
+    #[test]
+    fn typed_errors() {
+        let api = MockApi::default();
+        let mut keeper = WasmKeeper::<Empty, Empty>::new();
+        let block = mock_env().block;
+        let code_id = keeper.store_code(payout::contract());
+        let admin = Addr::unchecked("admin");
+        let mut wasm_storage = MockStorage::new();
+
+        let err = keeper
+            .execute_wasm(
+                &api,
+                &mut wasm_storage,
+                &mock_router(),
+                &block,
+                admin.clone(),
+                WasmMsg::Instantiate {
+                    admin: None,
+                    code_id: code_id as u64,
+                    msg: to_binary(&EmptyMsg {}).unwrap(),
+                    funds: vec![],
+                    label: "".to_owned(),
+                },
+            )
+            .unwrap_err();
+        assert_eq!(err.downcast::<WasmError>().unwrap(), WasmError::EmptyLabel);
+
+        let contract_addr = keeper
+            .register_contract(
+                &mut wasm_storage,
+                code_id,
+                admin.clone(),
+                admin.clone(),
+                "label".to_owned(),
+                1000,
+            )
+            .unwrap();
+        let err = keeper
+            .execute_wasm(
+                &api,
+                &mut wasm_storage,
+                &mock_router(),
+                &block,
+                admin,
+                WasmMsg::Migrate {
+                    contract_addr: contract_addr.to_string(),
+                    new_code_id: 1234,
+                    msg: to_binary(&EmptyMsg {}).unwrap(),
+                },
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot migrate contract to unregistered code id"
+        );
+        assert_eq!(
+            err.downcast::<Error>().unwrap(),
+            Error::UnregisteredCodeId(1234)
+        );
+    }
+
     #[test]
     fn by_default_uses_simple_address_generator() {
         let mut keeper = WasmKeeper::<Empty, Empty>::new();